@@ -16,12 +16,12 @@ fn main() {
   let dimensions = 3;
   let mut kdtree = KdTree::new(dimensions);
 
-  let p1 = Photon::new(&Wavelength::Red, &Ray::new(&Vector3::new(0.0, 0.0, 0.0), &Vector3::EY));
-  let p2 = Photon::new(&Wavelength::Green, &Ray::new(&Vector3::new(1.0, 1.0, 0.0), &Vector3::EX));
-  let p3 = Photon::new(&Wavelength::Blue, &Ray::new(&Vector3::new(1.0, 1.0, 1.0), &Vector3::EZ));
-  let p4 = Photon::new(&Wavelength::Red, &Ray::new(&Vector3::new(-1.0, -1.0, -1.0), &Vector3::EY));
-  let p5 = Photon::new(&Wavelength::Green, &Ray::new(&Vector3::new(2.0, 2.0, 2.0), &Vector3::EX));
-  let p6 = Photon::new(&Wavelength::Blue, &Ray::new(&Vector3::new(2.0, 2.0, 2.0), &Vector3::EZ));
+  let p1 = Photon::new(&Wavelength::RED, &Ray::new(&Vector3::new(0.0, 0.0, 0.0), &Vector3::EY));
+  let p2 = Photon::new(&Wavelength::GREEN, &Ray::new(&Vector3::new(1.0, 1.0, 0.0), &Vector3::EX));
+  let p3 = Photon::new(&Wavelength::BLUE, &Ray::new(&Vector3::new(1.0, 1.0, 1.0), &Vector3::EZ));
+  let p4 = Photon::new(&Wavelength::RED, &Ray::new(&Vector3::new(-1.0, -1.0, -1.0), &Vector3::EY));
+  let p5 = Photon::new(&Wavelength::GREEN, &Ray::new(&Vector3::new(2.0, 2.0, 2.0), &Vector3::EX));
+  let p6 = Photon::new(&Wavelength::BLUE, &Ray::new(&Vector3::new(2.0, 2.0, 2.0), &Vector3::EZ));
 
   kdtree.add(p1.ray.pos.v, p1).unwrap();
   kdtree.add(p2.ray.pos.v, p2).unwrap();
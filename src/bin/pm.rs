@@ -1,21 +1,32 @@
 // Photon tracer
 
 use std::env;
+use std::fs;
+use std::io;
+use std::io::BufWriter;
+use std::io::prelude::*;
+
+use rayon::prelude::*;
 //use std::io::{Error, ErrorKind};
 
+use ppmpa::error::*;
+use ppmpa::photonmap::*;
 use ppmpa::ray::*;
 //use ppmpa::ray::algebra::*;
 //use ppmpa::ray::geometry::*;
+use ppmpa::ray::bvh::*;
 use ppmpa::ray::light::*;
-use ppmpa::ray::object::*;
-use ppmpa::ray::physics::*;
-//use ppmpa::ray::optics::*;
+use ppmpa::ray::medium::*;
+use ppmpa::ray::optics::*;
+use ppmpa::ray::rng;
 use ppmpa::scene::*;
 use ppmpa::tracer::*;
 
-const USAGE: &str = "Usage: pm [-c|-h] <scene file> [<#photon>] (output photon map to stdout)";
+const USAGE: &str = "Usage: pm [-c|-h] [--text] [--threads N] [-o <map file>] <scene file> [<#photon>] (output photon map to stdout without -o)";
 const DEF_NPHOTON: i32 = 100_000;
+const CHUNK: i64 = 10_000;    // photons traced at a time and then written
 const DEF_USECLASSIC: bool = true;
+const DEF_NTHREAD: usize = 0;   // 0: as many as the cores
 
 //fn main() -> Result<(), std::io::Error> {
 fn main() {
@@ -26,8 +37,37 @@ fn main() {
     return;
   }
   //let scr = read_screen(&args[1]);
-  let nphoton = if args.len() == 3 {
-    let np = args[2].parse::<i32>();
+  let mut fmt = MapFormat::Binary;
+  let mut outfile: Option<String> = None;
+  let mut nthread = DEF_NTHREAD;
+  let mut argoffset = 1;
+  while argoffset < args.len() {
+    match args[argoffset].as_str() {
+      "--text" => fmt = MapFormat::Text,
+      "-o" if argoffset + 1 < args.len() => {
+        argoffset += 1;
+        outfile = Some(args[argoffset].clone());
+      },
+      "--threads" if argoffset + 1 < args.len() => {
+        argoffset += 1;
+        match args[argoffset].parse::<usize>() {
+          Ok(n) => nthread = n,
+          _     => {
+            println!("{}", USAGE);
+            return;
+          },
+        }
+      },
+      _        => break,
+    }
+    argoffset += 1;
+  }
+  if args.len() <= argoffset {
+    println!("{}", USAGE);
+    return;
+  }
+  let nphoton = if args.len() == argoffset + 2 {
+    let np = args[argoffset + 1].parse::<i32>();
     match np {
       Ok(n) => n,
       _     => DEF_NPHOTON,
@@ -36,41 +76,63 @@ fn main() {
     DEF_NPHOTON
   };
   let uc = DEF_USECLASSIC;
-  let (lgts, objs) = read_scene(&args[1]);
+  let (lgts, objs) = or_exit(read_scene(&args[argoffset]));
+  let fingerprint = match fs::read(&args[argoffset]) {
+    Ok(contents) => Some(scene_fingerprint(&contents)),
+    Err(_)       => None,
+  };
+  let bvh = Bvh::new(&objs);
+  rng::init_workers(nthread);
   let power0: Flt = lgts.iter().fold(0.0, |power0, l| power0 + l.flux());
   let power = power0 / nphoton as Flt;
-  let ns = lgts.iter().map(|l| calc_n(&power, l));   // 1光源あたりのフォトン数のリスト
-  
-  println!("{}", nphoton);
-  println!("{}", power);
+  let ns: Vec<i64> = lgts.iter().map(|l| calc_n(&power, l)).collect();   // 1光源あたりのフォトン数のリスト
 
-  for (n, l) in ns.zip(lgts.iter()) {
-    output_photon_caches(&uc, &objs, &l, n)
+  let hd = MapHeader {nphoton: nphoton as u64, power, fingerprint};
+  match outfile {
+    Some(f) => {
+      let file = or_exit(fs::File::create(&f).map_err(|e| Error::io(&f, &e)));
+      let (w, n) = or_exit(write_map(BufWriter::new(file), fmt, &hd, &uc, &bvh, &lgts, &ns).map_err(|e| Error::io(&f, &e)));
+      if fmt == MapFormat::Binary {
+        let mut file = or_exit(w.into_inner().map_err(|e| Error::io(&f, e.error())));
+        or_exit(patch_nrecord(&mut file, &n).map_err(|e| Error::io(&f, &e)));
+      }
+    },
+    None    => {
+      // stdout cannot seek: the binary map is left as a stream
+      let stdout = io::stdout();
+      or_exit(write_map(BufWriter::new(stdout.lock()), fmt, &hd, &uc, &bvh, &lgts, &ns));
+    },
   }
 }
 
-fn calc_n(power: &Flt, lgt: &Light) -> i64 {
-  (lgt.flux() / power).round() as i64
+// photons written chunk by chunk as they are traced
+// OUT: the output and the number of records written
+fn write_map<W: Write>(w: W, fmt: MapFormat, hd: &MapHeader, uc: &bool, objs: &Bvh, lgts: &[Light], ns: &[i64]) -> io::Result<(W, u64)> {
+  let mut mw = MapWriter::new(w, fmt, hd, None)?;
+  for (n, l) in ns.iter().zip(lgts.iter()) {
+    let mut i = 0;
+    while i < *n {
+      let m = CHUNK.min(n - i);
+      let (phs, vps) = get_photon_caches(uc, objs, l, m);
+      mw.write(&phs, &vps)?;
+      i += m;
+    }
+  }
+  mw.finish()
 }
 
-fn output_photon_caches(uc: &bool, objs: &Vec<Object>, lgt: &Light, np: i64) {
-  for _i in 0..np {
-    output_photon_cache(uc, objs, lgt);
-  }
+fn calc_n(power: &Flt, lgt: &Light) -> i64 {
+  (lgt.flux() / power).round() as i64
 }
 
-fn output_photon_cache(uc: &bool, objs: &Vec<Object>, lgt: &Light) {
-  let ph = lgt.generate_photon();
-  let pcs = trace_photon(uc, &M_AIR, objs, 0, &ph);
-  for pc in pcs {
-    let w = match pc.wl {
-      Wavelength::Red   => "Red",
-      Wavelength::Green => "Green",
-      Wavelength::Blue  => "Blue",
-    };
-    println!("{} {} {} {} {} {} {}", w,
-      pc.ray.pos.v[0], pc.ray.pos.v[1], pc.ray.pos.v[2],
-      pc.ray.dir.v[0], pc.ray.dir.v[1], pc.ray.dir.v[2]);
-  }
+fn get_photon_caches(uc: &bool, objs: &Bvh, lgt: &Light, np: i64) -> (Vec<Photon>, Vec<Photon>) {
+  let (phs, vps): (Vec<Vec<Photon>>, Vec<Vec<Photon>>) = (0..np).into_par_iter()
+    .map(|_| {
+      let mut vps: Vec<Photon> = vec![];
+      let phs = trace_photon(uc, &Media::new(&M_AIR), objs, 0, &lgt.generate_photon(), &mut vps);
+      (phs, vps)
+    })
+    .unzip();
+  (phs.concat(), vps.concat())
 }
 
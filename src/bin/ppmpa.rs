@@ -1,22 +1,48 @@
 // Progressive Photon Mapping with Probability Approach
-// 
+//
 
 use std::env;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::process;
 
+use rayon::prelude::*;
+
+use ppmpa::error::*;
 use ppmpa::camera::*;
 use ppmpa::photonmap::*;
 use ppmpa::ray::*;
+use ppmpa::ray::bvh::*;
 use ppmpa::ray::light::*;
-use ppmpa::ray::object::*;
+use ppmpa::ray::medium::*;
 use ppmpa::ray::optics::*;
+use ppmpa::ray::rng;
 use ppmpa::scene::*;
+use ppmpa::sppm::*;
 use ppmpa::tracer::*;
 
-const USAGE: &str = "Usage: ppmpa [-nc|-h] <#photon> <radius> <camera file> <scene file>";
+const USAGE: &str = "Usage: ppmpa [-nc|-h] [--threads N] [--iterations N] [--alpha A] [--sppm] [--snapshot N [--snapshot-prefix P]] <#photon> <radius> <camera file> <scene file>";
 const DEF_USECLASSIC: bool = true;
+const DEF_NTHREAD: usize = 0;   // 0: as many as the cores
+const DEF_NITERATION: usize = 1;
+const DEF_ALPHA: Flt = 0.5;
+const DEF_SNAPSHOT: usize = 0;  // 0: no snapshot
+const DEF_SNAPSHOT_PREFIX: &str = "snapshot";
 const DEF_NPHOTON: i32 = 100000;
 const DEF_RADIUS: Flt = 0.1;
 
+struct Params {
+  nphoton: i32,
+  radius: Flt,          // squared
+  uc: bool,
+  nthread: usize,
+  niteration: usize,
+  alpha: Flt,
+  sppm: bool,           // per pixel radius instead of averaging whole images
+  snapshot: usize,      // write the image every N passes
+  snapshot_prefix: String, // to <prefix>-NNNN.ppm (may have directories)
+}
+
 //fn main() -> Result<(), std::io::Error> {
 fn main() {
   let args: Vec<String> = env::args().collect();
@@ -26,12 +52,36 @@ fn main() {
     //return Err(std::io::Error::new(ErrorKind::Other, USAGE));
   }
 
-  let (nphoton, radius, uc, cam, lgts, objs) = get_params(&args);
-  let power: Flt = lgts.iter().fold(0.0, |power0, l| power0 + l.flux()) / nphoton as Flt;
+  let (pr, cam, lgts, objs) = get_params(&args);
+  rng::init_workers(pr.nthread);
+  let power: Flt = lgts.iter().fold(0.0, |power0, l| power0 + l.flux()) / pr.nphoton as Flt;
     let ns = lgts.iter().map(|l| calc_n(&power, l)).collect();   // 1光源あたりのフォトン数のリスト
-  
-  // iteration
-  let image = iteration(&uc, &power, &ns, &radius, &cam, &objs, &lgts);
+
+  // progressive iteration: the radius shrinks by (i + alpha) / (i + 1) each pass
+  let mut radius = pr.radius;
+  let mut acc = vec![Radiance::RADIANCE0; cam.screen_map.len()];
+  let mut stats = vec![PixelStat::new(&pr.radius); cam.screen_map.len()];
+  for i in 0..pr.niteration {
+    let n = i + 1;
+    if pr.sppm {
+      eprintln!("({})", i);
+      iteration_sppm(&pr, &power, &ns, &cam, &objs, &lgts, &mut stats);
+    } else {
+      eprintln!("({}) R={:.4}", i, radius.sqrt());
+      let image = iteration(&pr.uc, &power, &ns, &radius, &cam, &objs, &lgts);
+      for (a, c) in acc.iter_mut().zip(image.iter()) {
+        *a = *a + *c;
+      }
+      radius *= (n as Flt + pr.alpha) / (n as Flt + 1.0);
+    }
+    if pr.snapshot > 0 && n % pr.snapshot == 0 && n < pr.niteration {
+      let file = format!("{}-{:04}.ppm", pr.snapshot_prefix, n);
+      if let Err(e) = write_snapshot(&file, &cam, &current_image(&pr, &acc, &stats, n)) {
+        eprintln!("{}: {}", file, e);
+      }
+    }
+  }
+  let image = current_image(&pr, &acc, &stats, pr.niteration);
 
   for l in cam.pnm_header() {
     println!("{}", l);
@@ -45,50 +95,127 @@ fn print_usage() {
   eprintln!("{}", USAGE);
 }
 
-fn get_params(args: &Vec<String>) -> (i32, Flt, bool, Camera, Vec<Light>, Vec<Object>) {
-
-  let (argoffset, uc) = if args[1] == "-nc" {  // No use classic
-    (2, !DEF_USECLASSIC)
-  } else {
-    (1, DEF_USECLASSIC)
+fn get_params(args: &Vec<String>) -> (Params, Camera, Vec<Light>, Bvh) {
+  let mut argoffset = 1;
+  let mut pr = Params {
+    nphoton: DEF_NPHOTON,
+    radius: DEF_RADIUS * DEF_RADIUS,
+    uc: DEF_USECLASSIC,
+    nthread: DEF_NTHREAD,
+    niteration: DEF_NITERATION,
+    alpha: DEF_ALPHA,
+    sppm: false,
+    snapshot: DEF_SNAPSHOT,
+    snapshot_prefix: DEF_SNAPSHOT_PREFIX.to_string(),
   };
+  while argoffset < args.len() && args[argoffset].starts_with('-') {
+    match args[argoffset].as_str() {
+      "-nc"               => pr.uc = !DEF_USECLASSIC,   // No use classic
+      "--threads"         => pr.nthread = option_value(args, &mut argoffset),
+      "--iterations"      => pr.niteration = option_value(args, &mut argoffset),
+      "--alpha"           => pr.alpha = option_value(args, &mut argoffset),
+      "--sppm"            => pr.sppm = true,
+      "--snapshot"        => pr.snapshot = option_value(args, &mut argoffset),
+      "--snapshot-prefix" => pr.snapshot_prefix = option_value(args, &mut argoffset),
+      _                   => break,
+    }
+    argoffset += 1;
+  }
+  if args.len() < argoffset + 4 || pr.niteration == 0 || pr.alpha <= 0.0 || pr.alpha >= 1.0 {
+    print_usage();
+    process::exit(1);
+  }
 
-  let nphoton = match args[argoffset].parse::<i32>() {
-    Ok(np) => np,
-    _      => DEF_NPHOTON,
-  };
-  let radius  = match args[argoffset + 1].parse::<Flt>() {
-    Ok(r) => r * r,
-    _     => DEF_RADIUS * DEF_RADIUS,
-  };
-  let cam = read_camera(&args[argoffset + 2]);
-  let (lgts, objs) = read_scene(&args[argoffset + 3]);
+  if let Ok(np) = args[argoffset].parse::<i32>() {
+    pr.nphoton = np;
+  }
+  if let Ok(r) = args[argoffset + 1].parse::<Flt>() {
+    pr.radius = r * r;
+  }
+  let cam = or_exit(read_camera(&args[argoffset + 2]));
+  let (lgts, objs) = or_exit(read_scene(&args[argoffset + 3]));
+
+  (pr, cam, lgts, Bvh::new(&objs))
+}
 
-  (nphoton, radius, uc, cam, lgts, objs)
+// value following an option
+fn option_value<T: std::str::FromStr>(args: &[String], argoffset: &mut usize) -> T {
+  *argoffset += 1;
+  match args.get(*argoffset).map(|a| a.parse::<T>()) {
+    Some(Ok(v)) => v,
+    _           => {
+      print_usage();
+      process::exit(1);
+    },
+  }
 }
 
 fn calc_n(power: &Flt, lgt: &Light) -> i64 {
   (lgt.flux() / power).round() as i64
 }
 
-fn iteration(uc: &bool, pw: &Flt, ns: &Vec<i64>, radius: &Flt, cam: &Camera, objs: &Vec<Object>, lgts: &Vec<Light>) -> Vec<Radiance> {
-  let mut phs: Vec<Photon> = vec![];
-  for (n, l) in ns.iter().zip(lgts.iter()) {
-    phs.extend(get_photon_caches(&uc, &objs, &l, *n));
-  }
+fn iteration(uc: &bool, pw: &Flt, ns: &Vec<i64>, radius: &Flt, cam: &Camera, objs: &Bvh, lgts: &Vec<Light>) -> Vec<Radiance> {
+  let (phs, vps) = get_photons(uc, ns, objs, lgts);
   let (_msize, pmap) = build_photonmap(pw, radius, &phs, &cam.n_sample_photon);
+  let (_vsize, vmap) = build_photonmap(pw, radius, &vps, &cam.n_sample_photon);
 
-  let rays = cam.screen_map.iter().map(|p| cam.generate_ray(p));
-  let imgs = rays.map(|r| trace_ray(cam, &M_AIR, 0, objs, lgts, &r, &pmap, radius, uc)).collect();
-  imgs
+  cam.screen_map.par_iter()
+    .map(|p| trace_ray(cam, &Media::new(&M_AIR), 0, objs, lgts, &cam.generate_ray(p), &None, &pmap, &vmap, radius, uc))
+    .collect()
 }
 
-fn get_photon_caches(uc: &bool, objs: &Vec<Object>, lgt: &Light, np: i64) -> Vec<Photon> {
+// hit points are made again every pass so that blur and antialiasing converge
+fn iteration_sppm(pr: &Params, pw: &Flt, ns: &Vec<i64>, cam: &Camera, objs: &Bvh, lgts: &Vec<Light>, stats: &mut [PixelStat]) {
+  let (phs, vps) = get_photons(&pr.uc, ns, objs, lgts);
+  let (_msize, pmap) = build_photonmap(pw, &pr.radius, &phs, &cam.n_sample_photon);
+  let (_vsize, vmap) = build_photonmap(pw, &pr.radius, &vps, &cam.n_sample_photon);
+
+  stats.par_iter_mut().zip(cam.screen_map.par_iter()).for_each(|(st, p)| {
+    let mut hps: Vec<HitPoint> = vec![];
+    let d = trace_hitpoints(&Media::new(&M_AIR), 0, objs, lgts, &cam.generate_ray(p), &None, &Radiance::RADIANCE1, &pr.uc, &vmap, &pr.radius, &mut hps);
+    st.update(&pr.alpha, cam, &pmap, &hps, &d);
+  });
+}
+
+// photons on the surfaces and in the participating media
+fn get_photons(uc: &bool, ns: &Vec<i64>, objs: &Bvh, lgts: &Vec<Light>) -> (Vec<Photon>, Vec<Photon>) {
   let mut phs: Vec<Photon> = vec![];
-  for _i in 0..np {
-    phs.extend(trace_photon(uc, &M_AIR, objs, 0, &lgt.generate_photon()));
+  let mut vps: Vec<Photon> = vec![];
+  for (n, l) in ns.iter().zip(lgts.iter()) {
+    let (phs1, vps1) = get_photon_caches(uc, objs, l, *n);
+    phs.extend(phs1);
+    vps.extend(vps1);
   }
-  phs
+  (phs, vps)
+}
+
+fn get_photon_caches(uc: &bool, objs: &Bvh, lgt: &Light, np: i64) -> (Vec<Photon>, Vec<Photon>) {
+  let (phs, vps): (Vec<Vec<Photon>>, Vec<Vec<Photon>>) = (0..np).into_par_iter()
+    .map(|_| {
+      let mut vps: Vec<Photon> = vec![];
+      let phs = trace_photon(uc, &Media::new(&M_AIR), objs, 0, &lgt.generate_photon(), &mut vps);
+      (phs, vps)
+    })
+    .unzip();
+  (phs.concat(), vps.concat())
 }
 
+fn current_image(pr: &Params, acc: &[Radiance], stats: &[PixelStat], n: usize) -> Vec<Radiance> {
+  if pr.sppm {
+    stats.iter().map(|st| st.radiance()).collect()
+  } else {
+    let mag = 1.0 / n as Flt;
+    acc.iter().map(|c| *c * mag).collect()
+  }
+}
 
+fn write_snapshot(file: &str, cam: &Camera, image: &[Radiance]) -> std::io::Result<()> {
+  let mut w = BufWriter::new(File::create(file)?);
+  for l in cam.pnm_header() {
+    writeln!(w, "{}", l)?;
+  }
+  for c in image {
+    writeln!(w, "{}", radiance_to_string(c))?;
+  }
+  Ok(())
+}
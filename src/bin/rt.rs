@@ -1,32 +1,65 @@
 // Photon tracer
 
 use std::env;
+use std::fs;
+use std::io;
 use std::time::{Instant};
 
+use rayon::prelude::*;
+
+use ppmpa::error::*;
 use ppmpa::ray::*;
 //use ppmpa::ray::algebra::*;
 //use ppmpa::ray::physics::*;
 //use ppmpa::ray::geometry::*;
 use ppmpa::camera::*;
+use ppmpa::ray::bvh::*;
+use ppmpa::ray::medium::*;
 use ppmpa::ray::optics::*;
+use ppmpa::ray::rng;
 use ppmpa::photonmap::*;
 use ppmpa::scene::*;
 use ppmpa::tracer::*;
 
-const USAGE: &str = "Usage: rtc <scene file> <camera file> [<radius>]";
+const USAGE: &str = "Usage: rt [--threads N] <scene file> <camera file> [<radius>] [-m <photon map file>]... (photon map from stdin if no file)";
 const DEF_USECLASSIC: bool = true;
 const DEF_RADIUS: Flt = 0.1;
+const DEF_NTHREAD: usize = 0;   // 0: as many as the cores
 
 fn main() {
   let args: Vec<String> = env::args().collect();
-  if args.len() < 3 {
+  let mut pargs: Vec<String> = vec![];   // positional ones
+  let mut files: Vec<String> = vec![];
+  let mut nthread = DEF_NTHREAD;
+  let mut i = 1;
+  while i < args.len() {
+    match args[i].as_str() {
+      "-m" if i + 1 < args.len() => {
+        i += 1;
+        files.push(args[i].clone());
+      },
+      "--threads" if i + 1 < args.len() => {
+        i += 1;
+        match args[i].parse::<usize>() {
+          Ok(n) => nthread = n,
+          _     => {
+            println!("{}", USAGE);
+            return;
+          },
+        }
+      },
+      _ => pargs.push(args[i].clone()),
+    }
+    i += 1;
+  }
+  if pargs.len() < 2 || pargs.len() > 3 {
     println!("{}", USAGE);
     //return Err(std::io::Error::new(ErrorKind::Other, USAGE));
     return;
   }
   let uc = DEF_USECLASSIC;  // use classic for direct
-  let radius = if args.len() == 4 {
-    let r = args[3].parse::<Flt>();
+  let radius = if pargs.len() == 3 {
+    let r = pargs[2].parse::<Flt>();
     match r {
       Ok(r) => r * r,
       _     => DEF_RADIUS * DEF_RADIUS,
@@ -34,16 +67,29 @@ fn main() {
   } else {
     DEF_RADIUS * DEF_RADIUS
   };
-  let (lgts, objs) = read_scene(&args[1]);
-  let cam = read_camera(&args[2]);
+  let (lgts, objs) = or_exit(read_scene(&pargs[0]));
+  let cam = or_exit(read_camera(&pargs[1]));
+
+  let bvh = Bvh::new(&objs);
 
   let t0 = Instant::now();
-  let (msize, photonmap) = read_map(&cam.n_sample_photon, &radius);
+  let (hd, photonmap, volumemap) = if !files.is_empty() {
+    or_exit(read_map_files(&files, &cam.n_sample_photon, &radius))
+  } else {
+    let stdin = io::stdin();
+    or_exit(read_map(&mut stdin.lock(), &cam.n_sample_photon, &radius))
+  };
   let t1 = t0.elapsed();
-  eprintln!("finished reading map: {} photons, {:?}.", msize, t1);
+  eprintln!("finished reading map: {} photons, {} volume photons, {:?}.", photonmap.kdtree.size(), volumemap.kdtree.size(), t1);
+  if let (Some(fp), Ok(contents)) = (hd.fingerprint, fs::read(&pargs[0])) {
+    if fp != scene_fingerprint(&contents) {
+      eprintln!("warning: the photon map was made for another scene");
+    }
+  }
 
-  let rays = cam.screen_map.iter().map(|p| cam.generate_ray(p));
-  let image: Vec<Radiance> = rays.map(|r| trace_ray(&cam, &M_AIR, 0, &objs, &lgts, &r, &photonmap, &radius, &uc)).collect();
+  rng::init_workers(nthread);
+  let rays = cam.screen_map.par_iter().map(|p| cam.generate_ray(p));
+  let image: Vec<Radiance> = rays.map(|r| trace_ray(&cam, &Media::new(&M_AIR), 0, &bvh, &lgts, &r, &None, &photonmap, &volumemap, &radius, &uc)).collect();
 
   for l in cam.pnm_header() {
     println!("{}", l);
@@ -5,7 +5,10 @@ use std::env;
 
 //use ppmpa::ray::*;
 //use ppmpa::ray::algebra::*;
+use ppmpa::error::*;
+use ppmpa::ray::bvh::*;
 use ppmpa::ray::geometry::*;
+use ppmpa::ray::medium::*;
 use ppmpa::ray::optics::*;
 use ppmpa::camera::*;
 use ppmpa::scene::*;
@@ -21,14 +24,15 @@ fn main() {
     //return Err(std::io::Error::new(ErrorKind::Other, USAGE));
     return;
   }
-  let scr = read_camera(&args[1]);
+  let scr = or_exit(read_camera(&args[1]));
+  let (lgts, objs) = or_exit(read_scene(&args[2]));
+  let bvh = Bvh::new(&objs);
   for l in scr.pnm_header() {
     println!("{}", l);
   }
 
   let rays: Vec<Ray> = scr.screen_map.iter().map(|p| scr.generate_ray(p)).collect();
-  let (lgts, objs) = read_scene(&args[2]);
-  let image: Vec<Radiance> = rays.iter().map(|r| trace_ray_classic(&scr, &M_AIR, 0, &objs, &lgts, &r)).collect();
+  let image: Vec<Radiance> = rays.iter().map(|r| trace_ray_classic(&scr, &Media::new(&M_AIR), 0, &bvh, &lgts, &r, &None)).collect();
   for c in &image {
     if scr.progressive == false {
       println!("{}", rgb_to_string(&scr.radiance_to_rgb(c)));
@@ -2,12 +2,15 @@
 
 use std::collections::HashMap;
 use std::f64;
-use rand::Rng;
+use std::fs;
+use regex::Regex;
 
+use super::error::*;
 use super::ray::*;
 use super::ray::algebra::*;
 use super::ray::geometry::*;
 use super::ray::optics::*;
+use super::ray::rng;
 
 
 pub struct Rgb(i32, i32, i32);
@@ -21,7 +24,7 @@ impl fmt::Display for Rgb {
 */
 
 pub struct Camera {
-  //pub nphoton: i32,
+  pub nphoton: i32,
   pub xreso: i32,
   pub yreso: i32,
   pub n_sample_photon: i32,
@@ -37,6 +40,7 @@ pub struct Camera {
   pub f_number: Flt,
   pub focus: Flt,
   pub pfilter: PhotonFilter,
+  pub pestimate: PhotonEstimate,
   pub ambient: Radiance,
   pub eye_pos: Position3,
   pub photon_power: Flt,
@@ -52,20 +56,17 @@ pub struct Camera {
 pub const GAMMA: Flt  = 1.0 / 2.2;
 pub const RGBMAX: Flt = 255.0;
 
-//const DEFCONF: HashMap = HashMap
-
 impl Camera {
   pub fn generate_ray(&self, (y, x): &(Flt, Flt)) -> Ray {
-    let mut rng = rand::thread_rng();
     let blur_offset = if self.blur == true {
-      let r1: Flt = rng.gen_range(-0.5, 0.5);
-      let r2: Flt = rng.gen_range(-0.5, 0.5);
+      let r1: Flt = rng::gen_range(-0.5, 0.5);
+      let r2: Flt = rng::gen_range(-0.5, 0.5);
       r1 * self.eex + r2 * self.eey
     } else {
       Vector3::O
     };
     let (r3, r4) = if self.progressive == true && self.antialias == true {
-      (rng.gen_range(-0.5, 0.5), rng.gen_range(-0.5, 0.5))
+      (rng::gen_range(-0.5, 0.5), rng::gen_range(-0.5, 0.5))
     } else {
       (0.0, 0.0)
     };
@@ -105,51 +106,92 @@ const ISO_SENS     : Flt = 100.0;
 const F_NUMBER     : Flt = 4.9;
 const SHUTTER_SPEED: Flt = 1.0 / 250.0;
 
-pub fn read_camera(_file: &str) -> Camera {
-  let mut config = vec![
-    ("x_resolution"   , "256"),
-    ("y_resolution"   , "256"),
-    ("progressive"    , "true"),
-    ("antialias"      , "true"),
-    ("use_classic"    , "true"),
-    ("blur"           , "true"),
-    ("estimate_radius", "0.2"),
-    ("max_radiance"   , "0.01"),
-    ("iso_sensitivity", "100"),    // ISO100 is default (enough photons)
-    ("shutter_speed"  , "0.004"),  // unit is second
-    ("focal_length"   , "50.0"),   // unit is 'mm'
-    ("f_number"       , "4.0"),
-    ("focus"          , "7.0"),
-    ("photon_filter"  , "PF:None"),
-    ("ambient"        , "RAD[0.0,0.0,0.0]"),  // ambient light intensity
-    ("eye_position"   , "V3[1.0,2.0,-4.5]"),  // center of a camera diaphragm
-    ("target_position", "V3[0.0,1.0,0.0]"),   // center of a screen
-    ("upper_direction", "V3[0.0,1.0,0.0]"),
-  ].into_iter().collect::<HashMap<_, _>>();
+// camera file format: see doc/ebnf-camera.txt
+//   values in the file override the defaults below.
+
+const DEFAULT_CONFIG: [(&str, &str); 21] = [
+  ("n_photon"       , "100000"),
+  ("x_resolution"   , "256"),
+  ("y_resolution"   , "256"),
+  ("sample_photon"  , "500"),
+  ("progressive"    , "yes"),
+  ("antialias"      , "yes"),
+  ("use_classic"    , "yes"),
+  ("blur"           , "yes"),
+  ("estimate_radius", "0.2"),
+  ("max_radiance"   , "0.01"),
+  ("iso_sensitivity", "100"),    // ISO100 is default (enough photons)
+  ("shutter_speed"  , "0.004"),  // unit is second
+  ("focal_length"   , "50.0"),   // unit is 'mm'
+  ("f_number"       , "4.0"),
+  ("focus"          , "7.0"),
+  ("photon_filter"  , "none"),
+  ("photon_estimate", "radius"),
+  ("ambient"        , "[ 0.0, 0.0, 0.0 ]"),  // ambient light intensity
+  ("eye_position"   , "[ 1.0, 2.0, -4.5 ]"), // center of a camera diaphragm
+  ("target_position", "[ 0.0, 1.0, 0.0 ]"),  // center of a screen
+  ("upper_direction", "[ 0.0, 1.0, 0.0 ]"),
+];
+
+// legacy key spellings (cf. example/screen0.scr)
+const LEGACY_KEYS: [(&str, &str); 15] = [
+  ("nphoton"        , "n_photon"),
+  ("xresolution"    , "x_resolution"),
+  ("yresolution"    , "y_resolution"),
+  ("samplephoton"   , "sample_photon"),
+  ("useclassic"     , "use_classic"),
+  ("estimateradius" , "estimate_radius"),
+  ("maxradiance"    , "max_radiance"),
+  ("isosensitivity" , "iso_sensitivity"),
+  ("shutterspeed"   , "shutter_speed"),
+  ("focallength"    , "focal_length"),
+  ("fnumber"        , "f_number"),
+  ("photonfilter"   , "photon_filter"),
+  ("eyeposition"    , "eye_position"),
+  ("targetposition" , "target_position"),
+  ("upperdirection" , "upper_direction"),
+];
+
+pub fn read_camera(file: &str) -> Result<Camera, Error> {
+  let contents = fs::read_to_string(file).map_err(|e| Error::io(file, &e))?;
+  parse_camera(&contents).map_err(|e| e.in_file(file))
+}
 
+pub fn parse_camera(contents: &str) -> Result<Camera, Error> {
+  let mut config: Config = DEFAULT_CONFIG.iter()
+    .map(|(k, v)| (k.to_string(), (v.to_string(), None)))
+    .collect();
+  for (k, v, l) in parse_config(contents)? {
+    config.insert(k, (v, Some(l)));
+  }
 
   //let target = Vector3::new_pos(0.0, 2.0, 0.0);
-  let xreso      = param_int(&config, "x_resolution");
-  let yreso      = param_int(&config, "y_resolution");
-  let prog_flag  = param_bool(&config, "progressive");
-  let aa_flag    = param_bool(&config, "antialias");
-  let uc_flag    = param_bool(&config, "use_classic");
-  let blur_flag  = param_bool(&config, "blur");
-  let radius     = param_float(&config, "estimate_radius");
-  let max_rad    = param_float(&config, "max_radiance");
-  let iso_sens   = param_float(&config, "iso_sensitivity");
-  let shut_speed = param_float(&config, "shutter_speed");
-  let focal_len  = param_float(&config, "focal_length") / 1000.0;
-  let f_number   = param_float(&config, "f_number");
-  let focus      = param_float(&config, "focus");
-  let pf         = config.get("photon_filter").unwrap().parse::<PhotonFilter>().unwrap();
-  let ambient    = param_rad(&config, "ambient");
-  let eyepos     = param_vec3(&config, "eye_position");
-  let target     = param_vec3(&config, "target_position");
-  let upper      = param_vec3(&config, "upper_direction");
-
-  let _ez = (target - eyepos).normalize().unwrap();
-  let _ex = upper.cross(&_ez).normalize().unwrap();
+  let nphoton    = param_int(&config, "n_photon")?;
+  let xreso      = param_int(&config, "x_resolution")?;
+  let yreso      = param_int(&config, "y_resolution")?;
+  let nsample    = param_int(&config, "sample_photon")?;
+  let prog_flag  = param_bool(&config, "progressive")?;
+  let aa_flag    = param_bool(&config, "antialias")?;
+  let uc_flag    = param_bool(&config, "use_classic")?;
+  let blur_flag  = param_bool(&config, "blur")?;
+  let radius     = param_float(&config, "estimate_radius")?;
+  let max_rad    = param_float(&config, "max_radiance")?;
+  let iso_sens   = param_float(&config, "iso_sensitivity")?;
+  let shut_speed = param_float(&config, "shutter_speed")?;
+  let focal_len  = param_float(&config, "focal_length")? / 1000.0;
+  let f_number   = param_float(&config, "f_number")?;
+  let focus      = param_float(&config, "focus")?;
+  let pf         = param_filter(&config, "photon_filter")?;
+  let pe         = param_estimate(&config, "photon_estimate")?;
+  let ambient    = param_rad(&config, "ambient")?;
+  let eyepos     = param_vec3(&config, "eye_position")?;
+  let target     = param_vec3(&config, "target_position")?;
+  let upper      = param_vec3(&config, "upper_direction")?;
+
+  let _ez = (target - eyepos).normalize()
+    .ok_or_else(|| param_error(&config, "target_position", "a position apart from eye_position"))?;
+  let _ex = upper.cross(&_ez).normalize()
+    .ok_or_else(|| param_error(&config, "upper_direction", "a direction not parallel to the eye direction"))?;
   let _ey = _ex.cross(&_ez).normalize().unwrap();
 
   let _step = (focus * SENSOR_SIZE / focal_len) / xreso as Flt;
@@ -175,10 +217,10 @@ pub fn read_camera(_file: &str) -> Camera {
   }
 
   let cam = Camera {
-    //nphoton: 500000,
+    nphoton,
     xreso: xreso,
     yreso: yreso,
-    n_sample_photon: 500,
+    n_sample_photon: nsample,
     progressive: prog_flag,
     antialias: aa_flag,
     use_classic_for_direct: uc_flag,
@@ -191,10 +233,11 @@ pub fn read_camera(_file: &str) -> Camera {
     f_number: f_number,
     focus: focus,
     pfilter: pf,
+    pestimate: pe,
     ambient: ambient, //Radiance(0.001, 0.001, 0.001), //
     eye_pos: eyepos,
     photon_power: ppower,
-    eye_dir: (target - eyepos).normalize().unwrap(),    
+    eye_dir: _ez,
     screen_map: smap,
     origin: orig,
     esx: esx,
@@ -202,7 +245,7 @@ pub fn read_camera(_file: &str) -> Camera {
     eex: eex,
     eey: eey,
   };
-  cam
+  Ok(cam)
 }
 
 pub fn rgb_to_string(c: &Rgb) -> String {
@@ -221,24 +264,99 @@ pub fn rgb_to_radiance(cam: &Camera, c: &Rgb) -> Radiance {
 //--------------------
 // private
 
-fn param_int(config: &HashMap<&str, &str>, p: &str) -> i32 {
-  config.get(p).unwrap().parse::<i32>().unwrap()
+// value of a parameter and the line it is given (None: default)
+type Config = HashMap<String, (String, Option<usize>)>;
+
+// lines are "<key> : <value>" with optional "# comment"
+fn parse_config(contents: &str) -> Result<Vec<(String, String, usize)>, Error> {
+  let mut conf: Vec<(String, String, usize)> = vec![];
+  for (i, line) in contents.lines().enumerate() {
+    let body = match line.find('#') {
+      Some(i) => &line[..i],
+      None    => line,
+    }.trim();
+    if body.is_empty() {
+      continue;
+    }
+    let (key, value) = match body.find(':') {
+      Some(i) => (body[..i].trim(), body[i+1..].trim()),
+      None    => return Err(Error::new(&format!("expected '<parameter> : <value>', found '{}'", body)).at_line(Some(i + 1))),
+    };
+    let key2 = canonical_key(key).map_err(|e| e.at_line(Some(i + 1)))?;
+    conf.push((key2, value.to_string(), i + 1));
+  }
+  Ok(conf)
+}
+
+fn canonical_key(key: &str) -> Result<String, Error> {
+  if let Some((_, k)) = LEGACY_KEYS.iter().find(|(l, _)| *l == key) {
+    return Ok(k.to_string())
+  }
+  match DEFAULT_CONFIG.iter().find(|(k, _)| *k == key) {
+    Some((k, _)) => Ok(k.to_string()),
+    None         => Err(Error {
+      file: None,
+      line: None,
+      key: Some(key.to_string()),
+      message: "unknown camera parameter".to_string(),
+    }),
+  }
+}
+
+fn param_error(config: &Config, p: &str, expected: &str) -> Error {
+  let (v, l) = &config[p];
+  Error::invalid(p, expected, v).at_line(*l)
+}
+
+fn param_int(config: &Config, p: &str) -> Result<i32, Error> {
+  config[p].0.parse::<i32>().map_err(|_| param_error(config, p, "an integer"))
 }
 
-fn param_bool(config: &HashMap<&str, &str>, p: &str) -> bool {
-  config.get(p).unwrap().parse::<bool>().unwrap()
+fn param_bool(config: &Config, p: &str) -> Result<bool, Error> {
+  match config[p].0.as_str() {
+    "yes" => Ok(true),
+    "no"  => Ok(false),
+    _     => Err(param_error(config, p, "yes or no")),
+  }
+}
+
+fn param_float(config: &Config, p: &str) -> Result<Flt, Error> {
+  config[p].0.parse::<Flt>().map_err(|_| param_error(config, p, "a real number"))
+}
+
+fn param_vec3(config: &Config, p: &str) -> Result<Vector3, Error> {
+  let (x, y, z) = parse_vector(&config[p].0).ok_or_else(|| param_error(config, p, "a vector [ x, y, z ]"))?;
+  Ok(Vector3::new(x, y, z))
 }
 
-fn param_float(config: &HashMap<&str, &str>, p: &str) -> Flt {
-  config.get(p).unwrap().parse::<Flt>().unwrap()
+fn param_rad(config: &Config, p: &str) -> Result<Radiance, Error> {
+  let (r, g, b) = parse_vector(&config[p].0).ok_or_else(|| param_error(config, p, "a vector [ r, g, b ]"))?;
+  Ok(Radiance(r, g, b))
 }
 
-fn param_vec3(config: &HashMap<&str, &str>, p: &str) -> Vector3 {
-  config.get(p).unwrap().parse::<Vector3>().unwrap()
+fn param_filter(config: &Config, p: &str) -> Result<PhotonFilter, Error> {
+  match config[p].0.as_str() {
+    "none"  => Ok(PhotonFilter::None),
+    "cone"  => Ok(PhotonFilter::Cone),
+    "gauss" => Ok(PhotonFilter::Gauss),
+    _       => Err(param_error(config, p, "none, cone or gauss")),
+  }
 }
 
-fn param_rad(config: &HashMap<&str, &str>, p: &str) -> Radiance {
-  config.get(p).unwrap().parse::<Radiance>().unwrap()
+fn param_estimate(config: &Config, p: &str) -> Result<PhotonEstimate, Error> {
+  match config[p].0.as_str() {
+    "radius"         => Ok(PhotonEstimate::Radius),
+    "nearest"        => Ok(PhotonEstimate::Nearest),
+    "nearest_capped" => Ok(PhotonEstimate::NearestCapped),
+    _                => Err(param_error(config, p, "radius, nearest or nearest_capped")),
+  }
+}
+
+// vector = "[ x, y, z ]"
+fn parse_vector(s: &str) -> Option<(Flt, Flt, Flt)> {
+  let re = Regex::new(r"^\[\s*(\S+?)\s*,\s*(\S+?)\s*,\s*(\S+?)\s*\]$").unwrap();
+  let caps = re.captures(s)?;
+  Some((caps[1].parse::<Flt>().ok()?, caps[2].parse::<Flt>().ok()?, caps[3].parse::<Flt>().ok()?))
 }
 
 
@@ -265,3 +383,47 @@ fn make_generate_ray(aa_flag: &bool, prog_flag: &bool, epos: &Position3,
   f
 }
 */
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_parse_config() {
+    let conf = parse_config("# comment\nxresolution   : 512\n\nprogressive : no   # yes or no\n").unwrap();
+    assert_eq!(conf, vec![
+      ("x_resolution".to_string(), "512".to_string(), 2),
+      ("progressive".to_string(), "no".to_string(), 4),
+    ]);
+  }
+
+  #[test]
+  fn test_parse_camera() {
+    let cam = parse_camera("x_resolution: 128\nsamplephoton: 100\nblur: no\neyeposition: [ 0.0, 2.0, -4.5 ]\nphoton_filter: gauss\n").unwrap();
+    assert_eq!(cam.xreso, 128);
+    assert_eq!(cam.yreso, 256);
+    assert_eq!(cam.n_sample_photon, 100);
+    assert!(!cam.blur);
+    assert_eq!(cam.eye_pos, Vector3::new(0.0, 2.0, -4.5));
+    assert_eq!(cam.pfilter, PhotonFilter::Gauss);
+    assert_eq!(cam.pestimate, PhotonEstimate::Radius);
+    assert_eq!(cam.radius, 0.2 * 0.2);
+    let cam2 = parse_camera("photon_estimate: nearest_capped\n").unwrap();
+    assert_eq!(cam2.pestimate, PhotonEstimate::NearestCapped);
+  }
+
+  #[test]
+  fn test_parse_vector() {
+    assert_eq!(parse_vector("[ 0.001, -1, 2.5 ]"), Some((0.001, -1.0, 2.5)));
+    assert_eq!(parse_vector("[0.0,1.0,0.0]"), Some((0.0, 1.0, 0.0)));
+    assert_eq!(parse_vector("[0.0,1.0]"), None);
+  }
+
+  #[test]
+  fn test_camera_error() {
+    let e1 = parse_camera("x_resolution: 128\nblur: maybe\n").err().unwrap();
+    assert_eq!(format!("{}", e1), "2: 'blur': expected yes or no, found 'maybe'");
+    let e2 = parse_camera("\nfocuss: 2.0\n").err().unwrap();
+    assert_eq!(format!("{}", e2), "2: 'focuss': unknown camera parameter");
+  }
+}
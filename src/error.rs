@@ -0,0 +1,102 @@
+// error
+//   errors while loading scene / camera files
+
+use std::fmt;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Error {
+  pub file: Option<String>,
+  pub line: Option<usize>,
+  pub key: Option<String>,
+  pub message: String,
+}
+
+impl fmt::Display for Error {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    if let Some(file) = &self.file {
+      write!(f, "{}:", file)?;
+    }
+    if let Some(line) = self.line {
+      write!(f, "{}:", line)?;
+    }
+    if self.file.is_some() || self.line.is_some() {
+      write!(f, " ")?;
+    }
+    if let Some(key) = &self.key {
+      write!(f, "'{}': ", key)?;
+    }
+    write!(f, "{}", self.message)
+  }
+}
+
+impl std::error::Error for Error {}
+
+// the value, or the error printed and the process ended (for the commands)
+pub fn or_exit<T, E: fmt::Display>(r: Result<T, E>) -> T {
+  match r {
+    Ok(v)  => v,
+    Err(e) => {
+      eprintln!("{}", e);
+      std::process::exit(1);
+    },
+  }
+}
+
+impl Error {
+  pub fn new(message: &str) -> Error {
+    Error {file: None, line: None, key: None, message: message.to_string()}
+  }
+
+  // invalid value of a parameter
+  pub fn invalid(key: &str, expected: &str, found: &str) -> Error {
+    Error {
+      file: None,
+      line: None,
+      key: Some(key.to_string()),
+      message: format!("expected {}, found '{}'", expected, found),
+    }
+  }
+
+  // a parameter not given
+  pub fn missing(key: &str, expected: &str) -> Error {
+    Error {
+      file: None,
+      line: None,
+      key: Some(key.to_string()),
+      message: format!("missing, expected {}", expected),
+    }
+  }
+
+  pub fn io(file: &str, e: &std::io::Error) -> Error {
+    Error {file: Some(file.to_string()), line: None, key: None, message: e.to_string()}
+  }
+
+  // the first location set is kept
+  pub fn at_line(mut self, line: Option<usize>) -> Error {
+    if self.line.is_none() {
+      self.line = line;
+    }
+    self
+  }
+
+  pub fn in_file(mut self, file: &str) -> Error {
+    if self.file.is_none() {
+      self.file = Some(file.to_string());
+    }
+    self
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_error() {
+    let e = Error::invalid("radius", "a number", "abc").at_line(Some(12)).in_file("a.scene");
+    assert_eq!(format!("{}", e), "a.scene:12: 'radius': expected a number, found 'abc'");
+    let e2 = Error::new("empty").in_file("b.scr");
+    assert_eq!(format!("{}", e2), "b.scr: empty");
+    assert_eq!(format!("{}", Error::new("empty")), "empty");
+  }
+}
@@ -1,7 +1,12 @@
 
 
 pub mod camera;
+pub mod error;
 pub mod photonmap;
+pub mod ply;
 pub mod ray;
 pub mod scene;
+pub mod sppm;
+pub mod stl;
 pub mod tracer;
+pub mod wavefront;
@@ -1,11 +1,25 @@
 // photonmap
+//   photon map files are either text or binary; readers detect the format.
+//
+//   text:   <#photon>\n<power>\n then "<wavelength> px py pz dx dy dz" lines,
+//           wavelength in nm (or Red, Green, Blue of old maps), followed by "v"
+//           for a photon scattered in a participating medium (volume photon)
+//   binary: little endian
+//     magic "PPMA", version (u32), #photon emitted (u64), #record (u64),
+//     power (f64), scene fingerprint (u64, 0 if none), then records of
+//     position (3 x f32), direction (3 x f32), wavelength (f32, nm),
+//     kind (u8: 0=surface, 1=volume)
+//     (version 2: without the kind, version 1: wavelength (u8: 0=Red, 1=Green, 2=Blue))
+//     #record is all ones for a stream whose records go on to the end of it.
 
+use std::fs::File;
 use std::io;
 use std::io::BufReader;
 use std::io::prelude::*;
 
 use kdtree::KdTree;
 
+use super::error::*;
 use super::ray::*;
 use super::ray::geometry::*;
 //use super::ray::light::*;
@@ -13,6 +27,14 @@ use super::ray::geometry::*;
 use super::ray::optics::*;
 use super::ray::physics::*;
 
+pub const MAP_MAGIC: &[u8; 4] = b"PPMA";
+pub const MAP_VERSION: u32 = 3;
+const RECORD_SIZE: usize = 29;
+const RECORD_SIZE_V2: usize = 28;
+const RECORD_SIZE_V1: usize = 25;
+const NRECORD_OFFSET: u64 = 16;
+const NRECORD_UNKNOWN: u64 = u64::MAX;
+
 pub struct PhotonMap {
   pub power: Flt,
   pub nsample: i32,
@@ -20,6 +42,19 @@ pub struct PhotonMap {
   pub kdtree: KdTree<Flt, Photon, [Flt; 3]>,
 }
 
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum MapFormat {
+  Text,
+  Binary,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct MapHeader {
+  pub nphoton: u64,               // photons emitted from the lights
+  pub power: Flt,                 // power of a photon
+  pub fingerprint: Option<u64>,   // scene the map was made for (binary only)
+}
+
 pub fn build_photonmap(pw: &Flt, radius: &Flt, phs: &Vec<Photon>, nsample: &i32) -> (usize, PhotonMap) {
   let mut pmap = KdTree::new(3);
   for p in phs {
@@ -28,49 +63,284 @@ pub fn build_photonmap(pw: &Flt, radius: &Flt, phs: &Vec<Photon>, nsample: &i32)
   (pmap.size(), PhotonMap {power: *pw, nsample: *nsample, radius: *radius, kdtree: pmap})
 }
 
-pub fn read_map(nsample: &i32, radius: &Flt) -> (usize, PhotonMap) {
-  //eprintln!("radius= {}", radius);
-  let mut reader = BufReader::new(io::stdin());
-  let mut line1 = String::new();
-  reader.read_line(&mut line1).expect("invalid #photon");
-  let mut line2 = String::new();
-  let pw0 = match reader.read_line(&mut line2) {
-    Ok(_) => {
-      match line2.trim().parse::<Flt>() {
-        Ok(pw) => pw,
-        _ => 1.0,
+// OUT: header, photon map and volume photon map
+pub fn read_map<R: BufRead>(r: &mut R, nsample: &i32, radius: &Flt) -> io::Result<(MapHeader, PhotonMap, PhotonMap)> {
+  let (hd, phs, vps) = read_photons(r)?;
+  let (_msize, pmap) = build_photonmap(&hd.power, radius, &phs, nsample);
+  let (_vsize, vmap) = build_photonmap(&hd.power, radius, &vps, nsample);
+  Ok((hd, pmap, vmap))
+}
+
+// photon map files made for the same scene, merged into one map
+pub fn read_map_files(files: &[String], nsample: &i32, radius: &Flt) -> Result<(MapHeader, PhotonMap, PhotonMap), Error> {
+  let mut maps: Vec<(MapHeader, Vec<Photon>, Vec<Photon>)> = vec![];
+  for f in files {
+    let fp = File::open(f).map_err(|e| Error::io(f, &e))?;
+    let (hd, phs, vps) = read_photons(&mut BufReader::new(fp)).map_err(|e| Error::io(f, &e))?;
+    if let Some(i) = maps.iter().position(|(hd0, _, _)| hd0.fingerprint.is_some()) {
+      if hd.fingerprint.is_some() && hd.fingerprint != maps[i].0.fingerprint {
+        return Err(Error::new(&format!("made for another scene than {}", files[i])).in_file(f));
       }
-    },
-    _ => 1.0,
-  };
-  let mut pmap =  KdTree::new(3);
-  let mut contents = String::new();
-  match reader.read_to_string(&mut contents) {
-    Err(e) => panic!("Error in reading photon map: {:?}", e),
-    _      => (),
+    }
+    maps.push((hd, phs, vps));
+  }
+  let (hd, phs, vps) = merge_photons(maps).ok_or_else(|| Error::new("no photon map file"))?;
+  let (_msize, pmap) = build_photonmap(&hd.power, radius, &phs, nsample);
+  let (_vsize, vmap) = build_photonmap(&hd.power, radius, &vps, nsample);
+  Ok((hd, pmap, vmap))
+}
+
+// every map carries the whole flux of the lights, so a photon of the merged map
+// has (average flux of the maps) / (total #photon emitted).
+pub fn merge_photons(maps: Vec<(MapHeader, Vec<Photon>, Vec<Photon>)>) -> Option<(MapHeader, Vec<Photon>, Vec<Photon>)> {
+  if maps.is_empty() {
+    return None;
+  }
+  let nmap = maps.len() as Flt;
+  let nphoton: u64 = maps.iter().map(|(hd, _, _)| hd.nphoton).sum();
+  let flux: Flt = maps.iter().map(|(hd, _, _)| hd.power * hd.nphoton as Flt).sum::<Flt>() / nmap;
+  let fingerprint = maps.iter().find_map(|(hd, _, _)| hd.fingerprint);
+  let mut phs: Vec<Photon> = vec![];
+  let mut vps: Vec<Photon> = vec![];
+  for (_, phs1, vps1) in maps {
+    phs.extend(phs1);
+    vps.extend(vps1);
+  }
+  let power = if nphoton > 0 { flux / nphoton as Flt } else { 0.0 };
+  Some((MapHeader {nphoton, power, fingerprint}, phs, vps))
+}
+
+// FNV-1a hash of a scene file
+pub fn scene_fingerprint(contents: &[u8]) -> u64 {
+  contents.iter().fold(0xcbf2_9ce4_8422_2325, |h, b| (h ^ *b as u64).wrapping_mul(0x0100_0000_01b3))
+}
+
+pub fn detect_format<R: BufRead>(r: &mut R) -> io::Result<MapFormat> {
+  let buf = r.fill_buf()?;
+  if buf.len() >= MAP_MAGIC.len() && &buf[..MAP_MAGIC.len()] == MAP_MAGIC {
+    Ok(MapFormat::Binary)
+  } else {
+    Ok(MapFormat::Text)
+  }
+}
+
+// OUT: header, photons on the surfaces and those in the participating media
+pub fn read_photons<R: BufRead>(r: &mut R) -> io::Result<(MapHeader, Vec<Photon>, Vec<Photon>)> {
+  match detect_format(r)? {
+    MapFormat::Text   => read_photons_text(r),
+    MapFormat::Binary => read_photons_binary(r),
+  }
+}
+
+pub fn write_photons<W: Write>(w: &mut W, fmt: MapFormat, hd: &MapHeader, phs: &[Photon], vps: &[Photon]) -> io::Result<()> {
+  let mut mw = MapWriter::new(w, fmt, hd, Some((phs.len() + vps.len()) as u64))?;
+  mw.write(phs, vps)?;
+  mw.finish()?;
+  Ok(())
+}
+
+// photons written as they are traced, without holding all of them
+pub struct MapWriter<W: Write> {
+  w: W,
+  fmt: MapFormat,
+  nrecord: u64,     // written so far
+}
+
+impl<W: Write> MapWriter<W> {
+  // the header is written first. without nrecord, the binary header has that of a
+  // stream; patch_nrecord gives it the count when the output can seek.
+  pub fn new(mut w: W, fmt: MapFormat, hd: &MapHeader, nrecord: Option<u64>) -> io::Result<MapWriter<W>> {
+    match fmt {
+      MapFormat::Text   => write_header_text(&mut w, hd)?,
+      MapFormat::Binary => write_header_binary(&mut w, hd, &nrecord.unwrap_or(NRECORD_UNKNOWN))?,
+    }
+    Ok(MapWriter {w, fmt, nrecord: 0})
+  }
+
+  // photons on the surfaces (phs) and in the participating media (vps)
+  pub fn write(&mut self, phs: &[Photon], vps: &[Photon]) -> io::Result<()> {
+    let pcs = phs.iter().map(|pc| (pc, false)).chain(vps.iter().map(|pc| (pc, true)));
+    for (pc, vol) in pcs {
+      match self.fmt {
+        MapFormat::Text   => write_record_text(&mut self.w, pc, &vol)?,
+        MapFormat::Binary => write_record_binary(&mut self.w, pc, &vol)?,
+      }
+    }
+    self.nrecord += (phs.len() + vps.len()) as u64;
+    Ok(())
+  }
+
+  // OUT: the output and the number of records written
+  pub fn finish(mut self) -> io::Result<(W, u64)> {
+    self.w.flush()?;
+    Ok((self.w, self.nrecord))
   }
+}
+
+// the count of records in the header of a binary map written as a stream
+pub fn patch_nrecord<W: Write + Seek>(w: &mut W, nrecord: &u64) -> io::Result<()> {
+  let pos = w.stream_position()?;
+  w.seek(io::SeekFrom::Start(NRECORD_OFFSET))?;
+  w.write_all(&nrecord.to_le_bytes())?;
+  w.seek(io::SeekFrom::Start(pos))?;
+  w.flush()
+}
+
+//--------------------
+// private
+
+fn invalid_data(msg: String) -> io::Error {
+  io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+fn read_photons_text<R: BufRead>(r: &mut R) -> io::Result<(MapHeader, Vec<Photon>, Vec<Photon>)> {
+  let mut lines = r.lines();
+  let mut header = |name: &str| -> io::Result<String> {
+    match lines.next() {
+      Some(l) => l,
+      None    => Err(invalid_data(format!("photon map: missing {}", name))),
+    }
+  };
+  let l1 = header("#photon")?;
+  let nphoton = l1.trim().parse::<u64>()
+    .map_err(|_| invalid_data(format!("photon map line 1: invalid #photon '{}'", l1.trim())))?;
+  let l2 = header("power")?;
+  let power = l2.trim().parse::<Flt>()
+    .map_err(|_| invalid_data(format!("photon map line 2: invalid power '{}'", l2.trim())))?;
+
   let mut phs: Vec<Photon> = vec![];
-  let mut elems: Vec<&str>;
-  for line in contents.lines() {
-    elems = line.split(' ').collect();
-    let wl = match elems[0] {
-      "Red"   => Wavelength::Red,
-      "Green" => Wavelength::Green,
-      "Blue"  => Wavelength::Blue,
-      _       => Wavelength::Red,
+  let mut vps: Vec<Photon> = vec![];
+  for (i, line) in lines.enumerate() {
+    let line = line?;
+    if line.trim().is_empty() {
+      continue;
+    }
+    match parse_photon(&line) {
+      Some((ph, false)) => phs.push(ph),
+      Some((ph, true))  => vps.push(ph),
+      None              => return Err(invalid_data(format!("photon map line {}: invalid photon '{}'", i + 3, line))),
+    }
+  }
+  Ok((MapHeader {nphoton, power, fingerprint: None}, phs, vps))
+}
+
+// OUT: the photon and whether it is a volume photon
+fn parse_photon(line: &str) -> Option<(Photon, bool)> {
+  let elems: Vec<&str> = line.split_whitespace().collect();
+  let vol = match (elems.len(), elems.get(7)) {
+    (7, _)          => false,
+    (8, Some(&"v")) => true,
+    _               => return None,
+  };
+  let wl = format!("WL:{}", elems[0]).parse::<Wavelength>().ok()?;
+  let mut v = [0.0; 6];
+  for (x, e) in v.iter_mut().zip(elems[1..7].iter()) {
+    *x = e.parse::<Flt>().ok()?;
+  }
+  Some((Photon::new(&wl, &Ray::new_from_elem(v[0], v[1], v[2], v[3], v[4], v[5])?), vol))
+}
+
+fn write_header_text<W: Write>(w: &mut W, hd: &MapHeader) -> io::Result<()> {
+  writeln!(w, "{}", hd.nphoton)?;
+  writeln!(w, "{}", hd.power)
+}
+
+fn write_record_text<W: Write>(w: &mut W, pc: &Photon, vol: &bool) -> io::Result<()> {
+  writeln!(w, "{} {} {} {} {} {} {}{}", pc.wl.0,
+    pc.ray.pos.v[0], pc.ray.pos.v[1], pc.ray.pos.v[2],
+    pc.ray.dir.v[0], pc.ray.dir.v[1], pc.ray.dir.v[2],
+    if *vol { " v" } else { "" })
+}
+
+fn read_photons_binary<R: BufRead>(r: &mut R) -> io::Result<(MapHeader, Vec<Photon>, Vec<Photon>)> {
+  let mut hbuf = [0u8; 40];
+  r.read_exact(&mut hbuf).map_err(|_| invalid_data("photon map: truncated header".to_string()))?;
+  let u32_at = |i: usize| u32::from_le_bytes([hbuf[i], hbuf[i + 1], hbuf[i + 2], hbuf[i + 3]]);
+  let u64_at = |i: usize| {
+    let mut b = [0u8; 8];
+    b.copy_from_slice(&hbuf[i..i + 8]);
+    u64::from_le_bytes(b)
+  };
+  let version = u32_at(4);
+  if !(1..=MAP_VERSION).contains(&version) {
+    return Err(invalid_data(format!("photon map: unsupported version {}", version)));
+  }
+  let hd = MapHeader {
+    nphoton: u64_at(8),
+    power: f64::from_bits(u64_at(24)),
+    fingerprint: Some(u64_at(32)).filter(|fp| *fp != 0),
+  };
+  let nrecord = u64_at(NRECORD_OFFSET as usize);
+
+  let mut phs: Vec<Photon> = if nrecord == NRECORD_UNKNOWN { vec![] } else { Vec::with_capacity(nrecord as usize) };
+  let mut vps: Vec<Photon> = vec![];
+  let mut rec = [0u8; RECORD_SIZE];
+  let rsize = match version {
+    1 => RECORD_SIZE_V1,
+    2 => RECORD_SIZE_V2,
+    _ => RECORD_SIZE,
+  };
+  let mut i = 0;
+  while i < nrecord {
+    let n = read_full(r, &mut rec[..rsize])?;
+    if n == 0 && nrecord == NRECORD_UNKNOWN {
+      break;
+    }
+    if n < rsize {
+      return Err(invalid_data(format!("photon map: truncated at record {}", i)));
+    }
+    let f = |j: usize| f32::from_le_bytes([rec[j * 4], rec[j * 4 + 1], rec[j * 4 + 2], rec[j * 4 + 3]]) as Flt;
+    let wl = match (version, rec[24]) {
+      (1, 0) => Wavelength::RED,
+      (1, 1) => Wavelength::GREEN,
+      (1, 2) => Wavelength::BLUE,
+      (1, w) => return Err(invalid_data(format!("photon map: invalid wavelength {} at record {}", w, i))),
+      _      => Wavelength(f(6)),
     };
-    let px = elems[1].parse::<Flt>().unwrap();
-    let py = elems[2].parse::<Flt>().unwrap();
-    let pz = elems[3].parse::<Flt>().unwrap();
-    let dx = elems[4].parse::<Flt>().unwrap();
-    let dy = elems[5].parse::<Flt>().unwrap();
-    let dz = elems[6].parse::<Flt>().unwrap();
-    phs.push(Photon::new(&wl, &Ray::new_from_elem(px, py, pz, dx, dy, dz).unwrap()));
+    let ray = Ray::new_from_elem(f(0), f(1), f(2), f(3), f(4), f(5))
+      .ok_or_else(|| invalid_data(format!("photon map: invalid direction at record {}", i)))?;
+    match (version, rec[28]) {
+      (3, 1) => vps.push(Photon::new(&wl, &ray)),
+      (3, 0) | (1, _) | (2, _) => phs.push(Photon::new(&wl, &ray)),
+      (_, k) => return Err(invalid_data(format!("photon map: invalid kind {} at record {}", k, i))),
+    }
+    i += 1;
   }
-  for p in phs {
-    pmap.add(p.ray.pos.v, p).unwrap();
+  Ok((hd, phs, vps))
+}
+
+// bytes read into buf, less than its length only at the end of the input
+fn read_full<R: BufRead>(r: &mut R, buf: &mut [u8]) -> io::Result<usize> {
+  let mut n = 0;
+  while n < buf.len() {
+    match r.read(&mut buf[n..]) {
+      Ok(0)  => break,
+      Ok(m)  => n += m,
+      Err(e) if e.kind() == io::ErrorKind::Interrupted => {},
+      Err(e) => return Err(e),
+    }
+  }
+  Ok(n)
+}
+
+fn write_header_binary<W: Write>(w: &mut W, hd: &MapHeader, nrecord: &u64) -> io::Result<()> {
+  w.write_all(MAP_MAGIC)?;
+  w.write_all(&MAP_VERSION.to_le_bytes())?;
+  w.write_all(&hd.nphoton.to_le_bytes())?;
+  w.write_all(&nrecord.to_le_bytes())?;
+  w.write_all(&hd.power.to_bits().to_le_bytes())?;
+  w.write_all(&hd.fingerprint.unwrap_or(0).to_le_bytes())
+}
+
+fn write_record_binary<W: Write>(w: &mut W, pc: &Photon, vol: &bool) -> io::Result<()> {
+  let mut rec = [0u8; RECORD_SIZE];
+  let vs = pc.ray.pos.v.iter().chain(pc.ray.dir.v.iter());
+  for (j, x) in vs.enumerate() {
+    rec[j * 4..j * 4 + 4].copy_from_slice(&(*x as f32).to_le_bytes());
   }
-  (pmap.size(), PhotonMap {power: pw0, nsample: *nsample, radius: *radius, kdtree: pmap})
+  rec[24..28].copy_from_slice(&(pc.wl.0 as f32).to_le_bytes());
+  rec[28] = *vol as u8;
+  w.write_all(&rec)
 }
 
 /*
@@ -78,16 +348,116 @@ fn output_photon_cache(uc: &bool, objs: &Vec<Object>, lgt: &Light) {
   let ph = lgt.generate_photon();
   let pcs = trace_photon(uc, &M_AIR, objs, 0, &ph);
   for pc in pcs {
-    let w = match pc.wl {
-      Wavelength::Red   => "Red",
-      Wavelength::Green => "Green",
-      Wavelength::Blue  => "Blue",
-    };
-    println!("{} {} {} {} {} {} {}", w,
+    println!("{} {} {} {} {} {} {}", pc.wl.0,
       pc.ray.pos.v[0], pc.ray.pos.v[1], pc.ray.pos.v[2],
       pc.ray.dir.v[0], pc.ray.dir.v[1], pc.ray.dir.v[2]);
   }
 }
 */
 
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use super::super::ray::algebra::*;
+
+  fn photons() -> Vec<Photon> {
+    vec![
+      Photon::new(&Wavelength::RED, &Ray::new(&Vector3::new(0.5, 1.25, -2.0), &Vector3::EY)),
+      Photon::new(&Wavelength(487.5), &Ray::new(&Vector3::new(-1.0, 0.0, 3.5), &Vector3::EX)),
+    ]
+  }
+
+  #[test]
+  fn test_map_roundtrip() {
+    let hd = MapHeader {nphoton: 1000, power: 0.25, fingerprint: Some(scene_fingerprint(b"scene"))};
+    for fmt in &[MapFormat::Text, MapFormat::Binary] {
+      let mut buf: Vec<u8> = vec![];
+      write_photons(&mut buf, *fmt, &hd, &photons(), &photons()[1..]).unwrap();
+      let mut r = io::Cursor::new(buf);
+      assert_eq!(detect_format(&mut r).unwrap(), *fmt);
+      let (hd2, phs, vps) = read_photons(&mut r).unwrap();
+      assert_eq!(hd2.nphoton, 1000);
+      assert_eq!(hd2.power, 0.25);
+      assert_eq!(phs, photons());
+      assert_eq!(vps, photons()[1..].to_vec());
+      match fmt {
+        MapFormat::Text   => assert_eq!(hd2.fingerprint, None),
+        MapFormat::Binary => assert_eq!(hd2.fingerprint, hd.fingerprint),
+      }
+    }
+  }
+
+  #[test]
+  fn test_map_stream() {
+    // no fingerprint, the count of records unknown until the end
+    let hd = MapHeader {nphoton: 10, power: 0.5, fingerprint: None};
+    let mut mw = MapWriter::new(io::Cursor::new(vec![]), MapFormat::Binary, &hd, None).unwrap();
+    mw.write(&photons(), &[]).unwrap();
+    mw.write(&photons()[..1], &photons()).unwrap();
+    let (mut c, n) = mw.finish().unwrap();
+    assert_eq!(n, 5);
+    let (hd2, phs, vps) = read_photons(&mut io::Cursor::new(c.get_ref().clone())).unwrap();
+    assert_eq!(hd2, hd);
+    assert_eq!((phs.len(), vps.len()), (3, 2));
+    patch_nrecord(&mut c, &n).unwrap();
+    let mut buf = c.into_inner();
+    buf.extend_from_slice(&[0u8; RECORD_SIZE]);   // beyond the count
+    let (hd3, phs3, vps3) = read_photons(&mut io::Cursor::new(buf)).unwrap();
+    assert_eq!(hd3.fingerprint, None);
+    assert_eq!((phs3, vps3), (phs, vps));
+  }
+
+  #[test]
+  fn test_merge_photons() {
+    let hd1 = MapHeader {nphoton: 1000, power: 0.002, fingerprint: None};
+    let hd2 = MapHeader {nphoton: 3000, power: 2.0 / 3000.0, fingerprint: Some(7)};
+    let (hd, phs, vps) = merge_photons(vec![(hd1, photons(), vec![]), (hd2, photons(), photons())]).unwrap();
+    assert_eq!(hd.nphoton, 4000);
+    assert!((hd.power - 2.0 / 4000.0).abs() < 1.0e-12);
+    assert_eq!(hd.fingerprint, Some(7));
+    assert_eq!((phs.len(), vps.len()), (4, 2));
+    assert_eq!(merge_photons(vec![]), None);
+  }
+
+  #[test]
+  fn test_map_error() {
+    let mut r = io::Cursor::new("10\n0.1\nRed 0 0 0 0 1 0\nRed 0 x 0 0 1 0\n");
+    let e = read_photons(&mut r).unwrap_err();
+    assert_eq!(e.to_string(), "photon map line 4: invalid photon 'Red 0 x 0 0 1 0'");
+    // wavelengths of old maps by the names
+    let (_, phs, _) = read_photons(&mut io::Cursor::new("10\n0.1\nGreen 0 0 0 0 1 0\n520.5 0 0 0 0 1 0\n")).unwrap();
+    assert_eq!(phs[0].wl, Wavelength::GREEN);
+    assert_eq!(phs[1].wl, Wavelength(520.5));
+    let e1 = read_photons(&mut io::Cursor::new("10\n0.1\n520.5 0 0 0 0 1 0 s\n")).unwrap_err();
+    assert_eq!(e1.to_string(), "photon map line 3: invalid photon '520.5 0 0 0 0 1 0 s'");
+    let mut buf: Vec<u8> = vec![];
+    write_photons(&mut buf, MapFormat::Binary, &MapHeader {nphoton: 1, power: 1.0, fingerprint: None}, &photons(), &[]).unwrap();
+    buf.truncate(buf.len() - 1);
+    let e2 = read_photons(&mut io::Cursor::new(buf.clone())).unwrap_err();
+    assert_eq!(e2.to_string(), "photon map: truncated at record 1");
+    // maps of version 2 have no kind: all on the surfaces
+    buf[4] = 2;
+    let (_, phs, vps) = read_photons(&mut io::Cursor::new(buf[..buf.len() - 1].to_vec())).unwrap();
+    assert_eq!((phs.len(), vps.len()), (2, 0));
+  }
 
+  #[test]
+  fn test_map_files() {
+    // the first map without a fingerprint, the other two for different scenes
+    let files: Vec<String> = [None, Some(1), Some(2)].iter().enumerate().map(|(i, fingerprint)| {
+      let file = std::env::temp_dir().join(format!("ppmpa-test-map{}.map", i));
+      let mut buf: Vec<u8> = vec![];
+      write_photons(&mut buf, MapFormat::Binary, &MapHeader {nphoton: 2, power: 0.5, fingerprint: *fingerprint}, &photons(), &[]).unwrap();
+      std::fs::write(&file, buf).unwrap();
+      file.display().to_string()
+    }).collect();
+    let (hd, _, _) = read_map_files(&files[..2], &10, &0.01).unwrap();
+    assert_eq!(hd.nphoton, 4);
+    assert_eq!(hd.fingerprint, Some(1));
+    let e = read_map_files(&files, &10, &0.01).err().unwrap();
+    assert_eq!(e.to_string(), format!("{}: made for another scene than {}", files[2], files[1]));
+    for f in &files {
+      std::fs::remove_file(f).unwrap();
+    }
+  }
+}
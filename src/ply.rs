@@ -0,0 +1,295 @@
+// ply
+//   PLY (polygon file format) import, ASCII and binary little-endian.
+//   'vertex' elements give x, y, z and optional nx, ny, nz and u, v (or s, t),
+//   'face' elements give polygons by 'vertex_indices' (or 'vertex_index'),
+//   split into triangles as a fan. other elements and properties are skipped.
+
+use std::fs;
+use std::str::SplitWhitespace;
+
+use super::error::*;
+use super::ray::*;
+use super::ray::algebra::*;
+use super::ray::geometry::*;
+
+pub fn read_ply(file: &str) -> Result<Mesh, Error> {
+  let bytes = fs::read(file).map_err(|e| Error::io(file, &e))?;
+  parse_ply(&bytes).map_err(|e| e.in_file(file))
+}
+
+pub fn parse_ply(bytes: &[u8]) -> Result<Mesh, Error> {
+  let (format, elems, body) = parse_header(bytes)?;
+  let mut values = match format {
+    Format::Ascii => {
+      let text = std::str::from_utf8(body).map_err(|_| Error::new("ASCII PLY with non-text data"))?;
+      Values::Ascii(text.split_whitespace())
+    },
+    Format::BinaryLE => Values::Binary(body, 0),
+  };
+
+  let mut positions: Vec<Position3> = vec![];
+  let mut normals: Vec<Option<Direction3>> = vec![];
+  let mut uvs: Vec<(Flt, Flt)> = vec![];
+  let mut indices: Vec<[usize; 3]> = vec![];
+  for el in &elems {
+    let find = |name: &str| el.props.iter().position(|p| p.name == name);
+    let both = |a: &str, b: &str| find(a).and_then(|i| find(b).map(|j| (i, j)));
+    let mut row: Vec<Vec<Flt>> = vec![vec![]; el.props.len()];
+    match el.name.as_str() {
+      "vertex" => {
+        let (x, y, z) = match (find("x"), find("y"), find("z")) {
+          (Some(x), Some(y), Some(z)) => (x, y, z),
+          _ => return Err(Error::missing("vertex", "properties x, y and z")),
+        };
+        let nv = find("nx").and_then(|i| both("ny", "nz").map(|(j, k)| (i, j, k)));
+        let uv = both("u", "v").or_else(|| both("s", "t")).or_else(|| both("texture_u", "texture_v"));
+        for _ in 0..el.count {
+          values.read_row(el, &mut row)?;
+          positions.push(Vector3::new(row[x][0], row[y][0], row[z][0]));
+          if let Some((i, j, k)) = nv {
+            normals.push(Vector3::new(row[i][0], row[j][0], row[k][0]).normalize());
+          }
+          if let Some((i, j)) = uv {
+            uvs.push((row[i][0], row[j][0]));
+          }
+        }
+      },
+      "face" => {
+        let vi = find("vertex_indices").or_else(|| find("vertex_index"))
+          .ok_or_else(|| Error::missing("face", "property vertex_indices"))?;
+        for _ in 0..el.count {
+          values.read_row(el, &mut row)?;
+          let vs = &row[vi];
+          if vs.iter().any(|i| *i < 0.0) {
+            return Err(Error::invalid("vertex_indices", "non-negative indices", &format!("{:?}", vs)));
+          }
+          for k in 2..vs.len() {
+            indices.push([vs[0] as usize, vs[k - 1] as usize, vs[k] as usize]);
+          }
+        }
+      },
+      _ => {
+        for _ in 0..el.count {
+          values.read_row(el, &mut row)?;
+        }
+      },
+    }
+  }
+
+  // a zero normal cannot be used: use the normals of the faces instead
+  let normals = if !normals.is_empty() && normals.iter().all(|n| n.is_some()) {
+    Some(normals.into_iter().flatten().collect())
+  } else {
+    None
+  };
+  let uvs = if uvs.is_empty() { None } else { Some(uvs) };
+  Mesh::new(positions, normals, uvs, indices).map_err(|e| Error::new(&e))
+}
+
+//--------------------
+// private
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Format {
+  Ascii,
+  BinaryLE,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Type {
+  I8, U8, I16, U16, I32, U32, F32, F64,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Kind {
+  Scalar(Type),
+  List(Type, Type),   // type of the count, type of the items
+}
+
+#[derive(Debug, Clone, PartialEq)]
+struct Property {
+  name: String,
+  kind: Kind,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+struct Element {
+  name: String,
+  count: usize,
+  props: Vec<Property>,
+}
+
+// OUT: format, elements and the data following the header
+fn parse_header(bytes: &[u8]) -> Result<(Format, Vec<Element>, &[u8]), Error> {
+  let mut format: Option<Format> = None;
+  let mut elems: Vec<Element> = vec![];
+  let mut pos = 0;
+  let mut lno = 0;
+  loop {
+    let end = bytes[pos..].iter().position(|b| *b == b'\n')
+      .ok_or_else(|| Error::new("missing 'end_header'"))?;
+    let line = String::from_utf8_lossy(&bytes[pos..pos + end]);
+    let line = line.trim();
+    pos += end + 1;
+    lno += 1;
+    let ws: Vec<&str> = line.split_whitespace().collect();
+    let at = Some(lno);
+    if lno == 1 {
+      if line != "ply" {
+        return Err(Error::invalid("ply", "a PLY file", line).at_line(at));
+      }
+      continue;
+    }
+    match ws.first().copied() {
+      Some("end_header") => break,
+      Some("format") => format = match ws.get(1).copied() {
+        Some("ascii")                => Some(Format::Ascii),
+        Some("binary_little_endian") => Some(Format::BinaryLE),
+        f => return Err(Error::invalid("format", "ascii or binary_little_endian", f.unwrap_or("")).at_line(at)),
+      },
+      Some("element") => {
+        let count = ws.get(2).and_then(|c| c.parse::<usize>().ok())
+          .ok_or_else(|| Error::invalid("element", "a name and a count", line).at_line(at))?;
+        elems.push(Element {name: ws[1].to_string(), count, props: vec![]});
+      },
+      Some("property") => {
+        let kind = match ws.len() {
+          3 => type_of(ws[1]).map(Kind::Scalar),
+          5 if ws[1] == "list" => type_of(ws[2]).and_then(|c| type_of(ws[3]).map(|i| Kind::List(c, i))),
+          _ => None,
+        };
+        let kind = kind.ok_or_else(|| Error::invalid("property", "a type and a name", line).at_line(at))?;
+        let el = elems.last_mut().ok_or_else(|| Error::new("property before element").at_line(at))?;
+        el.props.push(Property {name: ws[ws.len() - 1].to_string(), kind});
+      },
+      _ => (),    // comment, obj_info
+    }
+  }
+  let format = format.ok_or_else(|| Error::missing("format", "ascii or binary_little_endian"))?;
+  Ok((format, elems, &bytes[pos..]))
+}
+
+fn type_of(s: &str) -> Option<Type> {
+  match s {
+    "char" | "int8"    => Some(Type::I8),
+    "uchar" | "uint8"  => Some(Type::U8),
+    "short" | "int16"  => Some(Type::I16),
+    "ushort" | "uint16" => Some(Type::U16),
+    "int" | "int32"    => Some(Type::I32),
+    "uint" | "uint32"  => Some(Type::U32),
+    "float" | "float32" => Some(Type::F32),
+    "double" | "float64" => Some(Type::F64),
+    _ => None,
+  }
+}
+
+enum Values<'a> {
+  Ascii(SplitWhitespace<'a>),
+  Binary(&'a [u8], usize),
+}
+
+impl<'a> Values<'a> {
+  fn read(&mut self, t: Type) -> Option<Flt> {
+    match self {
+      Values::Ascii(ws) => ws.next()?.parse::<Flt>().ok(),
+      Values::Binary(bs, pos) => {
+        let size = match t {
+          Type::I8 | Type::U8   => 1,
+          Type::I16 | Type::U16 => 2,
+          Type::I32 | Type::U32 | Type::F32 => 4,
+          Type::F64 => 8,
+        };
+        let b = bs.get(*pos..*pos + size)?;
+        *pos += size;
+        Some(match t {
+          Type::I8  => b[0] as i8 as Flt,
+          Type::U8  => b[0] as Flt,
+          Type::I16 => i16::from_le_bytes([b[0], b[1]]) as Flt,
+          Type::U16 => u16::from_le_bytes([b[0], b[1]]) as Flt,
+          Type::I32 => i32::from_le_bytes([b[0], b[1], b[2], b[3]]) as Flt,
+          Type::U32 => u32::from_le_bytes([b[0], b[1], b[2], b[3]]) as Flt,
+          Type::F32 => f32::from_le_bytes([b[0], b[1], b[2], b[3]]) as Flt,
+          Type::F64 => f64::from_le_bytes([b[0], b[1], b[2], b[3], b[4], b[5], b[6], b[7]]),
+        })
+      },
+    }
+  }
+
+  // values of every property of an element, into row
+  fn read_row(&mut self, el: &Element, row: &mut [Vec<Flt>]) -> Result<(), Error> {
+    let short = || Error::invalid(&el.name, &format!("{} {} elements", el.count, el.name), "fewer or broken values");
+    for (p, vs) in el.props.iter().zip(row.iter_mut()) {
+      vs.clear();
+      match p.kind {
+        Kind::Scalar(t) => vs.push(self.read(t).ok_or_else(short)?),
+        Kind::List(c, t) => {
+          let n = self.read(c).ok_or_else(short)?;
+          for _ in 0..n as usize {
+            vs.push(self.read(t).ok_or_else(short)?);
+          }
+        },
+      }
+    }
+    Ok(())
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  const PLY: &str = "ply
+format ascii 1.0
+comment a square and a triangle
+element vertex 5
+property float x
+property float y
+property float z
+property float nx
+property float ny
+property float nz
+element face 2
+property list uchar int vertex_indices
+property uchar flags
+end_header
+0 0 0 0 1 0
+1 0 0 0 1 0
+1 0 1 0 1 0
+0 0 1 0 1 0
+0 1 0 0 0 -1
+4 0 3 2 1 7
+3 0 1 4 0
+";
+
+  #[test]
+  fn test_parse_ply() {
+    let m = parse_ply(PLY.as_bytes()).unwrap();
+    assert_eq!(m.ntriangle(), 3);
+    assert_eq!(m.indices, vec![[0, 3, 2], [0, 2, 1], [0, 1, 4]]);
+    assert_eq!(m.normal(0, 0.2, 0.2), Some(Vector3::EY));
+
+    // the same in binary
+    let mut bin: Vec<u8> = PLY.split("end_header\n").next().unwrap()
+      .replace("ascii", "binary_little_endian").into_bytes();
+    bin.extend(b"end_header\n");
+    for (i, l) in PLY.split("end_header\n").nth(1).unwrap().lines().enumerate() {
+      let vs: Vec<&str> = l.split_whitespace().collect();
+      if i < 5 {
+        for v in vs {
+          bin.extend(&v.parse::<f32>().unwrap().to_le_bytes());
+        }
+      } else {
+        bin.push(vs[0].parse::<u8>().unwrap());
+        for v in &vs[1..vs.len() - 1] {
+          bin.extend(&v.parse::<i32>().unwrap().to_le_bytes());
+        }
+        bin.push(vs[vs.len() - 1].parse::<u8>().unwrap());
+      }
+    }
+    assert_eq!(parse_ply(&bin).unwrap(), m);
+
+    let e = parse_ply(PLY.replace("ascii", "binary_big_endian").as_bytes()).unwrap_err();
+    assert_eq!(format!("{}", e), "2: 'format': expected ascii or binary_little_endian, found 'binary_big_endian'");
+    assert!(parse_ply(&bin[..bin.len() - 3]).is_err());
+  }
+}
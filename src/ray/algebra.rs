@@ -1,6 +1,5 @@
 // algebra module
 
-use core::num::ParseFloatError;
 use std::f64;
 use std::fmt;
 use std::ops::Neg;
@@ -10,10 +9,10 @@ use std::ops::Mul;
 use std::ops::Div;
 use std::str::*;
 
-use rand::Rng;
 use regex::Regex;
 
 use super::*;
+use super::rng;
 
 // 参考URLs
 // https://scrapbox.io/nwtgck/Rustの演算子のオーバーロードで借用してmoveさせないようにする方法_-_std::ops::AddとかMulとか
@@ -35,18 +34,23 @@ impl fmt::Display for Vector3 {
 // https://text.baldanders.info/rust-lang/character-string-2/
 
 impl FromStr for Vector3 {
-  type Err = ParseFloatError;
+  type Err = String;
 
   fn from_str(s: &str) -> Result<Self, Self::Err> {
     let re = Regex::new(r"^V3\[(\S+?),(\S+?),(\S+?)\]$").unwrap();
-    let caps = re.captures(s).unwrap();
-    let x = caps[1].parse::<Flt>()?;
-    let y = caps[2].parse::<Flt>()?;
-    let z = caps[3].parse::<Flt>()?;
+    let caps = re.captures(s).ok_or(format!("invalid vector: {}", s))?;
+    let x = parse_flt(&caps[1], s)?;
+    let y = parse_flt(&caps[2], s)?;
+    let z = parse_flt(&caps[3], s)?;
     Ok(Vector3::new(x, y, z))
   }
 }
 
+// parse an element of 's'
+pub fn parse_flt(e: &str, s: &str) -> Result<Flt, String> {
+  e.parse::<Flt>().map_err(|_| format!("invalid number '{}' in {}", e, s))
+}
+
 impl Neg for Vector3 {
   type Output = Vector3;
 
@@ -209,11 +213,10 @@ impl Direction3 {
 }
 
 pub fn generate_random_dir() -> Direction3 {
-  let mut rng = rand::thread_rng();
   loop {
-    let x: Flt = rng.gen_range(-1.0, 1.0);
-    let y: Flt = rng.gen_range(-1.0, 1.0);
-    let z: Flt = rng.gen_range(-1.0, 1.0);
+    let x: Flt = rng::gen_range(-1.0, 1.0);
+    let y: Flt = rng::gen_range(-1.0, 1.0);
+    let z: Flt = rng::gen_range(-1.0, 1.0);
     let v = Vector3 {v: [x, y, z]};
     let len = v.norm();
     if 0.0 < len && len <= 1.0  {
@@ -223,9 +226,8 @@ pub fn generate_random_dir() -> Direction3 {
 }
 
 pub fn generate_random_dir_by_angle() -> Direction3 {
-  let mut rng = rand::thread_rng();
-  let phi: Flt = rng.gen_range(0.0, 2.0 * f64::consts::PI);
-  let xi:  Flt = rng.gen_range(-1.0, 1.0);
+  let phi: Flt = rng::gen_range(0.0, 2.0 * f64::consts::PI);
+  let xi:  Flt = rng::gen_range(-1.0, 1.0);
   let xi2: Flt = f64::sqrt(1.0 - xi.powf(2.0));
   let x: Flt = xi2 * f64::cos(phi);
   let y: Flt = xi;
@@ -249,6 +251,140 @@ pub fn generate_random_dir_by_angle() -> Direction3 {
   
 
 
+// ---------------------
+// affine transform
+//   m is a 4x4 matrix whose last row is [0, 0, 0, 1], inv is its inverse.
+
+type Matrix4 = [[Flt; 4]; 4];
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct Transform {
+  pub m: Matrix4,
+  pub inv: Matrix4,
+}
+
+const MATRIX4_ID: Matrix4 = [
+  [1.0, 0.0, 0.0, 0.0],
+  [0.0, 1.0, 0.0, 0.0],
+  [0.0, 0.0, 1.0, 0.0],
+  [0.0, 0.0, 0.0, 1.0],
+];
+
+impl Transform {
+  pub const IDENTITY: Transform = Transform {m: MATRIX4_ID, inv: MATRIX4_ID};
+
+  pub fn translate(d: &Vector3) -> Transform {
+    let mut m = MATRIX4_ID;
+    let mut inv = MATRIX4_ID;
+    for i in 0..3 {
+      m[i][3] = d.v[i];
+      inv[i][3] = -d.v[i];
+    }
+    Transform {m, inv}
+  }
+
+  // None: a factor is zero
+  pub fn scale(s: &Vector3) -> Option<Transform> {
+    if s.v.contains(&0.0) {
+      return None;
+    }
+    let mut m = MATRIX4_ID;
+    let mut inv = MATRIX4_ID;
+    for i in 0..3 {
+      m[i][i] = s.v[i];
+      inv[i][i] = 1.0 / s.v[i];
+    }
+    Some(Transform {m, inv})
+  }
+
+  // rotation around the x (0), y (1) or z (2) axis, in degrees
+  pub fn rotate(axis: usize, deg: Flt) -> Transform {
+    let (s, c) = deg.to_radians().sin_cos();
+    let (i, j) = ((axis + 1) % 3, (axis + 2) % 3);
+    let mut m = MATRIX4_ID;
+    m[i][i] = c;
+    m[i][j] = -s;
+    m[j][i] = s;
+    m[j][j] = c;
+    Transform {m, inv: transpose(&m)}
+  }
+
+  // the unit axes to d1, d2 and d3, the origin to o.
+  // None: the axes are on a plane
+  pub fn frame(o: &Position3, d1: &Vector3, d2: &Vector3, d3: &Vector3) -> Option<Transform> {
+    let det = d1.dot(&d2.cross(d3));
+    if det == 0.0 {
+      return None;
+    }
+    let rows = [d2.cross(d3) * (1.0 / det), d3.cross(d1) * (1.0 / det), d1.cross(d2) * (1.0 / det)];
+    let mut m = MATRIX4_ID;
+    let mut inv = MATRIX4_ID;
+    for i in 0..3 {
+      m[i] = [d1.v[i], d2.v[i], d3.v[i], o.v[i]];
+      inv[i] = [rows[i].v[0], rows[i].v[1], rows[i].v[2], -rows[i].dot(o)];
+    }
+    Some(Transform {m, inv})
+  }
+
+  // self first, then t
+  pub fn then(&self, t: &Transform) -> Transform {
+    Transform {m: mul_matrix(&t.m, &self.m), inv: mul_matrix(&self.inv, &t.inv)}
+  }
+
+  pub fn inverse(&self) -> Transform {
+    Transform {m: self.inv, inv: self.m}
+  }
+
+  pub fn position(&self, p: &Position3) -> Position3 {
+    mul_vector(&self.m, p, 1.0)
+  }
+
+  // a direction is not translated and not normalized
+  pub fn direction(&self, d: &Vector3) -> Vector3 {
+    mul_vector(&self.m, d, 0.0)
+  }
+
+  // from the transformed space back
+  pub fn inverse_position(&self, p: &Position3) -> Position3 {
+    mul_vector(&self.inv, p, 1.0)
+  }
+
+  pub fn inverse_direction(&self, d: &Vector3) -> Vector3 {
+    mul_vector(&self.inv, d, 0.0)
+  }
+
+  // normals are transformed by the inverse transpose
+  pub fn normal(&self, n: &Direction3) -> Option<Direction3> {
+    mul_vector(&transpose(&self.inv), n, 0.0).normalize()
+  }
+}
+
+fn mul_matrix(a: &Matrix4, b: &Matrix4) -> Matrix4 {
+  let mut m = [[0.0; 4]; 4];
+  for (i, row) in m.iter_mut().enumerate() {
+    for (j, e) in row.iter_mut().enumerate() {
+      *e = (0..4).map(|k| a[i][k] * b[k][j]).sum();
+    }
+  }
+  m
+}
+
+fn transpose(a: &Matrix4) -> Matrix4 {
+  let mut m = [[0.0; 4]; 4];
+  for (i, row) in m.iter_mut().enumerate() {
+    for (j, e) in row.iter_mut().enumerate() {
+      *e = a[j][i];
+    }
+  }
+  m
+}
+
+// w = 1.0 for a position, 0.0 for a direction
+fn mul_vector(a: &Matrix4, v: &Vector3, w: Flt) -> Vector3 {
+  let e = |i: usize| a[i][0] * v.v[0] + a[i][1] * v.v[1] + a[i][2] * v.v[2] + a[i][3] * w;
+  Vector3::new(e(0), e(1), e(2))
+}
+
 // TESTS
 
 #[cfg(test)]
@@ -279,6 +415,24 @@ mod tests {
     let v42 = v4.unwrap();
     assert_eq!(v42, Vector3 {v: [0.1, 0.3, 0.5]});
   }
+
+  #[test]
+  fn test_transform() {
+    let t = Transform::scale(&Vector3::new(2.0, 1.0, 1.0)).unwrap()
+      .then(&Transform::rotate(2, 90.0))
+      .then(&Transform::translate(&Vector3::new(0.0, 0.0, 5.0)));
+    assert!(t.position(&Vector3::EX).near(&Vector3::new(0.0, 2.0, 5.0)));
+    assert!(t.direction(&Vector3::EY).near(&Vector3::new(-1.0, 0.0, 0.0)));
+    assert!(t.inverse().position(&Vector3::new(0.0, 2.0, 5.0)).near(&Vector3::EX));
+    let n = Vector3::new(1.0, 1.0, 0.0).normalize().unwrap();
+    let n2 = t.normal(&n).unwrap();
+    assert!(n2.dot(&t.direction(&Vector3::new(1.0, -1.0, 0.0))).abs() < 1.0e-10);
+    assert_eq!(Transform::scale(&Vector3::new(1.0, 0.0, 1.0)), None);
+    let f = Transform::frame(&Vector3::EX, &Vector3::new(1.0, 1.0, 0.0), &Vector3::EY, &Vector3::new(0.0, 0.0, 2.0)).unwrap();
+    assert!(f.position(&Vector3::new(1.0, 1.0, 1.0)).near(&Vector3::new(2.0, 2.0, 2.0)));
+    assert!(f.inverse_position(&Vector3::new(2.0, 2.0, 2.0)).near(&Vector3::new(1.0, 1.0, 1.0)));
+    assert_eq!(Transform::frame(&Vector3::O, &Vector3::EX, &Vector3::EY, &Vector3::EX), None);
+  }
 }
 
 
@@ -0,0 +1,280 @@
+// bvh
+//   bounding volume hierarchy (SAH) over the objects of a scene.
+//   the leaves hold parts of the objects (each triangle of a mesh, or a whole shape).
+//   unbounded shapes (plains) are kept out of the tree and tested for every ray.
+
+use super::*;
+use super::algebra::*;
+use super::geometry::*;
+use super::object::*;
+
+const N_BIN: usize = 12;
+const MAX_LEAF: usize = 4;
+const COST_TRAVERSE: Flt = 1.0;
+const COST_INTERSECT: Flt = 1.0;
+
+#[derive(Debug, Clone, Copy)]
+enum Node {
+  Leaf {
+    bbox: Aabb,
+    start: usize,
+    count: usize,
+  },
+  Inner {
+    bbox: Aabb,
+    left: usize,
+    right: usize,
+  },
+}
+
+impl Node {
+  fn bbox(&self) -> &Aabb {
+    match self {
+      Node::Leaf {bbox, ..}  => bbox,
+      Node::Inner {bbox, ..} => bbox,
+    }
+  }
+}
+
+// (index of object, index of part)
+type Prim = (usize, usize);
+
+pub struct Bvh {
+  pub objs: Vec<Object>,
+  unbounded: Vec<Prim>,
+  indices: Vec<Prim>,
+  nodes: Vec<Node>,
+}
+
+impl Bvh {
+  pub fn new(objs: &[Object]) -> Bvh {
+    let mut bounded: Vec<(Prim, Aabb)> = vec![];
+    let mut unbounded: Vec<Prim> = vec![];
+    for (i, o) in objs.iter().enumerate() {
+      for j in 0..o.shape.nprim() {
+        match o.shape.prim_bounding_box(j) {
+          Some(b) => bounded.push(((i, j), b)),
+          None    => unbounded.push((i, j)),
+        }
+      }
+    }
+    let mut bvh = Bvh {objs: objs.to_vec(), unbounded, indices: vec![], nodes: vec![]};
+    if !bounded.is_empty() {
+      bvh.build(&mut bounded);
+    }
+    bvh
+  }
+
+  // nearest intersection farther than NEARLY0: (index of object, hit)
+  pub fn nearest(&self, r: &Ray) -> Option<(usize, Hit)> {
+    let mut hit: Option<(usize, Hit)> = None;
+    for i in &self.unbounded {
+      hit = self.nearer(r, *i, hit);
+    }
+    if self.nodes.is_empty() {
+      return hit
+    }
+    let inv = Vector3::new(1.0 / r.dir.v[0], 1.0 / r.dir.v[1], 1.0 / r.dir.v[2]);
+    let mut stack: Vec<usize> = vec![0];
+    while let Some(n) = stack.pop() {
+      let tmax = match hit {
+        Some((_, h)) => h.dist,
+        None         => f64::INFINITY,
+      };
+      if self.nodes[n].bbox().hit(r, &inv, tmax).is_none() {
+        continue;
+      }
+      match self.nodes[n] {
+        Node::Leaf {bbox: _, start, count} => {
+          for i in &self.indices[start..start + count] {
+            hit = self.nearer(r, *i, hit);
+          }
+        },
+        Node::Inner {bbox: _, left, right} => {
+          // visit the nearer child first
+          let tl = self.nodes[left].bbox().hit(r, &inv, tmax);
+          let tr = self.nodes[right].bbox().hit(r, &inv, tmax);
+          match (tl, tr) {
+            (Some(tl), Some(tr)) if tl < tr => { stack.push(right); stack.push(left); },
+            (Some(_), Some(_))              => { stack.push(left); stack.push(right); },
+            (Some(_), None)                 => stack.push(left),
+            (None, Some(_))                 => stack.push(right),
+            (None, None)                    => (),
+          }
+        },
+      }
+    }
+    hit
+  }
+
+  fn nearer(&self, r: &Ray, (i, j): Prim, hit: Option<(usize, Hit)>) -> Option<(usize, Hit)> {
+    match (self.objs[i].shape.nearest_hit(j, r), hit) {
+      (Some(h), Some((_, h0))) if h.dist < h0.dist => Some((i, h)),
+      (Some(h), None)                              => Some((i, h)),
+      _                                            => hit,
+    }
+  }
+
+  fn build(&mut self, items: &mut [(Prim, Aabb)]) -> usize {
+    let bbox = items.iter().skip(1).fold(items[0].1, |b, (_, b2)| b.merge(b2));
+    let id = self.nodes.len();
+    self.nodes.push(Node::Leaf {bbox, start: 0, count: 0});
+    let split = if items.len() > MAX_LEAF { split_sah(items, &bbox) } else { None };
+    match split {
+      Some(mid) => {
+        let (ls, rs) = items.split_at_mut(mid);
+        let left = self.build(ls);
+        let right = self.build(rs);
+        self.nodes[id] = Node::Inner {bbox, left, right};
+      },
+      None => {
+        let start = self.indices.len();
+        self.indices.extend(items.iter().map(|(i, _)| *i));
+        self.nodes[id] = Node::Leaf {bbox, start, count: items.len()};
+      },
+    }
+    id
+  }
+}
+
+// binned SAH: reorder items and return the split position,
+// or None if making a leaf is cheaper.
+fn split_sah(items: &mut [(Prim, Aabb)], bbox: &Aabb) -> Option<usize> {
+  let cbox = Aabb::new(&items.iter().map(|(_, b)| b.centroid()).collect::<Vec<Position3>>());
+  let ext = cbox.max - cbox.min;
+  let axis = if ext.v[0] > ext.v[1] && ext.v[0] > ext.v[2] {
+    0
+  } else if ext.v[1] > ext.v[2] {
+    1
+  } else {
+    2
+  };
+  let lo = cbox.min.v[axis];
+  let width = ext.v[axis];
+  let bin_of = |b: &Aabb| {
+    let k = ((b.centroid().v[axis] - lo) / width * N_BIN as Flt) as usize;
+    if k >= N_BIN { N_BIN - 1 } else { k }
+  };
+
+  let mut bins: Vec<(usize, Option<Aabb>)> = vec![(0, None); N_BIN];
+  for (_, b) in items.iter() {
+    let k = bin_of(b);
+    bins[k].0 += 1;
+    bins[k].1 = Some(match bins[k].1 {
+      Some(b0) => b0.merge(b),
+      None     => *b,
+    });
+  }
+  // cost of splitting between bin k-1 and k
+  let sweep = |range: &mut dyn Iterator<Item = usize>| {
+    let mut acc: Vec<(usize, Flt)> = vec![(0, 0.0); N_BIN];
+    let mut n = 0;
+    let mut b: Option<Aabb> = None;
+    for k in range {
+      n += bins[k].0;
+      if let Some(bk) = bins[k].1 {
+        b = Some(match b { Some(b0) => b0.merge(&bk), None => bk });
+      }
+      acc[k] = (n, b.map_or(0.0, |b| b.area()));
+    }
+    acc
+  };
+  let lacc = sweep(&mut (0..N_BIN));
+  let racc = sweep(&mut (0..N_BIN).rev());
+  let mut best: Option<(Flt, usize)> = None;
+  for k in 1..N_BIN {
+    let (nl, al) = lacc[k - 1];
+    let (nr, ar) = racc[k];
+    if nl == 0 || nr == 0 { continue; }
+    let cost = COST_TRAVERSE + COST_INTERSECT * (nl as Flt * al + nr as Flt * ar) / bbox.area();
+    match best {
+      Some((c, _)) if c <= cost => (),
+      _                         => best = Some((cost, k)),
+    }
+  }
+
+  match best {
+    Some((cost, k)) if cost < COST_INTERSECT * items.len() as Flt || items.len() > MAX_LEAF * 4 => {
+      items.sort_by_key(|(_, b)| bin_of(b));
+      Some(items.iter().position(|(_, b)| bin_of(b) >= k).unwrap())
+    },
+    Some(_) => None,
+    None if items.len() > MAX_LEAF * 4 => {
+      // all centroids are in one bin: split in half
+      Some(items.len() / 2)
+    },
+    None => None,
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use super::super::material::*;
+  use super::super::optics::*;
+  use super::super::physics::*;
+  use super::super::surface::*;
+  use std::sync::Arc;
+
+  fn brute_force(objs: &[Object], r: &Ray) -> Option<(Flt, usize)> {
+    let mut hit: Option<(Flt, usize)> = None;
+    for (i, o) in objs.iter().enumerate() {
+      for t in o.shape.distance(r) {
+        if t < NEARLY0 { continue; }
+        match hit {
+          Some((t0, _)) if t0 <= t => (),
+          _                        => hit = Some((t, i)),
+        }
+      }
+    }
+    hit
+  }
+
+  fn mesh() -> Shape {
+    // a bumpy grid of 8 x 8 x 2 triangles
+    let mut ps: Vec<Position3> = vec![];
+    for i in 0..9 {
+      for j in 0..9 {
+        ps.push(Vector3::new(i as Flt * 0.5 - 2.0, 3.0 + ((i + j) % 3) as Flt * 0.2, j as Flt * 0.5 + 4.0));
+      }
+    }
+    let mut ids: Vec<[usize; 3]> = vec![];
+    for i in 0..8 {
+      for j in 0..8 {
+        let k = i * 9 + j;
+        ids.push([k, k + 9, k + 1]);
+        ids.push([k + 1, k + 9, k + 10]);
+      }
+    }
+    Shape::Mesh {mesh: Arc::new(Mesh::new(ps, None, None, ids).unwrap())}
+  }
+
+  #[test]
+  fn test_bvh() {
+    let mate = Material {
+      emittance: Radiance::RADIANCE0,
+      transmittance: Color::BLACK,
+      ior: Color::BLACK,
+      dispersion: None,
+      scattering: None,
+      surface: Surface::Nothing,
+      priority: 0,
+    };
+    let mut objs = vec![Object::new(&Shape::Plain {nvec: Vector3::EY, dist: 0.0}, &mate)];
+    for i in 0..10 {
+      for j in 0..10 {
+        let c = Vector3::new(i as Flt - 4.5, 1.0 + j as Flt * 0.3, 5.0 + (i * j) as Flt * 0.1);
+        objs.push(Object::new(&Shape::Sphere {center: c, radius: 0.2}, &mate));
+        let p0 = c + Vector3::new(0.0, 0.0, -1.0);
+        objs.push(Object::new(&Shape::new_polygon(&p0, &(p0 + Vector3::EX * 0.3), &(p0 + Vector3::EY * 0.3)), &mate));
+      }
+    }
+    objs.push(Object::new(&mesh(), &mate));
+    let bvh = Bvh::new(&objs);
+    let o = Vector3::new(0.0, 2.0, -3.0);
+    for _ in 0..2000 {
+      let r = Ray::new(&o, &generate_random_dir());
+      assert_eq!(bvh.nearest(&r).map(|(i, h)| (h.dist, i)), brute_force(&objs, &r));
+    }
+  }
+}
@@ -0,0 +1,182 @@
+// conductor
+//   Fresnel reflectance of metals by the complex index of refraction n + ik.
+//   Reference URLs
+//   https://pbr-book.org/3ed-2018/Reflection_Models/Specular_Reflection_and_Transmission
+//   https://refractiveindex.info/
+//
+//   photons take n and k at their wavelength, eye rays at the representatives
+//   of the channels. the outer medium is taken as the air.
+
+use super::*;
+use super::physics::*;
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Conductor {
+  Rgb(Color, Color),    // n and k at the representatives of the channels
+  Metal(Metal),         // measured
+}
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Metal {
+  Au,
+  Ag,
+  Cu,
+  Al,
+  Cr,
+}
+
+// (nm, n, k) every 50 nm, rounded from the measurements of Johnson & Christy
+// (Au, Ag, Cu, Cr) and Rakic (Al)
+const TABLE_AU: [(Flt, Flt, Flt); 7] = [
+  (400.0, 1.47, 1.95), (450.0, 1.38, 1.91), (500.0, 0.97, 1.87), (550.0, 0.43, 2.45),
+  (600.0, 0.25, 3.07), (650.0, 0.15, 3.61), (700.0, 0.13, 4.07),
+];
+const TABLE_AG: [(Flt, Flt, Flt); 7] = [
+  (400.0, 0.05, 2.10), (450.0, 0.04, 2.66), (500.0, 0.05, 3.13), (550.0, 0.06, 3.59),
+  (600.0, 0.06, 4.01), (650.0, 0.05, 4.41), (700.0, 0.04, 4.81),
+];
+const TABLE_CU: [(Flt, Flt, Flt); 7] = [
+  (400.0, 1.18, 2.21), (450.0, 1.24, 2.56), (500.0, 1.18, 2.60), (550.0, 1.02, 2.58),
+  (600.0, 0.30, 3.05), (650.0, 0.21, 3.58), (700.0, 0.21, 4.00),
+];
+const TABLE_AL: [(Flt, Flt, Flt); 7] = [
+  (400.0, 0.49, 4.86), (450.0, 0.62, 5.47), (500.0, 0.77, 6.08), (550.0, 0.96, 6.69),
+  (600.0, 1.20, 7.26), (650.0, 1.49, 7.82), (700.0, 1.83, 8.31),
+];
+const TABLE_CR: [(Flt, Flt, Flt); 7] = [
+  (400.0, 1.98, 3.07), (450.0, 2.34, 3.18), (500.0, 2.75, 3.30), (550.0, 3.18, 3.33),
+  (600.0, 3.26, 3.33), (650.0, 3.20, 3.40), (700.0, 3.05, 3.60),
+];
+
+impl Metal {
+  // chemical symbol or name
+  pub fn from_name(s: &str) -> Option<Metal> {
+    match s {
+      "Au" | "gold"                   => Some(Metal::Au),
+      "Ag" | "silver"                 => Some(Metal::Ag),
+      "Cu" | "copper"                 => Some(Metal::Cu),
+      "Al" | "aluminium" | "aluminum" => Some(Metal::Al),
+      "Cr" | "chromium" | "chrome"    => Some(Metal::Cr),
+      _                               => None,
+    }
+  }
+
+  fn table(&self) -> &'static [(Flt, Flt, Flt)] {
+    match self {
+      Metal::Au => &TABLE_AU,
+      Metal::Ag => &TABLE_AG,
+      Metal::Cu => &TABLE_CU,
+      Metal::Al => &TABLE_AL,
+      Metal::Cr => &TABLE_CR,
+    }
+  }
+}
+
+impl Conductor {
+  // (n, k) at a wavelength
+  pub fn nk(&self, wl: &Wavelength) -> (Flt, Flt) {
+    match self {
+      Conductor::Rgb(n, k) => interpolate(&[
+        (Wavelength::BLUE.0,  n.2, k.2),
+        (Wavelength::GREEN.0, n.1, k.1),
+        (Wavelength::RED.0,   n.0, k.0),
+      ], &wl.0),
+      Conductor::Metal(m)  => interpolate(m.table(), &wl.0),
+    }
+  }
+
+  pub fn fresnel(&self, cos: &Flt, wl: &Wavelength) -> Flt {
+    let (n, k) = self.nk(wl);
+    fresnel_conductor(cos, &n, &k)
+  }
+
+  pub fn fresnel_color(&self, cos: &Flt) -> Color {
+    Color(
+      self.fresnel(cos, &Wavelength::RED),
+      self.fresnel(cos, &Wavelength::GREEN),
+      self.fresnel(cos, &Wavelength::BLUE),
+    )
+  }
+}
+
+// exact Fresnel reflectance of unpolarized light on a conductor
+pub fn fresnel_conductor(cos: &Flt, n: &Flt, k: &Flt) -> Flt {
+  let c2 = cos.clamp(0.0, 1.0).powi(2);
+  let s2 = 1.0 - c2;
+  let t0 = n * n - k * k - s2;
+  let a2b2 = (t0 * t0 + 4.0 * n * n * k * k).sqrt();
+  let a = (0.5 * (a2b2 + t0)).max(0.0).sqrt();
+  let t1 = a2b2 + c2;
+  let t2 = 2.0 * c2.sqrt() * a;
+  let rs = (t1 - t2) / (t1 + t2);
+  let t3 = c2 * a2b2 + s2 * s2;
+  let t4 = t2 * s2;
+  let rp = rs * (t3 - t4) / (t3 + t4);
+  0.5 * (rs + rp)
+}
+
+// linear between the samples, constant outside of them
+fn interpolate(tbl: &[(Flt, Flt, Flt)], nm: &Flt) -> (Flt, Flt) {
+  let last = tbl[tbl.len() - 1];
+  if *nm <= tbl[0].0 {
+    return (tbl[0].1, tbl[0].2)
+  }
+  if *nm >= last.0 {
+    return (last.1, last.2)
+  }
+  let i = tbl.iter().position(|s| s.0 > *nm).unwrap();
+  let (s0, s1) = (tbl[i - 1], tbl[i]);
+  let t = (nm - s0.0) / (s1.0 - s0.0);
+  (s0.1 + (s1.1 - s0.1) * t, s0.2 + (s1.2 - s0.2) * t)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn normal(n: Flt, k: Flt) -> Flt {
+    ((n - 1.0).powi(2) + k * k) / ((n + 1.0).powi(2) + k * k)
+  }
+
+  #[test]
+  fn test_fresnel_conductor() {
+    assert!((fresnel_conductor(&1.0, &0.2, &3.0) - normal(0.2, 3.0)).abs() < 1.0e-10);
+    assert!((fresnel_conductor(&0.0, &0.2, &3.0) - 1.0).abs() < 1.0e-10);
+    // no absorption: that of a dielectric
+    let (n, cos): (Flt, Flt) = (1.5, 0.6);
+    let cos_t = (1.0 - (1.0 - cos * cos) / (n * n)).sqrt();
+    let rs = ((cos - n * cos_t) / (cos + n * cos_t)).powi(2);
+    let rp = ((n * cos - cos_t) / (n * cos + cos_t)).powi(2);
+    assert!((fresnel_conductor(&cos, &n, &0.0) - 0.5 * (rs + rp)).abs() < 1.0e-10);
+    // rises to 1 toward the grazing angle
+    let r = |c: Flt| fresnel_conductor(&c, &1.2, &2.6);
+    assert!(r(0.2) > r(1.0) + 0.05);
+    assert!(r(0.05) > r(0.2) + 0.05);
+  }
+
+  #[test]
+  fn test_metal() {
+    assert_eq!(Metal::from_name("gold"), Some(Metal::Au));
+    assert_eq!(Metal::from_name("Cr"), Some(Metal::Cr));
+    assert_eq!(Metal::from_name("iron"), None);
+
+    let au = Conductor::Metal(Metal::Au);
+    assert_eq!(au.nk(&Wavelength(550.0)), (0.43, 2.45));
+    let (n, k) = au.nk(&Wavelength(525.0));
+    assert!((n - 0.70).abs() < 1.0e-10 && (k - 2.16).abs() < 1.0e-10);
+    assert_eq!(au.nk(&Wavelength(380.0)), au.nk(&Wavelength(400.0)));
+    assert_eq!(au.nk(&Wavelength(780.0)), au.nk(&Wavelength(700.0)));
+    // gold is yellow, silver and aluminium are white
+    let f = au.fresnel_color(&1.0);
+    assert!(f.0 > 0.9 && f.1 > 0.6 && f.2 < 0.45);
+    for m in [Metal::Ag, Metal::Al].iter() {
+      let f = Conductor::Metal(*m).fresnel_color(&1.0);
+      assert!(f.0 > 0.85 && f.1 > 0.85 && f.2 > 0.85);
+    }
+
+    let c = Conductor::Rgb(Color(0.2, 0.4, 1.4), Color(3.4, 2.3, 1.8));
+    assert_eq!(c.nk(&Wavelength::GREEN), (0.4, 2.3));
+    assert_eq!(c.nk(&Wavelength(700.0)), (0.2, 3.4));
+    assert!((c.fresnel_color(&1.0).2 - normal(1.4, 1.8)).abs() < 1.0e-10);
+  }
+}
@@ -0,0 +1,285 @@
+// disney
+//   Disney principled BRDF.
+//   Reference URLs
+//   https://media.disneyanimation.com/uploads/production/publication_asset/48/asset/s2012_pbs_disney_brdf_notes_v3.pdf
+//   https://github.com/wdas/brdf/blob/main/src/brdfs/disney.brdf
+//
+//   eye rays split it as the other surface models do: the diffuse lobes
+//   (diffuse, subsurface, sheen) weight the irradiance from the lights and
+//   the photon map, the specular lobes (specular, clearcoat) weight the
+//   radiance along one sampled reflection. photons sample every lobe.
+//   the anisotropy follows the tangent of the frame given, that of the shape.
+
+use super::*;
+use super::algebra::*;
+use super::microfacet::*;
+use super::physics::*;
+
+const ONE_PI: Flt = 1.0 / f64::consts::PI;
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct Disney {
+  pub base_color:      Color,
+  pub subsurface:      Flt,
+  pub metallic:        Flt,
+  pub specular:        Flt,
+  pub specular_tint:   Flt,
+  pub roughness:       Flt,
+  pub anisotropic:     Flt,
+  pub sheen:           Flt,
+  pub sheen_tint:      Flt,
+  pub clearcoat:       Flt,
+  pub clearcoat_gloss: Flt,
+}
+
+impl Disney {
+  // the others are the defaults of the reference implementation
+  pub fn new(base_color: &Color) -> Disney {
+    Disney {
+      base_color:      *base_color,
+      subsurface:      0.0,
+      metallic:        0.0,
+      specular:        0.5,
+      specular_tint:   0.0,
+      roughness:       0.5,
+      anisotropic:     0.0,
+      sheen:           0.0,
+      sheen_tint:      0.5,
+      clearcoat:       0.0,
+      clearcoat_gloss: 1.0,
+    }
+  }
+
+  pub fn albedo_diff(&self) -> Color {
+    (1.0 - self.metallic) * self.base_color
+  }
+
+  // reflectance at normal incidence
+  pub fn albedo_spec(&self) -> Color {
+    let tint = mix_color(&Color::WHITE, &self.tint(), &self.specular_tint);
+    mix_color(&(self.specular * 0.08 * tint), &self.base_color, &self.metallic)
+  }
+
+  // BRDF of the diffuse lobes for irradiance.
+  // the directions of the incoming light are not known here: it is taken from the normal.
+  // IN : vvec  eye direction (from surface)
+  pub fn diffuse(&self, nvec: &Direction3, vvec: &Direction3) -> Color {
+    let cos_v = nvec.dot(vvec);
+    if cos_v <= 0.0 {
+      return Color::BLACK
+    }
+    let cos_d = f64::sqrt((1.0 + cos_v) * 0.5);  // (l.h) with l = n
+    self.diffuse_lobes(&1.0, &cos_v, &cos_d)
+  }
+
+  // a reflection direction by the specular lobes
+  // IN : fr    local frame of the surface (its tangent for the anisotropy)
+  //      vvec  eye direction (from surface)
+  //      rdir  mirror direction, used if no sample is above the surface
+  pub fn sample_specular(&self, fr: &Frame, vvec: &Direction3, rdir: &Direction3) -> Direction3 {
+    let pc = self.clearcoat_probability(&fr.n.dot(vvec));
+    let (ax, ay) = self.alphas();
+    let ca = self.clearcoat_alpha();
+    let h = |v: &Vector3| if russian_roulette(&[pc]) == 0 { sample_gtr1(&ca) } else { sample_ggx_vndf(v, &ax, &ay) };
+    sample_reflection(fr, vvec, h).unwrap_or(*rdir)
+  }
+
+  // weight of the radiance along ldir sampled by sample_specular
+  pub fn specular(&self, fr: &Frame, vvec: &Direction3, ldir: &Direction3) -> Color {
+    let (l, v) = (fr.to_local(ldir), fr.to_local(vvec));
+    if l.v[1] <= 0.0 || v.v[1] <= 0.0 {
+      return Color::BLACK
+    }
+    let pc = self.clearcoat_probability(&v.v[1]);
+    let (ax, ay) = self.alphas();
+    let pdf = (1.0 - pc) * ggx_pdf_reflection(&l, &v, &ax, &ay)
+            + pc * gtr1_pdf_reflection(&l, &v, &self.clearcoat_alpha());
+    if pdf <= 0.0 {
+      return Color::BLACK
+    }
+    self.specular_lobes(&l, &v) * (l.v[1] / pdf)
+  }
+
+  // next direction of a photon, None if absorbed
+  // IN : vvec  photon direction (to surface)
+  pub fn scatter(&self, fr: &Frame, vvec: &Direction3, wl: &Wavelength) -> Option<Direction3> {
+    let v = fr.to_local(&-*vvec);
+    if v.v[1] <= 0.0 {
+      return None
+    }
+    let fv = schlick_weight(&v.v[1]);
+    let wd = (1.0 - self.metallic) * (luminance(&self.base_color) + self.sheen);
+    let ws = luminance(&mix_color(&self.albedo_spec(), &Color::WHITE, &fv));
+    let wc = self.clearcoat_weight(&fv);
+    let sum = wd + ws + wc;
+    if sum <= 0.0 {
+      return None
+    }
+    let (pd, ps, pc) = (wd / sum, ws / sum, wc / sum);
+    let (ax, ay) = self.alphas();
+    let ca = self.clearcoat_alpha();
+    let l = match russian_roulette(&[pd, pd + ps]) {
+      0 => sample_cosine(),
+      1 => reflect_local(&v, &sample_ggx_vndf(&v, &ax, &ay)),
+      _ => reflect_local(&v, &sample_gtr1(&ca)),
+    };
+    if l.v[1] <= 0.0 {
+      return None
+    }
+    let pdf = pd * l.v[1] * ONE_PI
+            + ps * ggx_pdf_reflection(&l, &v, &ax, &ay)
+            + pc * gtr1_pdf_reflection(&l, &v, &ca);
+    let h = (l + v).normalize()?;
+    let f = self.diffuse_lobes(&l.v[1], &v.v[1], &l.dot(&h)) + self.specular_lobes(&l, &v);
+    // survive by the ratio of the reflected flux
+    match russian_roulette(&[f.wavelength(wl) * l.v[1] / pdf]) {
+      0 => fr.to_world(&l).normalize(),
+      _ => None,
+    }
+  }
+
+  //--------------------
+  // private
+
+  // color without the luminance
+  fn tint(&self) -> Color {
+    let lum = luminance(&self.base_color);
+    if lum > 0.0 { self.base_color * (1.0 / lum) } else { Color::WHITE }
+  }
+
+  fn alphas(&self) -> (Flt, Flt) {
+    let aspect = f64::sqrt(1.0 - 0.9 * self.anisotropic);
+    let a = self.roughness * self.roughness;
+    ((a / aspect).max(MIN_ALPHA), (a * aspect).max(MIN_ALPHA))
+  }
+
+  fn clearcoat_alpha(&self) -> Flt {
+    mix(&0.1, &0.001, &self.clearcoat_gloss)
+  }
+
+  fn clearcoat_weight(&self, fv: &Flt) -> Flt {
+    0.25 * self.clearcoat * mix(&0.04, &1.0, fv)
+  }
+
+  // probability to sample the clearcoat rather than the specular lobe
+  fn clearcoat_probability(&self, cos_v: &Flt) -> Flt {
+    let fv = schlick_weight(cos_v);
+    let wc = self.clearcoat_weight(&fv);
+    let ws = luminance(&mix_color(&self.albedo_spec(), &Color::WHITE, &fv));
+    if wc + ws > 0.0 { wc / (wc + ws) } else { 0.0 }
+  }
+
+  // cos_d = (l.h)
+  fn diffuse_lobes(&self, cos_l: &Flt, cos_v: &Flt, cos_d: &Flt) -> Color {
+    let (fl, fv) = (schlick_weight(cos_l), schlick_weight(cos_v));
+    let rd = cos_d * cos_d * self.roughness;
+    let fd90 = 0.5 + 2.0 * rd;
+    let fd = mix(&1.0, &fd90, &fl) * mix(&1.0, &fd90, &fv);
+    // Hanrahan-Krueger like flattening for the subsurface
+    let fss = mix(&1.0, &rd, &fl) * mix(&1.0, &rd, &fv);
+    let ss = 1.25 * (fss * (1.0 / (cos_l + cos_v) - 0.5) + 0.5);
+    let sheen = self.sheen * schlick_weight(cos_d) * mix_color(&Color::WHITE, &self.tint(), &self.sheen_tint);
+    (1.0 - self.metallic) * (ONE_PI * mix(&fd, &ss, &self.subsurface) * self.base_color + sheen)
+  }
+
+  // BRDF of the specular lobes, local l and v
+  fn specular_lobes(&self, l: &Vector3, v: &Vector3) -> Color {
+    if l.v[1] <= 0.0 || v.v[1] <= 0.0 {
+      return Color::BLACK
+    }
+    let h = match (*l + *v).normalize() {
+      Some(h) => h,
+      None    => return Color::BLACK,
+    };
+    let fh = schlick_weight(&l.dot(&h));
+    let (ax, ay) = self.alphas();
+    let div = 1.0 / (4.0 * l.v[1] * v.v[1]);
+    let spec = ggx_d(&h, &ax, &ay) * ggx_g2(l, v, &ax, &ay) * div;
+    let coat = self.clearcoat_weight(&fh) * gtr1_d(&h.v[1], &self.clearcoat_alpha())
+             * ggx_g1(l, &0.25, &0.25) * ggx_g1(v, &0.25, &0.25) * div;
+    spec * mix_color(&self.albedo_spec(), &Color::WHITE, &fh) + coat * Color::WHITE
+  }
+}
+
+fn schlick_weight(cos: &Flt) -> Flt {
+  (1.0 - cos.clamp(0.0, 1.0)).powi(5)
+}
+
+fn mix(a: &Flt, b: &Flt, t: &Flt) -> Flt {
+  a + (b - a) * t
+}
+
+fn mix_color(a: &Color, b: &Color, t: &Flt) -> Color {
+  (1.0 - t) * *a + *t * *b
+}
+
+fn luminance(c: &Color) -> Flt {
+  0.3 * c.0 + 0.6 * c.1 + 0.1 * c.2
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  // reflected part of the flux from v by photons
+  fn albedo(d: &Disney, v: &Direction3, wl: &Wavelength) -> Flt {
+    let n = 100000;
+    let k = (0..n).filter(|_| d.scatter(&Frame::new(&Vector3::EY), &-*v, wl).is_some()).count();
+    k as Flt / n as Flt
+  }
+
+  #[test]
+  fn test_disney() {
+    let v = Vector3::new(0.3, 0.8, 0.1).normalize().unwrap();
+    let mut d = Disney::new(&Color(0.8, 0.4, 0.1));
+    // a dull dielectric is nearly Lambertian
+    d.roughness = 1.0;
+    let a = albedo(&d, &v, &Wavelength::RED);
+    assert!(a > 0.75 && a < 0.95, "{}", a);
+    let a = albedo(&d, &v, &Wavelength::BLUE);
+    assert!(a > 0.1 && a < 0.2, "{}", a);
+    assert!((d.diffuse(&Vector3::EY, &Vector3::EY).0 - 0.8 * ONE_PI).abs() < 0.05);
+
+    // a white metal reflects everything but the masked part
+    let mut m = Disney::new(&Color::WHITE);
+    m.metallic = 1.0;
+    m.roughness = 0.3;
+    assert_eq!(m.diffuse(&Vector3::EY, &v), Color::BLACK);
+    let a = albedo(&m, &v, &Wavelength::GREEN);
+    assert!(a > 0.9 && a <= 1.0, "{}", a);
+    // the weight of a sample is the masking of the light: low only toward the grazing angle
+    let r = Vector3::new(-0.3, 0.8, -0.1).normalize().unwrap();
+    let fr = Frame::new(&Vector3::EY);
+    let n = 1000;
+    let mut sum = 0.0;
+    for _ in 0..n {
+      let l = m.sample_specular(&fr, &v, &r);
+      assert!(l.v[1] > 0.0);
+      let w = m.specular(&fr, &v, &l);
+      assert!(w.1 > 0.0 && w.1 <= 1.0 + 1.0e-9, "{}", w);
+      sum += w.1;
+    }
+    assert!(sum / n as Flt > 0.9, "{}", sum / n as Flt);
+
+    // anisotropy stretches the highlight along the tangent
+    m.anisotropic = 1.0;
+    let (ax, ay) = m.alphas();
+    assert!(ax > ay);
+    let (mut sx, mut sz) = (0.0, 0.0);
+    for _ in 0..10000 {
+      let l = m.sample_specular(&fr, &Vector3::EY, &Vector3::EY);
+      sx += l.v[0] * l.v[0];
+      sz += l.v[2] * l.v[2];
+    }
+    assert!(sx > sz * 2.0);
+    // ... that of the shape
+    let fr = Frame::with_tangent(&Vector3::EY, &Vector3::EZ);
+    let (mut sx, mut sz) = (0.0, 0.0);
+    for _ in 0..10000 {
+      let l = m.sample_specular(&fr, &Vector3::EY, &Vector3::EY);
+      sx += l.v[0] * l.v[0];
+      sz += l.v[2] * l.v[2];
+    }
+    assert!(sz > sx * 2.0);
+  }
+}
@@ -1,9 +1,9 @@
 // geometry
 
-use core::num::ParseFloatError;
 use std::f64;
 use std::fmt;
 use std::str::*;
+use std::sync::Arc;
 
 use regex::Regex;
 
@@ -27,11 +27,11 @@ impl fmt::Display for Ray {
 }
 
 impl FromStr for Ray {
-  type Err = ParseFloatError;
+  type Err = String;
 
   fn from_str(s: &str) -> Result<Self, Self::Err> {
     let re = Regex::new(r"^RAY\[(V3\[\S+\]),(V3\[\S+\])\]$").unwrap();
-    let caps = re.captures(s).unwrap();
+    let caps = re.captures(s).ok_or(format!("invalid ray: {}", s))?;
     let p = caps[1].parse::<Vector3>()?;
     let d = caps[2].parse::<Vector3>()?;
     Ok(Ray::new(&p, &d))
@@ -62,7 +62,18 @@ impl Ray {
 // ---------------------
 // shape
 
+// where a ray hits a shape
+//   prim:   part of the shape (triangle of a mesh, 0 for the others)
+//   (u, v): barycentric coordinates on a triangle
 #[derive(Debug, PartialEq, Clone, Copy)]
+pub struct Hit {
+  pub dist: Flt,
+  pub prim: usize,
+  pub u: Flt,
+  pub v: Flt,
+}
+
+#[derive(Debug, PartialEq, Clone)]
 pub enum Shape {
   Point {
     position: Position3,
@@ -87,6 +98,51 @@ pub enum Shape {
     dir1: Direction3,
     dir2: Direction3,    
   },
+  Mesh {
+    mesh: Arc<Mesh>,
+  },
+  // axis aligned, see new_oriented_box() for the others
+  Box {
+    min: Position3,
+    max: Position3,
+  },
+  // capped. position is the center of the bottom, the top is position + axis * height
+  Cylinder {
+    position: Position3,
+    axis: Direction3,
+    height: Flt,
+    radius: Flt,
+  },
+  // capped at the base. position is the center of the base, the apex is position + axis * height
+  Cone {
+    position: Position3,
+    axis: Direction3,
+    height: Flt,
+    radius: Flt,
+  },
+  Disk {
+    center: Position3,
+    nvec: Direction3,
+    radius: Flt,
+  },
+  // major: radius of the center line of the tube, minor: radius of the tube
+  Torus {
+    center: Position3,
+    axis: Direction3,
+    major: Flt,
+    minor: Flt,
+  },
+  // a shared shape placed by a transform (object space -> world)
+  Instance {
+    shape: Arc<Shape>,
+    transform: Transform,
+  },
+  // combination of closed shapes, see is_closed()
+  Csg {
+    op: CsgOp,
+    left: Arc<Shape>,
+    right: Arc<Shape>,
+  },
 }
 
 impl Shape {
@@ -114,6 +170,48 @@ impl Shape {
     }
   }
 
+  // a parallelepiped of the edges d1, d2 and d3 from p0
+  pub fn new_oriented_box(p0: &Position3, d1: &Vector3, d2: &Vector3, d3: &Vector3) -> Option<Shape> {
+    let t = Transform::frame(p0, d1, d2, d3)?;
+    Some(Shape::new_instance(&Shape::Box {min: Vector3::O, max: Vector3::new(1.0, 1.0, 1.0)}, &t))
+  }
+
+  // axis: from the center of the bottom to the center of the top
+  pub fn new_cylinder(p0: &Position3, axis: &Vector3, radius: Flt) -> Option<Shape> {
+    Some(Shape::Cylinder {position: *p0, axis: axis.normalize()?, height: axis.norm(), radius})
+  }
+
+  // axis: from the center of the base to the apex
+  pub fn new_cone(p0: &Position3, axis: &Vector3, radius: Flt) -> Option<Shape> {
+    Some(Shape::Cone {position: *p0, axis: axis.normalize()?, height: axis.norm(), radius})
+  }
+
+  // None: an operand is not closed
+  pub fn new_csg(op: CsgOp, left: &Shape, right: &Shape) -> Option<Shape> {
+    if !left.is_closed() || !right.is_closed() {
+      return None;
+    }
+    Some(Shape::Csg {op, left: Arc::new(left.clone()), right: Arc::new(right.clone())})
+  }
+
+  // shapes with an inside, usable in CSG. a plain is the half space behind it,
+  // a mesh is taken as closed.
+  pub fn is_closed(&self) -> bool {
+    match self {
+      Shape::Point {..} | Shape::Polygon {..} | Shape::Parallelogram {..} | Shape::Disk {..} => false,
+      Shape::Instance {shape, transform: _} => shape.is_closed(),
+      _ => true,
+    }
+  }
+
+  // an instance of an instance is flattened
+  pub fn new_instance(s: &Shape, t: &Transform) -> Shape {
+    match s {
+      Shape::Instance {shape, transform} => Shape::Instance {shape: shape.clone(), transform: transform.then(t)},
+      _ => Shape::Instance {shape: Arc::new(s.clone()), transform: *t},
+    }
+  }
+
   pub fn get_normal(&self, p: &Position3) -> Option<Direction3> {
     match self {
       Shape::Point {position: _}
@@ -126,6 +224,164 @@ impl Shape {
         => Some(*nvec),
       Shape::Parallelogram {position: _, nvec, dir1: _, dir2: _}
         => Some(*nvec),
+      Shape::Mesh {mesh: _}
+        => None,    // depends on the triangle, see normal_at()
+      Shape::Box {min, max}
+        => Some(normal_box(p, min, max)),
+      Shape::Cylinder {position, axis, height, radius}
+        => normal_cylinder(p, position, axis, height, radius),
+      Shape::Cone {position, axis, height, radius}
+        => normal_cone(p, position, axis, height, radius),
+      Shape::Disk {center: _, nvec, radius: _}
+        => Some(*nvec),
+      Shape::Torus {center, axis, major, minor: _}
+        => normal_torus(p, center, axis, major),
+      Shape::Instance {shape, transform}
+        => transform.normal(&shape.get_normal(&transform.inverse_position(p))?),
+      Shape::Csg {..}
+        => None,    // depends on the operand, see normal_at()
+    }
+  }
+
+  // normal at a point given by nearest_hit()
+  pub fn normal_at(&self, p: &Position3, h: &Hit) -> Option<Direction3> {
+    match self {
+      Shape::Mesh {mesh} => mesh.normal(h.prim, h.u, h.v),
+      Shape::Instance {shape, transform}
+        => transform.normal(&shape.normal_at(&transform.inverse_position(p), h)?),
+      Shape::Csg {op, left, right} => {
+        // the inside of the subtracted shape is the outside of the result
+        let h2 = Hit {prim: h.prim / 2, ..*h};
+        match h.prim % 2 {
+          0                             => left.normal_at(p, &h2),
+          _ if *op == CsgOp::Difference => right.normal_at(p, &h2).map(|n| -n),
+          _                             => right.normal_at(p, &h2),
+        }
+      },
+      _                  => self.get_normal(p),
+    }
+  }
+
+  // tangent at a point given by nearest_hit(), for anisotropic surfaces: around
+  // the axis of round shapes, along dir1 of flat ones and along u of a mesh with uvs.
+  // None where the shape has none (the surface makes one).
+  pub fn tangent_at(&self, p: &Position3, h: &Hit) -> Option<Direction3> {
+    match self {
+      Shape::Sphere {center, radius: _}
+        => Vector3::EY.cross(&(*p - *center)).normalize(),
+      Shape::Polygon {position: _, nvec: _, dir1, dir2: _}
+        => dir1.normalize(),
+      Shape::Parallelogram {position: _, nvec: _, dir1, dir2: _}
+        => dir1.normalize(),
+      Shape::Cylinder {position, axis, ..} | Shape::Cone {position, axis, ..}
+        => axis.cross(&(*p - *position)).normalize(),
+      Shape::Disk {center, nvec, radius: _}
+        => nvec.cross(&(*p - *center)).normalize(),
+      Shape::Torus {center, axis, ..}
+        => axis.cross(&(*p - *center)).normalize(),
+      Shape::Mesh {mesh}
+        => mesh.tangent(h.prim),
+      Shape::Instance {shape, transform}
+        => transform.direction(&shape.tangent_at(&transform.inverse_position(p), h)?).normalize(),
+      Shape::Csg {op: _, left, right} => {
+        let h2 = Hit {prim: h.prim / 2, ..*h};
+        match h.prim % 2 {
+          0 => left.tangent_at(p, &h2),
+          _ => right.tangent_at(p, &h2),
+        }
+      },
+      _ => None,
+    }
+  }
+
+  // number of parts which are bounded and tested separately
+  pub fn nprim(&self) -> usize {
+    match self {
+      Shape::Mesh {mesh} => mesh.ntriangle(),
+      Shape::Instance {shape, transform: _} => shape.nprim(),
+      _                  => 1,
+    }
+  }
+
+  pub fn prim_bounding_box(&self, i: usize) -> Option<Aabb> {
+    match self {
+      Shape::Mesh {mesh} => Some(mesh.triangle_box(i)),
+      Shape::Instance {shape, transform}
+        => shape.prim_bounding_box(i).map(|b| b.transform(transform)),
+      _                  => self.bounding_box(),
+    }
+  }
+
+  // nearest hit on a part farther than NEARLY0
+  pub fn nearest_hit(&self, i: usize, r: &Ray) -> Option<Hit> {
+    self.nearest_hit_after(i, r, NEARLY0)
+  }
+
+  fn nearest_hit_after(&self, i: usize, r: &Ray, tmin: Flt) -> Option<Hit> {
+    match self {
+      Shape::Mesh {mesh} => {
+        let (t, u, v) = mesh.intersect(i, r)?;
+        if t < tmin { None } else { Some(Hit {dist: t, prim: i, u, v}) }
+      },
+      Shape::Instance {shape, transform} => {
+        let (r2, s) = local_ray(transform, r)?;
+        let h = shape.nearest_hit_after(i, &r2, tmin * s)?;
+        Some(Hit {dist: h.dist / s, ..h})
+      },
+      Shape::Csg {..} => self.intervals(r).into_iter()
+        .flat_map(|(h0, h1)| vec![h0, h1])
+        .find(|h| h.dist >= tmin && h.dist.is_finite()),
+      _ => {
+        let ts = self.distance(r);
+        let t = ts.iter().filter(|t| **t >= tmin).fold(f64::INFINITY, |t0, t| t0.min(*t));
+        if t == f64::INFINITY { None } else { Some(Hit {dist: t, prim: 0, u: 0.0, v: 0.0}) }
+      },
+    }
+  }
+
+  // None: the shape is not bounded (or has no area)
+  pub fn bounding_box(&self) -> Option<Aabb> {
+    match self {
+      Shape::Point {position: _}
+        => None,
+      Shape::Plain {nvec: _, dist: _}
+        => None,
+      Shape::Sphere {center, radius}
+        => {
+          let r = Vector3::new(*radius, *radius, *radius);
+          Some(Aabb::new(&[*center - r, *center + r]))
+        },
+      Shape::Polygon {position, nvec: _, dir1, dir2}
+        => Some(Aabb::new(&[*position, *position + *dir1, *position + *dir2])),
+      Shape::Parallelogram {position, nvec: _, dir1, dir2}
+        => Some(Aabb::new(&[*position, *position + *dir1, *position + *dir2, *position + *dir1 + *dir2])),
+      Shape::Mesh {mesh}
+        => if mesh.positions.is_empty() { None } else { Some(Aabb::new(&mesh.positions)) },
+      Shape::Box {min, max}
+        => Some(Aabb::new(&[*min, *max])),
+      Shape::Cylinder {position, axis, height, radius}
+        => Some(disk_box(position, axis, radius).merge(&disk_box(&(*position + *axis * *height), axis, radius))),
+      Shape::Cone {position, axis, height, radius}
+        => Some(disk_box(position, axis, radius).merge(&Aabb::new(&[*position + *axis * *height]))),
+      Shape::Disk {center, nvec, radius}
+        => Some(disk_box(center, nvec, radius)),
+      Shape::Torus {center, axis, major, minor}
+        => {
+          // the center line of the tube, grown by the tube
+          let b = disk_box(center, axis, major);
+          let m = Vector3::new(*minor, *minor, *minor);
+          Some(Aabb::new(&[b.min - m, b.max + m]))
+        },
+      Shape::Instance {shape, transform}
+        => shape.bounding_box().map(|b| b.transform(transform)),
+      Shape::Csg {op, left, right}
+        => match (op, left.bounding_box(), right.bounding_box()) {
+          (CsgOp::Union, Some(b0), Some(b1))        => Some(b0.merge(&b1)),
+          (CsgOp::Union, _, _)                      => None,
+          (CsgOp::Intersection, Some(b0), Some(b1)) => b0.overlap(&b1),
+          (CsgOp::Intersection, b0, b1)             => b0.or(b1),
+          (CsgOp::Difference, b0, _)                => b0,
+        },
     }
   }
 
@@ -141,9 +397,331 @@ impl Shape {
         => distance_polygon(&1.0, r, position, dir1, dir2),
       Shape::Parallelogram {position, nvec: _, dir1, dir2}
         => distance_polygon(&2.0, r, position, dir1, dir2),
+      Shape::Mesh {mesh}
+        => (0..mesh.ntriangle()).filter_map(|i| mesh.intersect(i, r)).map(|(t, _, _)| t).collect(),
+      Shape::Box {min, max}
+        => distance_box(r, min, max),
+      Shape::Cylinder {position, axis, height, radius}
+        => distance_cylinder(r, position, axis, height, radius),
+      Shape::Cone {position, axis, height, radius}
+        => distance_cone(r, position, axis, height, radius),
+      Shape::Disk {center, nvec, radius}
+        => distance_disk(r, center, nvec, radius),
+      Shape::Torus {center, axis, major, minor}
+        => distance_torus(r, center, axis, major, minor),
+      Shape::Instance {shape, transform}
+        => match local_ray(transform, r) {
+          Some((r2, s)) => shape.distance(&r2).iter().map(|t| t / s).collect(),
+          None          => vec![],
+        },
+      Shape::Csg {..}
+        => self.intervals(r).iter()
+          .flat_map(|(h0, h1)| vec![h0.dist, h1.dist])
+          .filter(|t| t.is_finite())
+          .collect(),
+    }
+  }
+
+  // every hit on the line of a ray, before or after the origin
+  fn hits(&self, r: &Ray) -> Vec<Hit> {
+    match self {
+      Shape::Mesh {mesh} => (0..mesh.ntriangle())
+        .filter_map(|i| mesh.intersect(i, r).map(|(t, u, v)| Hit {dist: t, prim: i, u, v}))
+        .collect(),
+      Shape::Instance {shape, transform} => match local_ray(transform, r) {
+        Some((r2, s)) => shape.hits(&r2).into_iter().map(|h| Hit {dist: h.dist / s, ..h}).collect(),
+        None          => vec![],
+      },
+      _ => self.distance(r).into_iter().map(|t| Hit {dist: t, prim: 0, u: 0.0, v: 0.0}).collect(),
+    }
+  }
+
+  // sorted intervals of a ray inside a closed shape.
+  // the hits of the operands of a CSG are told by prim: 2 * prim + (0: left, 1: right).
+  fn intervals(&self, r: &Ray) -> Vec<(Hit, Hit)> {
+    let far = |t: Flt| Hit {dist: t, prim: 0, u: 0.0, v: 0.0};
+    match self {
+      Shape::Plain {nvec, dist} => {
+        let cos0 = nvec.dot(&r.dir);
+        let behind = nvec.dot(&r.pos) + dist < 0.0;
+        match self.hits(r).first() {
+          Some(h) if cos0 > 0.0 => vec![(far(f64::NEG_INFINITY), *h)],
+          Some(h)               => vec![(*h, far(f64::INFINITY))],
+          None if behind        => vec![(far(f64::NEG_INFINITY), far(f64::INFINITY))],
+          None                  => vec![],
+        }
+      },
+      Shape::Instance {shape, transform} => match local_ray(transform, r) {
+        Some((r2, s)) => shape.intervals(&r2).into_iter()
+          .map(|(h0, h1)| (Hit {dist: h0.dist / s, ..h0}, Hit {dist: h1.dist / s, ..h1}))
+          .collect(),
+        None => vec![],
+      },
+      Shape::Csg {op, left, right} => {
+        let side = |is: Vec<(Hit, Hit)>, k: usize| -> Vec<(Hit, Hit)> {
+          let tag = |h: Hit| Hit {prim: h.prim * 2 + k, ..h};
+          is.into_iter().map(|(h0, h1)| (tag(h0), tag(h1))).collect()
+        };
+        combine_intervals(*op, &side(left.intervals(r), 0), &side(right.intervals(r), 1))
+      },
+      _ => {
+        let mut hs = self.hits(r);
+        hs.sort_by(|a, b| a.dist.partial_cmp(&b.dist).unwrap());
+        // a ray through an edge of a mesh hits both triangles
+        hs.dedup_by(|a, b| (a.dist - b.dist).abs() < 1.0e-9);
+        hs.chunks_exact(2).map(|c| (c[0], c[1])).collect()
+      },
+    }
+  }
+
+}
+
+// ---------------------
+// constructive solid geometry
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum CsgOp {
+  Union,
+  Intersection,
+  Difference,   // left - right
+}
+
+impl CsgOp {
+  fn inside(&self, a: bool, b: bool) -> bool {
+    match self {
+      CsgOp::Union        => a || b,
+      CsgOp::Intersection => a && b,
+      CsgOp::Difference   => a && !b,
+    }
+  }
+}
+
+// sweep over the ends of both interval lists
+fn combine_intervals(op: CsgOp, a: &[(Hit, Hit)], b: &[(Hit, Hit)]) -> Vec<(Hit, Hit)> {
+  let mut ends: Vec<(Hit, bool, bool)> = vec![];   // (hit, from a, entering)
+  for (is, from_a) in [(a, true), (b, false)].iter() {
+    for (h0, h1) in is.iter() {
+      ends.push((*h0, *from_a, true));
+      ends.push((*h1, *from_a, false));
+    }
+  }
+  ends.sort_by(|x, y| x.0.dist.partial_cmp(&y.0.dist).unwrap());
+  let (mut in_a, mut in_b) = (false, false);
+  let mut start: Option<Hit> = None;
+  let mut res: Vec<(Hit, Hit)> = vec![];
+  for (h, from_a, entering) in ends {
+    if from_a { in_a = entering; } else { in_b = entering; }
+    match (op.inside(in_a, in_b), start) {
+      // an interval touching the last one continues it
+      (true, None) => start = match res.last() {
+        Some((_, h1)) if h1.dist == h.dist => res.pop().map(|(h0, _)| h0),
+        _                                  => Some(h),
+      },
+      (false, Some(h0)) => {
+        if h0.dist < h.dist {
+          res.push((h0, h));
+        }
+        start = None;
+      },
+      _ => (),
+    }
+  }
+  res
+}
+
+// ---------------------
+// triangle mesh
+//   vertex buffers and triangle indices are shared by all the triangles.
+//   normals and uvs, if given, are per vertex.
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct Mesh {
+  pub positions: Vec<Position3>,
+  pub normals: Option<Vec<Direction3>>,
+  pub uvs: Option<Vec<(Flt, Flt)>>,
+  pub indices: Vec<[usize; 3]>,
+}
+
+impl Mesh {
+  pub fn new(positions: Vec<Position3>, normals: Option<Vec<Direction3>>, uvs: Option<Vec<(Flt, Flt)>>, indices: Vec<[usize; 3]>) -> Result<Mesh, String> {
+    let nv = positions.len();
+    if let Some(ns) = &normals {
+      if ns.len() != nv {
+        return Err(format!("{} normals for {} positions", ns.len(), nv));
+      }
+    }
+    if let Some(ts) = &uvs {
+      if ts.len() != nv {
+        return Err(format!("{} uvs for {} positions", ts.len(), nv));
+      }
+    }
+    if let Some(i) = indices.iter().flatten().find(|i| **i >= nv) {
+      return Err(format!("index {} out of {} positions", i, nv));
+    }
+    Ok(Mesh {positions, normals, uvs, indices})
+  }
+
+  pub fn ntriangle(&self) -> usize {
+    self.indices.len()
+  }
+
+  // remove triangles with no area (they have no normal).
+  // OUT: number of removed triangles
+  pub fn remove_degenerate(&mut self) -> usize {
+    let n = self.indices.len();
+    let ps = &self.positions;
+    self.indices.retain(|[a, b, c]| {
+      let nv = (ps[*b] - ps[*a]).cross(&(ps[*c] - ps[*a])).norm();
+      nv > 0.0 && nv.is_finite()
+    });
+    n - self.indices.len()
+  }
+
+  fn vertices(&self, i: usize) -> (Position3, Position3, Position3) {
+    let [a, b, c] = self.indices[i];
+    (self.positions[a], self.positions[b], self.positions[c])
+  }
+
+  pub fn triangle_box(&self, i: usize) -> Aabb {
+    let (p0, p1, p2) = self.vertices(i);
+    Aabb::new(&[p0, p1, p2])
+  }
+
+  // OUT: (distance, u, v)
+  pub fn intersect(&self, i: usize, r: &Ray) -> Option<(Flt, Flt, Flt)> {
+    let (p0, p1, p2) = self.vertices(i);
+    let (u, v, t) = method_moller(&1.0, &p0, &(p1 - p0), &(p2 - p0), &r.pos, &r.dir)?;
+    Some((t, u, v))
+  }
+
+  // interpolated vertex normals, or the normal of the triangle
+  pub fn normal(&self, i: usize, u: Flt, v: Flt) -> Option<Direction3> {
+    let [a, b, c] = self.indices[i];
+    match &self.normals {
+      Some(ns) => ((1.0 - u - v) * ns[a] + u * ns[b] + v * ns[c]).normalize(),
+      None     => {
+        let (p0, p1, p2) = self.vertices(i);
+        (p1 - p0).cross(&(p2 - p0)).normalize()
+      },
     }
   }
 
+  // direction of increasing u of the uvs on a triangle
+  pub fn tangent(&self, i: usize) -> Option<Direction3> {
+    let [a, b, c] = self.indices[i];
+    let ts = self.uvs.as_ref()?;
+    let (p0, p1, p2) = self.vertices(i);
+    let (du1, dv1) = (ts[b].0 - ts[a].0, ts[b].1 - ts[a].1);
+    let (du2, dv2) = (ts[c].0 - ts[a].0, ts[c].1 - ts[a].1);
+    let det = du1 * dv2 - du2 * dv1;
+    if det == 0.0 {
+      return None
+    }
+    (((p1 - p0) * dv2 - (p2 - p0) * dv1) * (1.0 / det)).normalize()
+  }
+
+  pub fn uv(&self, i: usize, u: Flt, v: Flt) -> Option<(Flt, Flt)> {
+    let [a, b, c] = self.indices[i];
+    let ts = self.uvs.as_ref()?;
+    let w = 1.0 - u - v;
+    Some((w * ts[a].0 + u * ts[b].0 + v * ts[c].0, w * ts[a].1 + u * ts[b].1 + v * ts[c].1))
+  }
+}
+
+// ---------------------
+// axis aligned bounding box
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct Aabb {
+  pub min: Position3,
+  pub max: Position3,
+}
+
+impl Aabb {
+  // boxes are padded a little so that flat shapes have a thickness
+  pub fn new(ps: &[Position3]) -> Aabb {
+    let mut min = [f64::INFINITY; 3];
+    let mut max = [f64::NEG_INFINITY; 3];
+    for p in ps {
+      for i in 0..3 {
+        min[i] = min[i].min(p.v[i] - NEARLY0);
+        max[i] = max[i].max(p.v[i] + NEARLY0);
+      }
+    }
+    Aabb {min: Vector3 {v: min}, max: Vector3 {v: max}}
+  }
+
+  pub fn merge(&self, b: &Aabb) -> Aabb {
+    let mut min = self.min;
+    let mut max = self.max;
+    for i in 0..3 {
+      min.v[i] = min.v[i].min(b.min.v[i]);
+      max.v[i] = max.v[i].max(b.max.v[i]);
+    }
+    Aabb {min, max}
+  }
+
+  // the box containing this box moved by t
+  pub fn transform(&self, t: &Transform) -> Aabb {
+    let corners: Vec<Position3> = (0..8)
+      .map(|k| {
+        let pick = |i: usize| if k & (1 << i) == 0 { self.min.v[i] } else { self.max.v[i] };
+        t.position(&Vector3::new(pick(0), pick(1), pick(2)))
+      })
+      .collect();
+    Aabb::new(&corners)
+  }
+
+  // None: no common part
+  pub fn overlap(&self, b: &Aabb) -> Option<Aabb> {
+    let mut min = self.min;
+    let mut max = self.max;
+    for i in 0..3 {
+      min.v[i] = min.v[i].max(b.min.v[i]);
+      max.v[i] = max.v[i].min(b.max.v[i]);
+      if min.v[i] > max.v[i] {
+        return None;
+      }
+    }
+    Some(Aabb {min, max})
+  }
+
+  pub fn centroid(&self) -> Position3 {
+    0.5 * (self.min + self.max)
+  }
+
+  pub fn area(&self) -> Flt {
+    let d = self.max - self.min;
+    2.0 * (d.v[0] * d.v[1] + d.v[1] * d.v[2] + d.v[2] * d.v[0])
+  }
+
+  // slab method
+  //   inv: reciprocal of ray direction
+  //   OUT: distance to enter the box, if it is nearer than tmax
+  pub fn hit(&self, r: &Ray, inv: &Vector3, tmax: Flt) -> Option<Flt> {
+    let mut t0: Flt = 0.0;
+    let mut t1 = tmax;
+    for i in 0..3 {
+      // parallel to the slab: 0 * inf would be NaN on its planes
+      if r.dir.v[i] == 0.0 {
+        if r.pos.v[i] < self.min.v[i] || r.pos.v[i] > self.max.v[i] {
+          return None
+        }
+        continue;
+      }
+      let mut tn = (self.min.v[i] - r.pos.v[i]) * inv.v[i];
+      let mut tf = (self.max.v[i] - r.pos.v[i]) * inv.v[i];
+      if tn > tf {
+        std::mem::swap(&mut tn, &mut tf);
+      }
+      t0 = if tn > t0 { tn } else { t0 };
+      t1 = if tf < t1 { tf } else { t1 };
+      if t0 > t1 {
+        return None
+      }
+    }
+    Some(t0)
+  }
 }
 
 pub fn method_moller(l: &Flt, p0: &Position3, d1: &Direction3, d2: &Direction3, p: &Position3, d: &Direction3) -> Option<(Flt, Flt, Flt)> {
@@ -167,6 +745,13 @@ pub fn method_moller(l: &Flt, p0: &Position3, d1: &Direction3, d2: &Direction3,
 
 // utility functions
 
+// the ray in the object space of a transform, with a unit direction.
+//   OUT: (ray, scale), distance in the world = distance in the object space / scale
+fn local_ray(t: &Transform, r: &Ray) -> Option<(Ray, Flt)> {
+  let d = t.inverse_direction(&r.dir);
+  Some((Ray::new(&t.inverse_position(&r.pos), &d.normalize()?), d.norm()))
+}
+
 fn distance_plain(r: &Ray, n: &Direction3, d: &Flt) -> Vec<Flt> {
   let cos0 = n.dot(&r.dir);
   if cos0 == 0.0 {
@@ -201,6 +786,217 @@ fn distance_polygon(l: &Flt, r: &Ray, p: &Position3, d1: &Direction3, d2: &Direc
   }
 }
 
+// slab method, entering and leaving
+fn distance_box(r: &Ray, min: &Position3, max: &Position3) -> Vec<Flt> {
+  let mut t0 = f64::NEG_INFINITY;
+  let mut t1 = f64::INFINITY;
+  for i in 0..3 {
+    let (p, d) = (r.pos.v[i], r.dir.v[i]);
+    if d == 0.0 {
+      if p < min.v[i] || p > max.v[i] {
+        return vec![];
+      }
+      continue;
+    }
+    let (tn, tf) = ((min.v[i] - p) / d, (max.v[i] - p) / d);
+    t0 = t0.max(tn.min(tf));
+    t1 = t1.min(tn.max(tf));
+  }
+  if t0 > t1 { vec![] } else { vec![t0, t1] }
+}
+
+// the side, then the caps (positions along the axis from 0 to height)
+fn distance_cylinder(r: &Ray, p: &Position3, a: &Direction3, h: &Flt, rad: &Flt) -> Vec<Flt> {
+  let (o, oa, da) = along_axis(r, p, a);
+  let or = o - *a * oa;
+  let dr = r.dir - *a * da;
+  let mut ts = solve_quadratic(dr.square(), 2.0 * or.dot(&dr), or.square() - rad * rad);
+  ts.retain(|t| (0.0..=*h).contains(&(oa + t * da)));
+  if da != 0.0 {
+    for hc in [0.0, *h].iter() {
+      let t = (hc - oa) / da;
+      if (or + dr * t).square() <= rad * rad {
+        ts.push(t);
+      }
+    }
+  }
+  ts.sort_by(|a, b| a.partial_cmp(b).unwrap());
+  ts
+}
+
+// the side: |q_r| = k (h - q_a) with k = radius / height, then the base
+fn distance_cone(r: &Ray, p: &Position3, a: &Direction3, h: &Flt, rad: &Flt) -> Vec<Flt> {
+  let (o, oa, da) = along_axis(r, p, a);
+  let or = o - *a * oa;
+  let dr = r.dir - *a * da;
+  let k2 = (rad / h) * (rad / h);
+  let w0 = h - oa;
+  let mut ts = solve_quadratic(dr.square() - k2 * da * da, 2.0 * (or.dot(&dr) + k2 * w0 * da), or.square() - k2 * w0 * w0);
+  ts.retain(|t| (0.0..=*h).contains(&(oa + t * da)));
+  if da != 0.0 {
+    let t = -oa / da;
+    if (or + dr * t).square() <= rad * rad {
+      ts.push(t);
+    }
+  }
+  ts.sort_by(|a, b| a.partial_cmp(b).unwrap());
+  ts
+}
+
+fn distance_disk(r: &Ray, c: &Position3, n: &Direction3, rad: &Flt) -> Vec<Flt> {
+  distance_plain(r, n, &-n.dot(c)).into_iter()
+    .filter(|t| (r.target(*t) - *c).square() <= rad * rad)
+    .collect()
+}
+
+// (|q|^2 + R^2 - r^2)^2 = 4 R^2 (|q|^2 - (q.a)^2), q = o + t d.
+// the origin is moved to the nearest point to the center to keep the quartic accurate.
+fn distance_torus(r: &Ray, c: &Position3, a: &Direction3, major: &Flt, minor: &Flt) -> Vec<Flt> {
+  let tc = (*c - r.pos).dot(&r.dir);
+  let o = r.target(tc) - *c;
+  let rr = major + minor;
+  if o.square() > rr * rr {
+    return vec![];
+  }
+  let (m, n, oa, da) = (o.square(), o.dot(&r.dir), o.dot(a), r.dir.dot(a));
+  let k = m + major * major - minor * minor;
+  let r4 = 4.0 * major * major;
+  let cs = [
+    k * k - r4 * (m - oa * oa),
+    4.0 * n * k - 2.0 * r4 * (n - oa * da),
+    4.0 * n * n + 2.0 * k - r4 * (1.0 - da * da),
+    4.0 * n,
+    1.0,
+  ];
+  poly_roots(&cs, -rr, rr).iter().map(|t| t + tc).collect()
+}
+
+// OUT: (origin relative to p, its position along a, direction along a)
+fn along_axis(r: &Ray, p: &Position3, a: &Direction3) -> (Vector3, Flt, Flt) {
+  let o = r.pos - *p;
+  (o, o.dot(a), r.dir.dot(a))
+}
+
+// a t^2 + b t + c = 0
+fn solve_quadratic(a: Flt, b: Flt, c: Flt) -> Vec<Flt> {
+  if a == 0.0 {
+    return if b == 0.0 { vec![] } else { vec![-c / b] };
+  }
+  let d = b * b - 4.0 * a * c;
+  if d < 0.0 {
+    return vec![];
+  }
+  // avoid the cancellation of b and sqrt(d)
+  let q = -0.5 * (b + b.signum() * d.sqrt());
+  if q == 0.0 {
+    return vec![0.0];
+  }
+  let (t0, t1) = (q / a, c / q);
+  if t0 < t1 { vec![t0, t1] } else { vec![t1, t0] }
+}
+
+// real roots in [lo, hi] of a polynomial, cs[i] is the coefficient of t^i.
+// the roots of the derivative split the range into monotonic intervals, searched by bisection.
+// roots of even multiplicity (touching) are not found.
+fn poly_roots(cs: &[Flt], lo: Flt, hi: Flt) -> Vec<Flt> {
+  let deg = match cs.iter().rposition(|c| *c != 0.0) {
+    Some(d) => d,
+    None    => return vec![],
+  };
+  if deg == 0 {
+    return vec![];
+  }
+  if deg == 1 {
+    let t = -cs[0] / cs[1];
+    return if lo <= t && t <= hi { vec![t] } else { vec![] };
+  }
+  let f = |t: Flt| cs[..=deg].iter().rev().fold(0.0, |acc, c| acc * t + c);
+  let ds: Vec<Flt> = (1..=deg).map(|i| cs[i] * i as Flt).collect();
+  let mut ps = vec![lo];
+  ps.extend(poly_roots(&ds, lo, hi));
+  ps.push(hi);
+  let mut roots: Vec<Flt> = vec![];
+  for w in ps.windows(2) {
+    let (mut a, mut b) = (w[0], w[1]);
+    let (fa, fb) = (f(a), f(b));
+    if fa == 0.0 {
+      if roots.last() != Some(&a) { roots.push(a); }
+      continue;
+    }
+    if fa * fb > 0.0 {
+      continue;
+    }
+    for _ in 0..100 {
+      let mid = 0.5 * (a + b);
+      if mid <= a || mid >= b {
+        break;
+      }
+      if f(mid) * fa > 0.0 { a = mid; } else { b = mid; }
+    }
+    roots.push(0.5 * (a + b));
+  }
+  roots
+}
+
+// the nearest face
+fn normal_box(p: &Position3, min: &Position3, max: &Position3) -> Direction3 {
+  let mut best = (f64::INFINITY, 0, 1.0);
+  for i in 0..3 {
+    for (plane, sign) in [(min.v[i], -1.0), (max.v[i], 1.0)].iter() {
+      let d = (p.v[i] - plane).abs();
+      if d < best.0 {
+        best = (d, i, *sign);
+      }
+    }
+  }
+  let mut n = Vector3::O;
+  n.v[best.1] = best.2;
+  n
+}
+
+// of the nearest part: the bottom, the top or the side
+fn normal_cylinder(p: &Position3, p0: &Position3, a: &Direction3, h: &Flt, rad: &Flt) -> Option<Direction3> {
+  let q = *p - *p0;
+  let qa = q.dot(a);
+  let qr = q - *a * qa;
+  let ds = (qr.norm() - rad).abs();
+  if qa.abs() < ds && qa.abs() < (qa - h).abs() {
+    Some(-*a)
+  } else if (qa - h).abs() < ds {
+    Some(*a)
+  } else {
+    qr.normalize()
+  }
+}
+
+fn normal_cone(p: &Position3, p0: &Position3, a: &Direction3, h: &Flt, rad: &Flt) -> Option<Direction3> {
+  let q = *p - *p0;
+  let qa = q.dot(a);
+  let qr = q - *a * qa;
+  let side = (qr.norm() - rad * (1.0 - qa / h)).abs() * h / (h * h + rad * rad).sqrt();
+  if qa.abs() < side {
+    return Some(-*a);
+  }
+  match qr.normalize() {
+    Some(e) => (e * *h + *a * *rad).normalize(),
+    None    => Some(*a),    // the apex
+  }
+}
+
+// from the nearest point on the center line of the tube
+fn normal_torus(p: &Position3, c: &Position3, a: &Direction3, major: &Flt) -> Option<Direction3> {
+  let q = *p - *c;
+  let qr = q - *a * q.dot(a);
+  (q - qr.normalize()? * *major).normalize()
+}
+
+// the box of a disk
+fn disk_box(c: &Position3, n: &Direction3, rad: &Flt) -> Aabb {
+  let e: Vec<Flt> = n.v.iter().map(|ni| rad * (1.0 - ni * ni).max(0.0).sqrt()).collect();
+  let ev = Vector3::new(e[0], e[1], e[2]);
+  Aabb::new(&[*c - ev, *c + ev])
+}
+
 
 //----
 // tests
@@ -242,6 +1038,212 @@ mod tests {
     assert_eq!(pa.get_normal(&Vector3::new(0.0, 1.0, 0.0)), Some(Vector3::new(0.4082482904638631, -0.8164965809277261, 0.4082482904638631)));
   }
 
+  #[test]
+  fn test_aabb() {
+    let sp = Shape::Sphere {center: Vector3::new(1.0, 0.0, 0.0), radius: 1.0};
+    let b = sp.bounding_box().unwrap();
+    assert!((b.min - Vector3::new(0.0, -1.0, -1.0)).norm() < 0.001);
+    assert!((b.max - Vector3::new(2.0, 1.0, 1.0)).norm() < 0.001);
+    assert_eq!(Shape::Plain {nvec: Vector3::EY, dist: 1.0}.bounding_box(), None);
+    let r = Ray::new(&Vector3::new(1.0, 0.0, -5.0), &Vector3::EZ);
+    let inv = Vector3::new(1.0 / 0.0, 1.0 / 0.0, 1.0);
+    assert!((b.hit(&r, &inv, 100.0).unwrap() - 4.0).abs() < 0.001);
+    assert_eq!(b.hit(&r, &inv, 3.0), None);
+    let r2 = Ray::new(&Vector3::new(3.0, 0.0, -5.0), &Vector3::EZ);
+    assert_eq!(b.hit(&r2, &inv, 100.0), None);
+    // on a plane of the box, parallel to it
+    let r3 = Ray::new(&Vector3::new(0.0, 1.0, -5.0), &Vector3::EZ);
+    assert!((b.hit(&r3, &inv, 100.0).unwrap() - 4.0).abs() < 0.001);
+    let r4 = Ray::new(&Vector3::new(2.0, 1.0, 0.0), &Vector3::EZ);
+    assert_eq!(b.hit(&r4, &inv, 100.0), Some(0.0));
+    let b2 = Aabb::new(&[Vector3::new(4.0, 4.0, 4.0)]);
+    assert!((b.merge(&b2).max - Vector3::new(4.0, 4.0, 4.0)).norm() < 0.001);
+  }
+
+  #[test]
+  fn test_mesh() {
+    let ps = vec![Vector3::O, Vector3::EX, Vector3::EZ, Vector3::new(1.0, 0.0, 1.0)];
+    let ns = vec![Vector3::EY, Vector3::EY, Vector3::EY, Vector3::new(1.0, 1.0, 0.0).normalize().unwrap()];
+    let uvs = vec![(0.0, 0.0), (1.0, 0.0), (0.0, 1.0), (1.0, 1.0)];
+    assert!(Mesh::new(ps.clone(), None, None, vec![[0, 1, 4]]).is_err());
+    assert!(Mesh::new(ps.clone(), Some(vec![Vector3::EY]), None, vec![[0, 1, 2]]).is_err());
+    let mut m0 = Mesh::new(ps.clone(), None, None, vec![[0, 1, 1], [0, 2, 1], [0, 0, 0]]).unwrap();
+    assert_eq!(m0.remove_degenerate(), 2);
+    assert_eq!(m0.indices, vec![[0, 2, 1]]);
+    let m = Mesh::new(ps, Some(ns), Some(uvs), vec![[0, 2, 1], [1, 2, 3]]).unwrap();
+    let sh = Shape::Mesh {mesh: Arc::new(m)};
+    assert_eq!(sh.nprim(), 2);
+
+    let r = Ray::new(&Vector3::new(0.75, 1.0, 0.5), &(-Vector3::EY));
+    assert_eq!(sh.nearest_hit(0, &r), None);
+    let h = sh.nearest_hit(1, &r).unwrap();
+    assert!((h.dist - 1.0).abs() < 1.0e-10);
+    assert_eq!(h.prim, 1);
+    assert!((h.u - 0.25).abs() < 1.0e-10 && (h.v - 0.25).abs() < 1.0e-10);
+    let n = sh.normal_at(&r.target(h.dist), &h).unwrap();
+    assert!(n.v[0] > 0.0 && n.v[1] > n.v[0]);
+    if let Shape::Mesh {mesh} = &sh {
+      let (u, v) = mesh.uv(h.prim, h.u, h.v).unwrap();
+      assert!((u - 0.75).abs() < 1.0e-10 && (v - 0.5).abs() < 1.0e-10);
+    }
+    assert_eq!(sh.distance(&r).len(), 1);
+    assert!((sh.bounding_box().unwrap().max - Vector3::new(1.0, 0.0, 1.0)).norm() < 0.001);
+  }
+
+  #[test]
+  fn test_solids() {
+    let near = |ts: Vec<Flt>, es: &[Flt]| ts.len() == es.len() && ts.iter().zip(es).all(|(t, e)| (t - e).abs() < 1.0e-9);
+    let down = |x: Flt, z: Flt| Ray::new(&Vector3::new(x, 10.0, z), &(-Vector3::EY));
+
+    let bx = Shape::Box {min: Vector3::O, max: Vector3::new(1.0, 2.0, 3.0)};
+    assert!(near(bx.distance(&down(0.5, 0.5)), &[8.0, 10.0]));
+    assert!(bx.distance(&down(1.5, 0.5)).is_empty());
+    assert_eq!(bx.get_normal(&Vector3::new(0.5, 2.0, 1.0)), Some(Vector3::EY));
+    assert_eq!(bx.get_normal(&Vector3::new(0.0, 1.0, 1.0)), Some(-Vector3::EX));
+    let ob = Shape::new_oriented_box(&Vector3::O, &Vector3::new(1.0, 1.0, 0.0), &Vector3::new(-1.0, 1.0, 0.0), &Vector3::EZ).unwrap();
+    assert!(near(ob.distance(&down(0.0, 0.5)), &[8.0, 10.0]));
+    assert!(ob.get_normal(&Vector3::new(0.5, 0.5, 0.5)).unwrap().near(&Vector3::new(1.0, -1.0, 0.0).normalize().unwrap()));
+
+    let cy = Shape::new_cylinder(&Vector3::EY, &Vector3::new(0.0, 2.0, 0.0), 0.5).unwrap();
+    assert!(near(cy.distance(&down(0.2, 0.0)), &[7.0, 9.0]));
+    let side = Ray::new(&Vector3::new(-5.0, 2.0, 0.0), &Vector3::EX);
+    assert!(near(cy.distance(&side), &[4.5, 5.5]));
+    assert_eq!(cy.get_normal(&Vector3::new(0.2, 3.0, 0.0)), Some(Vector3::EY));
+    assert_eq!(cy.get_normal(&Vector3::new(0.5, 2.0, 0.0)), Some(Vector3::EX));
+    assert!((cy.bounding_box().unwrap().max - Vector3::new(0.5, 3.0, 0.5)).norm() < 0.001);
+
+    let co = Shape::new_cone(&Vector3::O, &Vector3::new(0.0, 2.0, 0.0), 1.0).unwrap();
+    assert!(near(co.distance(&down(0.5, 0.0)), &[9.0, 10.0]));
+    assert!(near(co.distance(&Ray::new(&Vector3::new(-5.0, 1.0, 0.0), &Vector3::EX)), &[4.5, 5.5]));
+    assert!(co.get_normal(&Vector3::new(0.5, 1.0, 0.0)).unwrap().near(&Vector3::new(2.0, 1.0, 0.0).normalize().unwrap()));
+    assert_eq!(co.get_normal(&Vector3::new(0.2, 0.0, 0.1)), Some(-Vector3::EY));
+
+    let dk = Shape::Disk {center: Vector3::EY, nvec: Vector3::EY, radius: 1.0};
+    assert!(near(dk.distance(&down(0.5, 0.5)), &[9.0]));
+    assert!(dk.distance(&down(1.0, 0.5)).is_empty());
+
+    let to = Shape::Torus {center: Vector3::O, axis: Vector3::EY, major: 2.0, minor: 0.5};
+    assert!(near(to.distance(&down(2.0, 0.0)), &[9.5, 10.5]));
+    assert!(to.distance(&down(0.0, 0.0)).is_empty());
+    assert!(near(to.distance(&Ray::new(&Vector3::new(-5.0, 0.0, 0.0), &Vector3::EX)), &[2.5, 3.5, 6.5, 7.5]));
+    assert!(to.get_normal(&Vector3::new(0.0, 0.0, 2.5)).unwrap().near(&Vector3::EZ));
+    assert!(to.get_normal(&Vector3::new(1.5, 0.0, 0.0)).unwrap().near(&(-Vector3::EX)));
+    let b = to.bounding_box().unwrap();
+    assert!((b.max - Vector3::new(2.5, 0.5, 2.5)).norm() < 0.001);
+
+    // every hit is on the surface and inside the bounding box
+    for _ in 0..500 {
+      let r = Ray::new(&Vector3::new(0.3, 0.5, -6.0), &generate_random_dir());
+      for t in to.distance(&r) {
+        let q = r.target(t);
+        let qr = Vector3::new(q.v[0], 0.0, q.v[2]).norm() - 2.0;
+        assert!((qr * qr + q.v[1] * q.v[1] - 0.25).abs() < 1.0e-6);
+      }
+      for sh in &[&cy, &co, &to] {
+        let b = sh.bounding_box().unwrap();
+        for t in sh.distance(&r) {
+          let q = r.target(t);
+          assert!((0..3).all(|i| b.min.v[i] <= q.v[i] && q.v[i] <= b.max.v[i]));
+        }
+      }
+    }
+  }
+
+  #[test]
+  fn test_csg() {
+    let ball = |x: Flt| Shape::Sphere {center: Vector3::new(x, 0.0, 0.0), radius: 1.0};
+    let r = Ray::new(&Vector3::new(-5.0, 0.0, 0.0), &Vector3::EX);
+    let hit_normal = |sh: &Shape| {
+      let h = sh.nearest_hit(0, &r).unwrap();
+      (h.dist, sh.normal_at(&r.target(h.dist), &h).unwrap())
+    };
+
+    // a lens
+    let lens = Shape::new_csg(CsgOp::Intersection, &ball(-0.5), &ball(0.5)).unwrap();
+    assert_eq!(lens.distance(&r), vec![4.5, 5.5]);
+    assert_eq!(hit_normal(&lens), (4.5, -Vector3::EX));
+    let b = lens.bounding_box().unwrap();
+    assert!((b.min.v[0] + 0.5).abs() < 0.001 && (b.max.v[0] - 0.5).abs() < 0.001);
+
+    // a bitten ball: the hole surface faces out of the result
+    let bitten = Shape::new_csg(CsgOp::Difference, &ball(0.0), &ball(-1.5)).unwrap();
+    assert_eq!(bitten.distance(&r), vec![4.5, 6.0]);
+    assert_eq!(hit_normal(&bitten), (4.5, -Vector3::EX));
+    let inside = Ray::new(&Vector3::new(0.5, 0.0, 0.0), &(-Vector3::EX));
+    let h = bitten.nearest_hit(0, &inside).unwrap();
+    assert_eq!(h.dist, 1.0);
+    assert_eq!(bitten.normal_at(&inside.target(h.dist), &h), Some(-Vector3::EX));
+
+    let two = Shape::new_csg(CsgOp::Union, &ball(0.0), &ball(1.5)).unwrap();
+    assert_eq!(two.distance(&r), vec![4.0, 7.5]);
+
+    // a hemisphere cut by a plain, then a nested operation
+    let half = Shape::new_csg(CsgOp::Intersection, &ball(0.0), &Shape::Plain {nvec: Vector3::EX, dist: 0.0}).unwrap();
+    assert_eq!(half.distance(&r), vec![4.0, 5.0]);
+    assert_eq!(hit_normal(&Shape::new_csg(CsgOp::Difference, &two, &half).unwrap()), (5.0, -Vector3::EX));
+
+    // a closed mesh: a tetrahedron
+    let ps = vec![Vector3::new(-1.0, -1.0, -1.0), Vector3::new(1.0, -1.0, -1.0), Vector3::new(0.0, 1.0, -1.0), Vector3::new(0.0, 0.0, 1.0)];
+    let tet = Shape::Mesh {mesh: Arc::new(Mesh::new(ps, None, None, vec![[0, 2, 1], [0, 1, 3], [1, 2, 3], [2, 0, 3]]).unwrap())};
+    let cut = Shape::new_csg(CsgOp::Difference, &tet, &ball(0.0)).unwrap();
+    let down = Ray::new(&Vector3::new(0.0, -0.3, 5.0), &(-Vector3::EZ));
+    let h = cut.nearest_hit(0, &down).unwrap();
+    assert!((h.dist - 5.0 - (1.0 - 0.09 as Flt).sqrt()).abs() < 1.0e-9);
+    assert!(cut.normal_at(&down.target(h.dist), &h).unwrap().v[2] > 0.0);
+
+    assert_eq!(Shape::new_csg(CsgOp::Union, &ball(0.0), &Shape::Disk {center: Vector3::O, nvec: Vector3::EY, radius: 1.0}), None);
+  }
+
+  #[test]
+  fn test_tangent() {
+    let h = Hit {dist: 1.0, prim: 0, u: 0.0, v: 0.0};
+    let ball = Shape::Sphere {center: Vector3::O, radius: 1.0};
+    assert!(ball.tangent_at(&Vector3::EX, &h).unwrap().near(&-Vector3::EZ));
+    assert_eq!(ball.tangent_at(&Vector3::EY, &h), None);
+    let cyl = Shape::new_cylinder(&Vector3::O, &Vector3::new(0.0, 2.0, 0.0), 1.0).unwrap();
+    assert!(cyl.tangent_at(&Vector3::new(0.0, 1.0, 1.0), &h).unwrap().near(&Vector3::EX));
+    let pg = Shape::new_parallelogram(&Vector3::O, &Vector3::new(0.0, 0.0, 2.0), &Vector3::EX);
+    assert!(pg.tangent_at(&Vector3::O, &h).unwrap().near(&Vector3::EZ));
+    assert_eq!(Shape::Box {min: Vector3::O, max: Vector3::EX}.tangent_at(&Vector3::O, &h), None);
+
+    // along u of a mesh, none without uvs
+    let ps = vec![Vector3::O, Vector3::EX, Vector3::EZ];
+    let m = Mesh::new(ps.clone(), None, Some(vec![(0.0, 0.0), (0.0, 1.0), (1.0, 0.0)]), vec![[0, 1, 2]]).unwrap();
+    assert!(m.tangent(0).unwrap().near(&Vector3::EZ));
+    assert_eq!(Mesh::new(ps, None, None, vec![[0, 1, 2]]).unwrap().tangent(0), None);
+
+    // turned with an instance
+    let sh = Shape::new_instance(&pg, &Transform::rotate(1, 90.0));
+    assert!(sh.tangent_at(&Vector3::O, &h).unwrap().near(&Vector3::EX));
+  }
+
+  #[test]
+  fn test_instance() {
+    // a sphere stretched to an ellipsoid, then moved
+    let t = Transform::scale(&Vector3::new(2.0, 1.0, 1.0)).unwrap()
+      .then(&Transform::translate(&Vector3::new(0.0, 0.0, 5.0)));
+    let sh = Shape::new_instance(&Shape::Sphere {center: Vector3::O, radius: 1.0}, &t);
+    let r = Ray::new(&Vector3::new(-5.0, 0.0, 5.0), &Vector3::EX);
+    assert_eq!(sh.distance(&r), vec![3.0, 7.0]);
+    let h = sh.nearest_hit(0, &r).unwrap();
+    assert_eq!(h.dist, 3.0);
+    assert!(sh.normal_at(&r.target(h.dist), &h).unwrap().near(&(-Vector3::EX)));
+    let p = Vector3::new(2.0_f64.sqrt(), 0.5_f64.sqrt(), 5.0);
+    assert!(sh.get_normal(&p).unwrap().near(&Vector3::new(1.0, 2.0, 0.0).normalize().unwrap()));
+    let b = sh.bounding_box().unwrap();
+    assert!((b.min - Vector3::new(-2.0, -1.0, 4.0)).norm() < 0.001 && (b.max - Vector3::new(2.0, 1.0, 6.0)).norm() < 0.001);
+
+    // an instance of an instance
+    let sh2 = Shape::new_instance(&sh, &Transform::rotate(1, 90.0));
+    match &sh2 {
+      Shape::Instance {shape, transform: _} => assert_eq!(**shape, Shape::Sphere {center: Vector3::O, radius: 1.0}),
+      _ => panic!("not an instance"),
+    }
+    let r2 = Ray::new(&Vector3::new(10.0, 0.0, 0.0), &(-Vector3::EX));
+    let h2 = sh2.nearest_hit(0, &r2).unwrap();
+    assert!((h2.dist - 4.0).abs() < 1.0e-10);
+  }
+
 
 }
 
@@ -1,9 +1,9 @@
 // light
 
 use std::fmt;
-use rand::Rng;
 
 use super::*;
+use super::rng;
 use super::algebra::*;
 use super::geometry::*;
 use super::optics::*;
@@ -71,9 +71,8 @@ impl Light {
       Light::ParallelogramLight {color, flux:_, pos, nvec, dir1, dir2}
         => {
           let w = select_wavelength(color);
-          let mut rng = rand::thread_rng();
-          let t1 = rng.gen_range(0.0, 1.0);
-          let t2 = rng.gen_range(0.0, 1.0);
+          let t1 = rng::gen_range(0.0, 1.0);
+          let t2 = rng::gen_range(0.0, 1.0);
           let d = diffuse_reflection(nvec);
           let r = Ray::new(&(*pos + t1 * *dir1 + t2 * *dir2), &d);
           Photon::new(&w, &r)
@@ -81,9 +80,8 @@ impl Light {
       Light::SunLight {color, flux:_, pos, nvec:_, dir1, dir2, dir}
         => {
           let w = select_wavelength(color);
-          let mut rng = rand::thread_rng();
-          let t1 = rng.gen_range(0.0, 1.0);
-          let t2 = rng.gen_range(0.0, 1.0);
+          let t1 = rng::gen_range(0.0, 1.0);
+          let t2 = rng::gen_range(0.0, 1.0);
           let r = Ray::new(&(*pos + t1 * *dir1 + t2 * *dir2), dir);
           Photon::new(&w, &r)
         }
@@ -155,8 +153,7 @@ fn gen_pos(pos: &Position3, dir1: &Direction3, dir2: &Direction3, x: &Flt, y: &F
 }
 
 fn select_wavelength(c: &Color) -> Wavelength {
-  let mut rng = rand::thread_rng();
-  c.decide_wavelength(rng.gen_range(0.0, 1.0))
+  Wavelength::sample(c)
 }
 
 const PARA_DIV: Flt = 0.2;
@@ -4,15 +4,19 @@
 use super::optics::*;
 use super::physics::*;
 use super::surface::*;
+use super::volume::*;
 
 type Flt = f64;
 
 #[derive(Debug, PartialEq, Clone, Copy)]
 pub struct Material {
   pub emittance: Radiance,
-  pub transmittance: Color,
+  pub transmittance: Color,   // absorbed over the unit distance inside (0: clear)
   pub ior:           Color,
+  pub dispersion:    Option<Dispersion>,  // ior by the wavelength, in place of the RGB ior
+  pub scattering:    Option<Scattering>,  // participating medium inside
   pub surface:       Surface,
+  pub priority:      i32,     // of the medium where objects overlap
 }
 
 impl Material {
@@ -20,6 +24,29 @@ impl Material {
     (self.ior.0 + self.ior.1 + self.ior.2) / 3.0
   }
 
+  pub fn ior_wavelength(&self, wl: &Wavelength) -> Flt {
+    match self.dispersion {
+      Some(d) => d.ior(wl),
+      None    => self.ior.ior_wavelength(wl),
+    }
+  }
+
+  // light left after travelling d inside (Beer-Lambert): exp(-(sigma_a + sigma_s) d)
+  // with sigma_a = -ln(1 - transmittance) and sigma_s of the scattering, if any
+  pub fn attenuation(&self, d: &Flt) -> Color {
+    let t = |a: Flt| (1.0 - a).clamp(0.0, 1.0).powf(*d);
+    let ta = Color(t(self.transmittance.0), t(self.transmittance.1), t(self.transmittance.2));
+    match self.scattering {
+      Some(s) => ta * s.transmittance(d),
+      None    => ta,
+    }
+  }
+
+  // absorption only: photons sample the scattering by the free path
+  pub fn absorption_wavelength(&self, d: &Flt, wl: &Wavelength) -> Flt {
+    (1.0 - self.transmittance.wavelength(wl)).clamp(0.0, 1.0).powf(*d)
+  }
+
 }
 
 #[cfg(test)]
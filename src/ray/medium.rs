@@ -0,0 +1,145 @@
+// medium
+//   the media a path is in, for nested transparent objects (glass of water).
+//   entering an object adds its material, leaving it removes it again.
+//   objects are told by their ids (index in the scene), not by the materials,
+//   which overlapping objects may share.
+//   when objects overlap, the medium of the highest priority is the one
+//   the path is in (the latest entered of them); the surfaces of the others
+//   inside it are hidden and do not refract.
+
+use super::material::*;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Media {
+  outer: Material,        // outside of every object (air)
+  inner: Vec<(usize, Material)>,   // ids and materials of the objects entered, in order
+}
+
+impl Media {
+  pub fn new(outer: &Material) -> Media {
+    Media {outer: *outer, inner: vec![]}
+  }
+
+  pub fn current(&self) -> &Material {
+    match self.top() {
+      Some(i) => &self.inner[i].1,
+      None    => &self.outer,
+    }
+  }
+
+  // media after passing through a surface of the object id of material m
+  pub fn cross(&self, id: &usize, m: &Material, entering: &bool) -> Media {
+    let mut ms = self.clone();
+    if *entering {
+      ms.inner.push((*id, *m));
+    } else if let Some(i) = ms.inner.iter().rposition(|(n, _)| n == id) {
+      ms.inner.remove(i);
+    }
+    ms
+  }
+
+  // a surface of m inside a medium of higher priority does not change the medium
+  pub fn hidden(&self, id: &usize, m: &Material, entering: &bool) -> bool {
+    if *entering {
+      self.inner.iter().any(|(_, n)| n.priority > m.priority)
+    } else {
+      match self.inner.iter().rposition(|(n, _)| n == id) {
+        Some(i) => self.top() != Some(i),
+        None    => false,   // never entered: leave into the current medium
+      }
+    }
+  }
+
+  // index of the current medium: the latest of the highest priority
+  fn top(&self) -> Option<usize> {
+    let mut top: Option<usize> = None;
+    for (i, (_, m)) in self.inner.iter().enumerate() {
+      match top {
+        Some(j) if self.inner[j].1.priority > m.priority => (),
+        _                                                => top = Some(i),
+      }
+    }
+    top
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use super::super::optics::*;
+  use super::super::physics::*;
+  use super::super::surface::*;
+  use super::super::volume::*;
+
+  fn mate(ior: f64, priority: i32) -> Material {
+    Material {
+      emittance: Radiance::RADIANCE0,
+      transmittance: Color::BLACK,
+      ior: Color(ior, ior, ior),
+      dispersion: None,
+      scattering: None,
+      surface: Surface::Nothing,
+      priority,
+    }
+  }
+
+  #[test]
+  fn test_media() {
+    let air = mate(1.0, 0);
+    let glass = mate(1.5, 0);
+    let water = mate(1.33, 0);
+    let m0 = Media::new(&air);
+    assert_eq!(m0.current(), &air);
+    assert!(!m0.hidden(&1, &glass, &false));
+    assert_eq!(m0.cross(&1, &glass, &false), m0);
+
+    // water in a glass: air -> glass -> water -> glass -> air
+    let m1 = m0.cross(&1, &glass, &true);
+    assert_eq!(m1.current(), &glass);
+    let m2 = m1.cross(&2, &water, &true);
+    assert_eq!(m2.current(), &water);
+    assert!(!m2.hidden(&2, &water, &false));
+    let m3 = m2.cross(&2, &water, &false);
+    assert_eq!(m3, m1);
+    assert_eq!(m3.cross(&1, &glass, &false), m0);
+
+    // the glass overlapping the water has a higher priority:
+    // the surface of the water inside the wall of the glass is hidden
+    let glass = mate(1.5, 1);
+    let m1 = m0.cross(&1, &glass, &true);
+    assert!(m1.hidden(&2, &water, &true));
+    let m2 = m1.cross(&2, &water, &true);
+    assert_eq!(m2.current(), &glass);
+    assert!(!m2.hidden(&1, &glass, &false));
+    let m3 = m2.cross(&1, &glass, &false);
+    assert_eq!(m3.current(), &water);
+    assert!(!m3.hidden(&1, &glass, &true));
+    let m4 = m3.cross(&1, &glass, &true);
+    assert_eq!(m4.current(), &glass);
+    assert!(m4.hidden(&2, &water, &false));
+    assert_eq!(m4.cross(&2, &water, &false), m1);
+
+    // two overlapping objects of the same material: leaving the first entered
+    let m1 = m0.cross(&1, &water, &true);
+    let m2 = m1.cross(&2, &water, &true);
+    assert_eq!(m2.cross(&1, &water, &false), m0.cross(&2, &water, &true));
+    assert!(m2.hidden(&1, &water, &false));
+    assert!(!m2.hidden(&2, &water, &false));
+  }
+
+  #[test]
+  fn test_attenuation() {
+    let mut wine = mate(1.34, 0);
+    wine.transmittance = Color(0.5, 0.75, 1.0);
+    assert_eq!(wine.attenuation(&0.0), Color::WHITE);
+    assert_eq!(wine.attenuation(&1.0), Color(0.5, 0.25, 0.0));
+    assert_eq!(wine.attenuation(&2.0), Color(0.25, 0.0625, 0.0));
+    assert_eq!(wine.absorption_wavelength(&2.0, &Wavelength(700.0)), 0.25);
+    // fog scatters the light out of the eye rays, without absorbing photons
+    let mut fog = mate(1.0, 0);
+    fog.scattering = Some(Scattering {sigma_s: Color(0.5, 0.5, 0.5), g: 0.0});
+    assert!((fog.attenuation(&2.0).1 - (-1.0 as f64).exp()).abs() < 1.0e-12);
+    assert_eq!(fog.absorption_wavelength(&2.0, &Wavelength::GREEN), 1.0);
+    assert_eq!(mate(1.0, 0).attenuation(&10.0), Color::WHITE);
+  }
+}
@@ -0,0 +1,348 @@
+// microfacet
+//   GGX (Trowbridge-Reitz) distribution with Smith masking, the GTR1
+//   distribution of the Disney clearcoat and the anisotropic Phong distribution
+//   of Ashikhmin and Shirley, in the local frame of a surface:
+//   x = tangent, y = normal, z = bitangent (y up, as new_dir_from_angle).
+//   Reference URLs
+//   https://jcgt.org/published/0007/04/01/  (sampling the visible normals)
+//   https://media.disneyanimation.com/uploads/production/publication_asset/48/asset/s2012_pbs_disney_brdf_notes_v3.pdf
+//   https://www.cs.utah.edu/~michael/brdfs/jgtbrdf.pdf  (An Anisotropic Phong BRDF Model)
+
+use super::*;
+use super::algebra::*;
+
+const ONE_PI: Flt = 1.0 / f64::consts::PI;
+pub const MAX_SAMPLE: usize = 8;    // tries to sample a direction above the surface
+pub const MIN_ALPHA: Flt = 0.001;   // nearly a mirror, keeps D finite
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Frame {
+  pub t: Direction3,
+  pub n: Direction3,
+  pub b: Direction3,
+}
+
+impl Frame {
+  // a frame around n whose tangent is horizontal (along x where n is vertical)
+  pub fn new(n: &Direction3) -> Frame {
+    let t = Vector3::EY.cross(n).normalize().unwrap_or(Vector3::EX);
+    Frame {t, n: *n, b: t.cross(n)}
+  }
+
+  // a frame around n with the tangent t projected on the surface, or new() if t is along n
+  pub fn with_tangent(n: &Direction3, t: &Direction3) -> Frame {
+    match (*t - t.dot(n) * *n).normalize() {
+      Some(t) => Frame {t, n: *n, b: t.cross(n)},
+      None    => Frame::new(n),
+    }
+  }
+
+  pub fn to_local(&self, v: &Vector3) -> Vector3 {
+    Vector3::new(v.dot(&self.t), v.dot(&self.n), v.dot(&self.b))
+  }
+
+  pub fn to_world(&self, v: &Vector3) -> Vector3 {
+    v.v[0] * self.t + v.v[1] * self.n + v.v[2] * self.b
+  }
+}
+
+// normal distribution of a local half vector h
+pub fn ggx_d(h: &Vector3, ax: &Flt, ay: &Flt) -> Flt {
+  if h.v[1] <= 0.0 {
+    return 0.0
+  }
+  let (x, z) = (h.v[0] / ax, h.v[2] / ay);
+  let d = x * x + z * z + h.v[1] * h.v[1];
+  ONE_PI / (ax * ay * d * d)
+}
+
+// Smith masking of a local direction v
+pub fn ggx_g1(v: &Vector3, ax: &Flt, ay: &Flt) -> Flt {
+  if v.v[1] <= 0.0 {
+    return 0.0
+  }
+  1.0 / (1.0 + ggx_lambda(v, ax, ay))
+}
+
+// height-correlated masking and shadowing of local l and v
+pub fn ggx_g2(l: &Vector3, v: &Vector3, ax: &Flt, ay: &Flt) -> Flt {
+  if l.v[1] <= 0.0 || v.v[1] <= 0.0 {
+    return 0.0
+  }
+  1.0 / (1.0 + ggx_lambda(l, ax, ay) + ggx_lambda(v, ax, ay))
+}
+
+fn ggx_lambda(v: &Vector3, ax: &Flt, ay: &Flt) -> Flt {
+  let (x, z) = (ax * v.v[0], ay * v.v[2]);
+  let y2 = v.v[1] * v.v[1];
+  0.5 * (f64::sqrt(1.0 + (x * x + z * z) / y2) - 1.0)
+}
+
+// a half vector from the normals visible from local v (v.y > 0)
+pub fn sample_ggx_vndf(v: &Vector3, ax: &Flt, ay: &Flt) -> Vector3 {
+  // stretch to the hemisphere configuration (y up)
+  let vh = Vector3::new(ax * v.v[0], v.v[1], ay * v.v[2]).normalize().unwrap_or(Vector3::EY);
+  let lensq = vh.v[0] * vh.v[0] + vh.v[2] * vh.v[2];
+  let t1 = if lensq > 0.0 {
+    Vector3::new(vh.v[2], 0.0, -vh.v[0]) * (1.0 / lensq.sqrt())
+  } else {
+    Vector3::EX
+  };
+  let t2 = vh.cross(&t1);
+  let r = f64::sqrt(rng::gen_range(0.0, 1.0));
+  let phi = PI2 * rng::gen_range(0.0, 1.0);
+  let p1 = r * phi.cos();
+  let s = 0.5 * (1.0 + vh.v[1]);
+  let p2 = (1.0 - s) * f64::sqrt(1.0 - p1 * p1) + s * r * phi.sin();
+  let nh = p1 * t1 + p2 * t2 + f64::sqrt((1.0 - p1 * p1 - p2 * p2).max(0.0)) * vh;
+  // back to the ellipsoid configuration
+  Vector3::new(ax * nh.v[0], nh.v[1].max(0.0), ay * nh.v[2]).normalize().unwrap_or(Vector3::EY)
+}
+
+// density of l = reflect(v, h) with h by sample_ggx_vndf
+pub fn ggx_pdf_reflection(l: &Vector3, v: &Vector3, ax: &Flt, ay: &Flt) -> Flt {
+  if l.v[1] <= 0.0 || v.v[1] <= 0.0 {
+    return 0.0
+  }
+  let h = (*l + *v).normalize().unwrap_or(Vector3::EY);
+  ggx_g1(v, ax, ay) * ggx_d(&h, ax, ay) / (4.0 * v.v[1])
+}
+
+// reflection of v (from surface) by a local half vector of sample_h (given local v),
+// None if none is found above the surface
+pub fn sample_reflection<F: Fn(&Vector3) -> Vector3>(fr: &Frame, v: &Direction3, sample_h: F) -> Option<Direction3> {
+  let vl = fr.to_local(v);
+  if vl.v[1] <= 0.0 {
+    return None
+  }
+  for _ in 0..MAX_SAMPLE {
+    let l = reflect_local(&vl, &sample_h(&vl));
+    if l.v[1] > 0.0 {
+      return fr.to_world(&l).normalize()
+    }
+  }
+  None
+}
+
+// reflection of v (from surface) by a visible normal
+pub fn sample_ggx_reflection(fr: &Frame, v: &Direction3, ax: &Flt, ay: &Flt) -> Option<Direction3> {
+  sample_reflection(fr, v, |vl| sample_ggx_vndf(vl, ax, ay))
+}
+
+// weight of l sampled by the visible normals from v: D G2 / (4 cos_l cos_v) * cos_l / pdf = G2 / G1,
+// without the Fresnel term
+pub fn ggx_reflection_weight(fr: &Frame, l: &Direction3, v: &Direction3, ax: &Flt, ay: &Flt) -> Flt {
+  let (l, v) = (fr.to_local(l), fr.to_local(v));
+  let g1 = ggx_g1(&v, ax, ay);
+  if g1 > 0.0 { ggx_g2(&l, &v, ax, ay) / g1 } else { 0.0 }
+}
+
+// GTR1 distribution (clearcoat) by the cosine of a half vector
+pub fn gtr1_d(cos_h: &Flt, a: &Flt) -> Flt {
+  let a2 = a * a;
+  if a2 >= 1.0 {
+    return ONE_PI
+  }
+  (a2 - 1.0) / (f64::consts::PI * a2.ln() * (1.0 + (a2 - 1.0) * cos_h * cos_h))
+}
+
+pub fn sample_gtr1(a: &Flt) -> Vector3 {
+  let a2 = a * a;
+  let u: Flt = rng::gen_range(0.0, 1.0);
+  let cos = if a2 >= 1.0 {
+    f64::sqrt(1.0 - u)
+  } else {
+    f64::sqrt((1.0 - a2.powf(1.0 - u)) / (1.0 - a2))
+  };
+  let sin = f64::sqrt((1.0 - cos * cos).max(0.0));
+  let phi = PI2 * rng::gen_range(0.0, 1.0);
+  Vector3::new(sin * phi.cos(), cos, sin * phi.sin())
+}
+
+// density of l = reflect(v, h) with h by sample_gtr1
+pub fn gtr1_pdf_reflection(l: &Vector3, v: &Vector3, a: &Flt) -> Flt {
+  if l.v[1] <= 0.0 || v.v[1] <= 0.0 {
+    return 0.0
+  }
+  let h = (*l + *v).normalize().unwrap_or(Vector3::EY);
+  gtr1_d(&h.v[1], a) * h.v[1] / (4.0 * l.dot(&h))
+}
+
+// Phong exponent of the Ashikhmin-Shirley distribution for a local half vector
+fn as_exponent(h: &Vector3, et: &Flt, eb: &Flt) -> Flt {
+  let s2 = h.v[0] * h.v[0] + h.v[2] * h.v[2];
+  if s2 > 0.0 { (et * h.v[0] * h.v[0] + eb * h.v[2] * h.v[2]) / s2 } else { 0.5 * (et + eb) }
+}
+
+// density of a local half vector (exponents et along the tangent, eb along the bitangent)
+pub fn as_pdf(h: &Vector3, et: &Flt, eb: &Flt) -> Flt {
+  if h.v[1] <= 0.0 {
+    return 0.0
+  }
+  f64::sqrt((et + 1.0) * (eb + 1.0)) * 0.5 * ONE_PI * h.v[1].powf(as_exponent(h, et, eb))
+}
+
+// a local half vector by as_pdf, sampling a quadrant at a time
+pub fn sample_as(et: &Flt, eb: &Flt) -> Vector3 {
+  let u: Flt = rng::gen_range(0.0, 1.0);
+  let first = |u: Flt| {
+    let t = u * f64::consts::FRAC_PI_2;
+    if et == eb { t } else { f64::atan(f64::sqrt((et + 1.0) / (eb + 1.0)) * t.tan()) }
+  };
+  let phi = match u {
+    _ if u < 0.25 => first(4.0 * u),
+    _ if u < 0.5  => f64::consts::PI - first(4.0 * (0.5 - u)),
+    _ if u < 0.75 => f64::consts::PI + first(4.0 * (u - 0.5)),
+    _             => PI2 - first(4.0 * (1.0 - u)),
+  };
+  let (sin_p, cos_p) = phi.sin_cos();
+  let e = et * cos_p * cos_p + eb * sin_p * sin_p;
+  let cos = (1.0 - rng::gen_range(0.0, 1.0)).powf(1.0 / (e + 1.0));
+  let sin = f64::sqrt((1.0 - cos * cos).max(0.0));
+  Vector3::new(sin * cos_p, cos, sin * sin_p)
+}
+
+// density of l = reflect(v, h) with h by sample_as
+pub fn as_pdf_reflection(l: &Vector3, v: &Vector3, et: &Flt, eb: &Flt) -> Flt {
+  if l.v[1] <= 0.0 || v.v[1] <= 0.0 {
+    return 0.0
+  }
+  let h = (*l + *v).normalize().unwrap_or(Vector3::EY);
+  as_pdf(&h, et, eb) / (4.0 * v.dot(&h))
+}
+
+// reflection of v (from surface) by sample_as
+pub fn sample_as_reflection(fr: &Frame, v: &Direction3, et: &Flt, eb: &Flt) -> Option<Direction3> {
+  sample_reflection(fr, v, |_| sample_as(et, eb))
+}
+
+// weight of l sampled by sample_as from v, without the Fresnel term:
+// the distribution cancels, cos_l / max(cos_l, cos_v) is left
+pub fn as_reflection_weight(n: &Direction3, l: &Direction3, v: &Direction3) -> Flt {
+  let (cos_l, cos_v) = (n.dot(l), n.dot(v));
+  if cos_l <= 0.0 || cos_v <= 0.0 { 0.0 } else { cos_l / cos_l.max(cos_v) }
+}
+
+// cosine weighted local direction
+pub fn sample_cosine() -> Vector3 {
+  let u: Flt = rng::gen_range(0.0, 1.0);
+  let phi = PI2 * rng::gen_range(0.0, 1.0);
+  let r = u.sqrt();
+  Vector3::new(r * phi.cos(), f64::sqrt(1.0 - u), r * phi.sin())
+}
+
+// mirror of local v about h
+pub fn reflect_local(v: &Vector3, h: &Vector3) -> Vector3 {
+  2.0 * v.dot(h) * *h - *v
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  // integral over the hemisphere by stratified (theta, phi)
+  fn integrate<F: Fn(&Vector3) -> Flt>(f: F) -> Flt {
+    let (nt, np) = (400, 400);
+    let mut s = 0.0;
+    for i in 0..nt {
+      let th = (i as Flt + 0.5) / nt as Flt * f64::consts::FRAC_PI_2;
+      for j in 0..np {
+        let ph = (j as Flt + 0.5) / np as Flt * PI2;
+        let w = Vector3::new(th.sin() * ph.cos(), th.cos(), th.sin() * ph.sin());
+        s += f(&w) * th.sin();
+      }
+    }
+    s * (f64::consts::FRAC_PI_2 / nt as Flt) * (PI2 / np as Flt)
+  }
+
+  #[test]
+  fn test_frame() {
+    let n = Vector3::new(1.0, 2.0, -0.5).normalize().unwrap();
+    let f = Frame::new(&n);
+    let v = Vector3::new(0.3, -0.2, 0.9);
+    assert!(f.to_world(&f.to_local(&v)).near(&v));
+    assert!(f.to_local(&n).near(&Vector3::EY));
+    assert_eq!(Frame::new(&Vector3::EY).t, Vector3::EX);
+  }
+
+  #[test]
+  fn test_ggx() {
+    // projected area of the microfacets is 1
+    let (ax, ay) = (0.3, 0.6);
+    let a = integrate(|h| ggx_d(h, &ax, &ay) * h.v[1]);
+    assert!((a - 1.0).abs() < 0.01, "{}", a);
+    let v = Vector3::new(0.5, 0.6, -0.2).normalize().unwrap();
+    // the sampled half vectors face v
+    for _ in 0..1000 {
+      let h = sample_ggx_vndf(&v, &ax, &ay);
+      assert!(h.v[1] >= 0.0 && h.dot(&v) > -1.0e-9);
+      assert!((h.norm() - 1.0).abs() < 1.0e-9);
+    }
+    // sampling follows the density: the part above the surface and the mean of l.x
+    let n = 200000;
+    let check = |sample: &dyn Fn() -> Vector3, pdf: &dyn Fn(&Vector3) -> Flt| {
+      let (mut k, mut m) = (0, 0.0);
+      for _ in 0..n {
+        let l = reflect_local(&v, &sample());
+        if l.v[1] > 0.0 {
+          k += 1;
+          m += l.v[0];
+        }
+      }
+      let p = integrate(pdf);
+      let e = integrate(|l| l.v[0] * pdf(l));
+      assert!(p > 0.5 && p < 1.001, "{}", p);
+      assert!((k as Flt / n as Flt - p).abs() < 0.01, "{} {}", k as Flt / n as Flt, p);
+      assert!((m / n as Flt - e).abs() < 0.01, "{} {}", m / n as Flt, e);
+    };
+    check(&|| sample_ggx_vndf(&v, &ax, &ay), &|l| ggx_pdf_reflection(l, &v, &ax, &ay));
+    check(&|| sample_gtr1(&0.2), &|l| gtr1_pdf_reflection(l, &v, &0.2));
+    // the weight is the ratio of BRDF * cos to the density
+    let fr = Frame::new(&Vector3::EY);
+    for _ in 0..100 {
+      let l = sample_ggx_reflection(&fr, &v, &ax, &ay).unwrap();
+      let h = (l + v).normalize().unwrap();
+      let f = ggx_d(&h, &ax, &ay) * ggx_g2(&l, &v, &ax, &ay) / (4.0 * v.v[1]);
+      let w = ggx_reflection_weight(&fr, &l, &v, &ax, &ay);
+      assert!((w - f / ggx_pdf_reflection(&l, &v, &ax, &ay)).abs() < 1.0e-9);
+      assert!(w > 0.0 && w <= 1.0);
+    }
+    assert!(ggx_g1(&v, &ax, &ay) < 1.0);
+    assert!(ggx_g2(&v, &v, &ax, &ay) < ggx_g1(&v, &ax, &ay));
+  }
+
+  #[test]
+  fn test_as() {
+    let (et, eb) = (100.0, 10.0);
+    // the half vectors are distributed by the density
+    let a = integrate(|h| as_pdf(h, &et, &eb));
+    assert!((a - 1.0).abs() < 0.01, "{}", a);
+    let n = 200000;
+    let (mut mx, mut mz) = (0.0, 0.0);
+    for _ in 0..n {
+      let h = sample_as(&et, &eb);
+      assert!(h.v[1] >= 0.0 && (h.norm() - 1.0).abs() < 1.0e-9);
+      mx += h.v[0].abs();
+      mz += h.v[2].abs();
+    }
+    let ex = integrate(|h| h.v[0].abs() * as_pdf(h, &et, &eb));
+    let ez = integrate(|h| h.v[2].abs() * as_pdf(h, &et, &eb));
+    assert!((mx / n as Flt - ex).abs() < 0.005, "{} {}", mx / n as Flt, ex);
+    assert!((mz / n as Flt - ez).abs() < 0.005, "{} {}", mz / n as Flt, ez);
+    // narrower along the tangent with the larger exponent
+    assert!(ex < ez);
+    let v = Vector3::new(0.3, 0.8, 0.1).normalize().unwrap();
+    let p = integrate(|l| as_pdf_reflection(l, &v, &et, &eb));
+    assert!(p > 0.9 && p < 1.001, "{}", p);
+    // the frame follows the tangent
+    let fr = Frame::with_tangent(&Vector3::EY, &Vector3::new(1.0, 1.0, 0.0));
+    assert!(fr.t.near(&Vector3::EX) && fr.b.near(&Vector3::EZ));
+    assert_eq!(Frame::with_tangent(&Vector3::EY, &Vector3::EY), Frame::new(&Vector3::EY));
+    for _ in 0..100 {
+      let l = sample_as_reflection(&fr, &v, &et, &eb).unwrap();
+      assert!(l.v[1] > 0.0);
+      let w = as_reflection_weight(&Vector3::EY, &l, &v);
+      assert!(w > 0.0 && w <= 1.0);
+    }
+  }
+}
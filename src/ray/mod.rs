@@ -3,13 +3,20 @@
 use std::f64;
 
 pub mod algebra;
+pub mod bvh;
+pub mod conductor;
+pub mod disney;
 pub mod geometry;
 pub mod material;
+pub mod medium;
+pub mod microfacet;
 pub mod light;
 pub mod object;
 pub mod optics;
 pub mod physics;
+pub mod rng;
 pub mod surface;
+pub mod volume;
 
 pub type Flt = f64;
 
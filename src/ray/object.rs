@@ -6,7 +6,7 @@ use super::material::*;
 //use super::optics::*;
 //use super::physics::*;
 
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone)]
 pub struct Object {
   pub shape: Shape,
   pub material: Material,
@@ -20,7 +20,7 @@ impl PartialEq for Object {
 
 impl Object {
   pub fn new(s: &Shape, m: &Material) -> Object {
-    Object {shape: *s, material: *m}
+    Object {shape: s.clone(), material: *m}
   }
 }
 
@@ -1,6 +1,5 @@
 // optics
 
-use core::num::ParseFloatError;
 use regex::Regex;
 use std::fmt;
 use std::ops::Add;
@@ -36,7 +35,7 @@ impl FromStr for PhotonFilter {
 
   fn from_str(s: &str) -> Result<Self, Self::Err> {
     let re = Regex::new(r"^PF:(\S*?)$").unwrap();
-    let caps = re.captures(s).unwrap();
+    let caps = re.captures(s).ok_or(format!("invalid photon filter: {}", s))?;
     match &caps[1] {
       "None"  => Ok(PhotonFilter::None),
       "Cone"  => Ok(PhotonFilter::Cone),
@@ -46,6 +45,14 @@ impl FromStr for PhotonFilter {
   }
 }
 
+// how the photons for a radiance estimate are gathered
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum PhotonEstimate {
+  Radius,           // all photons within the estimate radius
+  Nearest,          // k nearest photons, radius is the distance to the k-th
+  NearestCapped,    // same as Nearest, but not beyond the estimate radius
+}
+
 // --------------
 // Radiance
 // --------------
@@ -60,14 +67,14 @@ impl fmt::Display for Radiance {
 }
 
 impl FromStr for Radiance {
-  type Err = ParseFloatError;
+  type Err = String;
 
   fn from_str(s: &str) -> Result<Self, Self::Err> {
     let re = Regex::new(r"^RAD\[(\S+?),(\S+?),(\S+?)\]$").unwrap();
-    let caps = re.captures(s).unwrap();
-    let r = caps[1].parse::<Flt>()?;
-    let g = caps[2].parse::<Flt>()?;
-    let b = caps[3].parse::<Flt>()?;
+    let caps = re.captures(s).ok_or(format!("invalid radiance: {}", s))?;
+    let r = parse_flt(&caps[1], s)?;
+    let g = parse_flt(&caps[2], s)?;
+    let b = parse_flt(&caps[3], s)?;
     Ok(Radiance(r, g, b))
   }
 }
@@ -128,12 +135,9 @@ impl Radiance {
   pub const RADIANCE0: Radiance = Radiance(0.0, 0.0, 0.0);
   pub const RADIANCE1: Radiance = Radiance(1.0, 1.0, 1.0);
 
-  pub fn select_wavelength(&self, w: Wavelength) -> Flt {
-    match w {
-      Wavelength::Red   => self.0,
-      Wavelength::Green => self.1,
-      Wavelength::Blue  => self.2,
-    }
+  // per channel product, as a weight of a hit point on the radiance of a photon
+  pub fn filter(&self, r: &Radiance) -> Radiance {
+    Radiance(self.0 * r.0, self.1 * r.1, self.2 * r.2)
   }
 
   pub fn r(&self) -> Flt {
@@ -181,7 +185,7 @@ impl FromStr for Photon {
 
   fn from_str(s: &str) -> Result<Self, Self::Err> {
     let re = Regex::new(r"^PHOTON\[(WL:\S+),(RAY\[\S+\])\]$").unwrap();
-    let caps = re.captures(s).unwrap();
+    let caps = re.captures(s).ok_or(format!("invalid photon: {}", s))?;
     let wl = caps[1].parse::<Wavelength>()?;
     let r  = caps[2].parse::<Ray>();
     match r {
@@ -197,17 +201,13 @@ impl Photon {
   }
   
   pub fn dummy(p: &Position3) -> Photon {
-    Photon {wl: Wavelength::Red, ray: Ray::new(p, &Vector3::EX)}
+    Photon {wl: Wavelength::GREEN, ray: Ray::new(p, &Vector3::EX)}
   }
 
   pub fn to_radiance(&self, n: &Direction3, pw: &Flt) -> Radiance {
     let cos0 = n.dot(&self.ray.dir);
     let pw2 = if cos0 > 0.0 { pw * cos0 } else { 0.0 };
-    match self.wl {
-      Wavelength::Red   => Radiance(pw2, 0.0, 0.0),
-      Wavelength::Green => Radiance(0.0, pw2, 0.0),
-      Wavelength::Blue  => Radiance(0.0, 0.0, pw2),
-    }
+    spectral_radiance(&self.wl, &pw2)
   }
 
   pub fn to_points(&self) -> [Flt; 3] {
@@ -225,11 +225,17 @@ pub fn photon_to_radiance(n: &Direction3, pw: &Flt, ph: &Photon) -> Radiance {
   let cos0 = n.dot(&ph.ray.dir);
   let pw2 = if cos0 < 0.0 { *pw * -cos0 } else { 0.0 };
   //eprintln!("NV:{}", pw);
-  match ph.wl {
-    Wavelength::Red   => Radiance(pw2, 0.0, 0.0),
-    Wavelength::Green => Radiance(0.0, pw2, 0.0),
-    Wavelength::Blue  => Radiance(0.0, 0.0, pw2),
-  }
+  spectral_radiance(&ph.wl, &pw2)
+}
+
+// power of a photon in RGB, as it is (for participating media)
+pub fn photon_power(pw: &Flt, ph: &Photon) -> Radiance {
+  spectral_radiance(&ph.wl, pw)
+}
+
+// power of a photon in RGB by the colour matching functions
+fn spectral_radiance(wl: &Wavelength, pw: &Flt) -> Radiance {
+  wl.to_rgb() * (Radiance::RADIANCE1 * *pw)
 }
 
 
@@ -259,9 +265,7 @@ mod tests {
     assert_eq!(r2 * 2.5, Radiance(2.5, 2.0, 1.5));
     let c = Color(1.0, 2.0, 4.0);
     assert_eq!(c * r2, Radiance(1.0, 1.6, 2.4));
-    assert_eq!(r2.select_wavelength(Wavelength::Red), 1.0);
-    assert_eq!(r2.select_wavelength(Wavelength::Green), 0.8);
-    assert_eq!(r2.select_wavelength(Wavelength::Blue), 0.6);
+    assert_eq!(r2.filter(&Radiance(0.5, 1.0, 2.0)), Radiance(0.5, 0.8, 1.2));
     assert_eq!(format!("{}", r2), "RAD[1,0.8,0.6]");
     let r3 = Radiance::from_str(&format!("{}", r2));
     assert_eq!(r3.unwrap(), r2);
@@ -276,14 +280,63 @@ mod tests {
 
   #[test]
   fn test_photon() {
-    let pi1 = Photon::new(&Wavelength::Red, &Ray::new(&Vector3::new_pos(1.0, 2.0, 3.1), &Vector3::new_dir(1.0, 1.0, 1.0).unwrap()));
+    let pi1 = Photon::new(&Wavelength::RED, &Ray::new(&Vector3::new_pos(1.0, 2.0, 3.1), &Vector3::new_dir(1.0, 1.0, 1.0).unwrap()));
     assert_eq!(pi1.to_points(), [1.0, 2.0, 3.1]);
     let r = Ray::new(&Vector3::new(5.5, 4.4, 3.3), &Vector3::EY);
-    let ph1 = Photon {wl: Wavelength::Blue, ray: r};
-    assert_eq!(format!("{}", ph1), "PHOTON[WL:Blue,RAY[V3[5.5,4.4,3.3],V3[0,1,0]]]");
+    let ph1 = Photon {wl: Wavelength::BLUE, ray: r};
+    assert_eq!(format!("{}", ph1), "PHOTON[WL:450,RAY[V3[5.5,4.4,3.3],V3[0,1,0]]]");
     let sph = "PHOTON[WL:Blue,RAY[V3[5.5,4.4,3.3],V3[0,0,1]]]";
     let ph2 = Photon::from_str(sph);
-    assert_eq!(ph2.unwrap(), Photon::new(&Wavelength::Blue, &Ray::new(&Vector3::new(5.5, 4.4, 3.3), &Vector3::new(0.0, 0.0, 1.0))));
+    assert_eq!(ph2.unwrap(), Photon::new(&Wavelength::BLUE, &Ray::new(&Vector3::new(5.5, 4.4, 3.3), &Vector3::new(0.0, 0.0, 1.0))));
+    let sph3 = "PHOTON[WL:587.6,RAY[V3[5.5,4.4,3.3],V3[0,0,1]]]";
+    assert_eq!(Photon::from_str(sph3).unwrap().wl, Wavelength(587.6));
+  }
+
+  #[test]
+  fn test_spectrum() {
+    // photons of white, red and greenish lights on average come out in their colours
+    let n = 200000;
+    for c in &[Color::WHITE, Color(1.0, 0.0, 0.0), Color(0.2, 0.7, 0.1)] {
+      let mut rad = Radiance::RADIANCE0;
+      for _ in 0..n {
+        let ph = Photon::new(&Wavelength::sample(c), &Ray::new(&Vector3::O, &-Vector3::EY));
+        assert!(ph.wl.0 >= WL_MIN && ph.wl.0 <= WL_MAX);
+        rad = rad + photon_to_radiance(&Vector3::EY, &(1.0 / n as Flt), &ph);
+      }
+      let c = c.normalize();
+      assert!((rad - Radiance(c.0, c.1, c.2)).norm() < 0.03, "{} {}", rad, c);
+    }
+    // monochromatic light: hues along the spectrum
+    let hue = |nm: Flt| Wavelength(nm).to_rgb();
+    assert!(hue(450.0).2 > hue(450.0).0 && hue(450.0).2 > hue(450.0).1);
+    assert!(hue(530.0).1 > hue(530.0).0 && hue(530.0).1 > hue(530.0).2);
+    assert!(hue(620.0).0 > hue(620.0).1 && hue(620.0).0 > hue(620.0).2);
+    // a colour as a spectrum
+    let c = Color(0.8, 0.5, 0.3);
+    assert_eq!(c.wavelength(&Wavelength(700.0)), 0.8);
+    assert_eq!(c.wavelength(&Wavelength(540.0)), 0.5);
+    assert_eq!(c.wavelength(&Wavelength(400.0)), 0.3);
+    // RGB ior by a Cauchy fit near the representatives, decreasing with the wavelength
+    let ior = Color(1.3, 1.5, 1.7);
+    assert!((ior.ior_wavelength(&Wavelength::RED) - 1.3).abs() < 0.05);
+    assert!((ior.ior_wavelength(&Wavelength::GREEN) - 1.5).abs() < 0.05);
+    assert!((ior.ior_wavelength(&Wavelength::BLUE) - 1.7).abs() < 0.05);
+    assert!(ior.ior_wavelength(&Wavelength(500.0)) > 1.5 && ior.ior_wavelength(&Wavelength(500.0)) < 1.7);
+    let glass = Color(1.51, 1.52, 1.53);
+    assert!((glass.ior_wavelength(&Wavelength::GREEN) - 1.52).abs() < 0.002);
+    for nm in (380..780).step_by(10) {
+      let (wl1, wl2) = (Wavelength(nm as Flt), Wavelength((nm + 10) as Flt));
+      assert!(glass.ior_wavelength(&wl1) > glass.ior_wavelength(&wl2), "{}", nm);
+      assert!(ior.ior_wavelength(&wl1) > ior.ior_wavelength(&wl2), "{}", nm);
+    }
+    assert!((Color(1.5, 1.5, 1.5).ior_wavelength(&Wavelength(700.0)) - 1.5).abs() < 1.0e-9);
+    // BK7 glass by Cauchy and Sellmeier, at the sodium D line
+    let d = Wavelength(587.6);
+    assert!((Dispersion::Cauchy(1.5046, 0.00420).ior(&d) - 1.5168).abs() < 0.001);
+    let bk7 = Dispersion::Sellmeier([1.03961212, 0.231792344, 1.01046945], [0.00600069867, 0.0200179144, 103.560653]);
+    assert!((bk7.ior(&d) - 1.5168).abs() < 0.0001);
+    assert!(bk7.ior(&Wavelength::BLUE) > bk7.ior(&Wavelength::RED));
+    assert_eq!(bk7.ior_color().1, bk7.ior(&Wavelength::GREEN));
   }
 
 }
@@ -1,49 +1,146 @@
 /// physics
 
-use core::num::ParseFloatError;
 use std::fmt;
 use std::ops::Neg;
 use std::ops::Add;
 use std::ops::Mul;
 use std::str::*;
 
-use rand::Rng;
 use regex::Regex;
 
 use super::*;
+use super::rng;
 use super::algebra::*;
 
+// wavelength of a photon in nm, within [WL_MIN, WL_MAX]
+//   RGB colours are turned into spectra by three smooth bands (blue, green, red)
+//   summing to one, and a spectrum back into RGB by the CIE colour matching
+//   functions (the analytic fit of Wyman et al., JCGT 2013), with a matrix
+//   making the three bands the primaries again: a white light on a white
+//   surface stays white, and colours of lights and surfaces are kept.
+pub const WL_MIN: Flt = 380.0;
+pub const WL_MAX: Flt = 780.0;
+
 #[derive(Debug, PartialEq, Clone, Copy)]
-pub enum Wavelength {
-  Red,
-  Green,
-  Blue
+pub struct Wavelength(pub Flt);
+
+// the bands of blue, green and red: (start, end) of the rise of green and red
+const BAND_GREEN: (Flt, Flt) = (475.0, 505.0);
+const BAND_RED: (Flt, Flt)   = (575.0, 605.0);
+
+// XYZ to RGB, of which the bands above are the primaries
+const XYZ_TO_RGB: [[Flt; 3]; 3] = [
+  [ 4.92099, -2.37053, -0.76699],
+  [-0.88639,  1.74625,  0.07420],
+  [ 0.08556, -0.16862,  1.11298],
+];
+
+impl Wavelength {
+  // representatives of the channels, where an RGB ior is given
+  pub const RED: Wavelength   = Wavelength(620.0);
+  pub const GREEN: Wavelength = Wavelength(540.0);
+  pub const BLUE: Wavelength  = Wavelength(450.0);
+
+  // a wavelength from the spectrum of a light of colour c:
+  //   a band by the (normalized) colour, then a wavelength in the band
+  pub fn sample(c: &Color) -> Wavelength {
+    let (lo, hi, ch) = match c.normalize().decide_channel(rng::gen_range(0.0, 1.0)) {
+      0 => (BAND_RED.0, WL_MAX, 0),
+      1 => (BAND_GREEN.0, BAND_RED.1, 1),
+      _ => (WL_MIN, BAND_GREEN.1, 2),
+    };
+    loop {
+      let wl = Wavelength(rng::gen_range(lo, hi));
+      if rng::gen_range(0.0, 1.0) < wl.bands().channel(ch) {
+        return wl
+      }
+    }
+  }
+
+  pub fn micrometer(&self) -> Flt {
+    self.0 * 0.001
+  }
+
+  // RGB of a photon of this wavelength, averaging a colour of the light over its spectrum
+  pub fn to_rgb(&self) -> Color {
+    let xyz = cie_xyz(&self.0);
+    let c = |i: usize| XYZ_TO_RGB[i][0] * xyz.0 + XYZ_TO_RGB[i][1] * xyz.1 + XYZ_TO_RGB[i][2] * xyz.2;
+    Color(c(0), c(1), c(2))
+  }
+
+  // weights of the red, green and blue bands here (sum to 1)
+  fn bands(&self) -> Color {
+    let r = smoothstep(&BAND_RED.0, &BAND_RED.1, &self.0);
+    let g = smoothstep(&BAND_GREEN.0, &BAND_GREEN.1, &self.0);
+    Color(r, g - r, 1.0 - g)
+  }
 }
 
 impl fmt::Display for Wavelength {
   fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-    let swl = match self {
-      Wavelength::Red   => "Red",
-      Wavelength::Green => "Green",
-      Wavelength::Blue  => "Blue",
-    };
-    write!(f, "WL:{}", swl)
+    write!(f, "WL:{}", self.0)
   }
 }
 
+// nm, or the names of the representatives (Red, Green, Blue)
 impl FromStr for Wavelength {
   type Err = String;
 
   fn from_str(s: &str) -> Result<Self, Self::Err> {
     let re = Regex::new(r"^WL:(\S+?)$").unwrap();
-    let caps = re.captures(s).unwrap();
+    let caps = re.captures(s).ok_or(format!("invalid wavelength: {}", s))?;
     match &caps[1] {
-      "Red"   => Ok(Wavelength::Red),
-      "Green" => Ok(Wavelength::Green),
-      "Blue"  => Ok(Wavelength::Blue),
-      _       => Err(format!("invalid wavelength: {}", s)),
+      "Red"   => Ok(Wavelength::RED),
+      "Green" => Ok(Wavelength::GREEN),
+      "Blue"  => Ok(Wavelength::BLUE),
+      w       => match w.parse::<Flt>() {
+        Ok(nm) if (WL_MIN..=WL_MAX).contains(&nm) => Ok(Wavelength(nm)),
+        _                                         => Err(format!("invalid wavelength: {}", s)),
+      },
+    }
+  }
+}
+
+// CIE 1931 colour matching functions, multi-lobe gaussian fit
+fn cie_xyz(nm: &Flt) -> (Flt, Flt, Flt) {
+  let g = |mu: Flt, s1: Flt, s2: Flt| {
+    let t = (nm - mu) / if *nm < mu { s1 } else { s2 };
+    f64::exp(-0.5 * t * t)
+  };
+  let x = 1.056 * g(599.8, 37.9, 31.0) + 0.362 * g(442.0, 16.0, 26.7) - 0.065 * g(501.1, 20.4, 26.2);
+  let y = 0.821 * g(568.8, 46.9, 40.5) + 0.286 * g(530.9, 16.3, 31.1);
+  let z = 1.217 * g(437.0, 11.8, 36.0) + 0.681 * g(459.0, 26.0, 13.8);
+  (x, y, z)
+}
+
+fn smoothstep(a: &Flt, b: &Flt, x: &Flt) -> Flt {
+  let t = ((x - a) / (b - a)).clamp(0.0, 1.0);
+  t * t * (3.0 - 2.0 * t)
+}
+
+// dispersion of a transparent material, wavelength in micrometers
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Dispersion {
+  Cauchy(Flt, Flt),                 // n = A + B / l^2
+  Sellmeier([Flt; 3], [Flt; 3]),    // n^2 = 1 + sum(Bi l^2 / (l^2 - Ci))
+}
+
+impl Dispersion {
+  pub fn ior(&self, wl: &Wavelength) -> Flt {
+    let l2 = wl.micrometer() * wl.micrometer();
+    match self {
+      Dispersion::Cauchy(a, b)      => a + b / l2,
+      Dispersion::Sellmeier(bs, cs) => {
+        let n2 = 1.0 + bs.iter().zip(cs.iter()).map(|(b, c)| b * l2 / (l2 - c)).sum::<Flt>();
+        n2.max(0.0).sqrt()
+      },
     }
   }
+
+  // ior at the representatives of the channels
+  pub fn ior_color(&self) -> Color {
+    Color(self.ior(&Wavelength::RED), self.ior(&Wavelength::GREEN), self.ior(&Wavelength::BLUE))
+  }
 }
 
 #[derive(Debug, PartialEq, Clone, Copy)]
@@ -70,28 +167,47 @@ impl Color {
     }
   }
 
+  // channel where p falls in the accumulated (normalized) colour
   #[inline(always)]
-  pub fn decide_wavelength(&self, p: Flt) -> Wavelength {
+  pub fn decide_channel(&self, p: Flt) -> usize {
     if p < self.0 {
-      Wavelength::Red
+      0
+    } else if p < self.0 + self.1 {
+      1
     } else {
-      if p < self.0 + self.1 {
-        Wavelength::Green
-      } else {
-        Wavelength::Blue
-      }
+      2
     }
   }
 
   #[inline(always)]
-  pub fn wavelength(&self, w: &Wavelength) -> Flt {
-    match w {
-      Wavelength::Red   => self.0,
-      Wavelength::Green => self.1,
-      Wavelength::Blue  => self.2,
+  pub fn channel(&self, i: usize) -> Flt {
+    match i {
+      0 => self.0,
+      1 => self.1,
+      _ => self.2,
     }
   }
 
+  // value of the spectrum made of this colour at a wavelength
+  #[inline(always)]
+  pub fn wavelength(&self, w: &Wavelength) -> Flt {
+    let b = w.bands();
+    self.0 * b.0 + self.1 * b.1 + self.2 * b.2
+  }
+
+  // ior at a wavelength of this RGB ior: n = A + B / l^2 (Cauchy) fitted to the
+  // three channels at their representatives by least squares, so that it is monotone
+  // over the whole spectrum
+  pub fn ior_wavelength(&self, w: &Wavelength) -> Flt {
+    let x = |wl: &Wavelength| 1.0 / (wl.micrometer() * wl.micrometer());
+    let xs = [x(&Wavelength::RED), x(&Wavelength::GREEN), x(&Wavelength::BLUE)];
+    let ns = [self.0, self.1, self.2];
+    let (xm, nm) = ((xs[0] + xs[1] + xs[2]) / 3.0, (ns[0] + ns[1] + ns[2]) / 3.0);
+    let sxn: Flt = (0..3).map(|i| (xs[i] - xm) * (ns[i] - nm)).sum();
+    let sxx: Flt = (0..3).map(|i| (xs[i] - xm) * (xs[i] - xm)).sum();
+    let b = sxn / sxx;
+    nm + b * (x(w) - xm)
+  }
 }
 
 impl fmt::Display for Color {
@@ -101,14 +217,14 @@ impl fmt::Display for Color {
 }
 
 impl FromStr for Color {
-  type Err = ParseFloatError;
+  type Err = String;
 
   fn from_str(s: &str) -> Result<Self, Self::Err> {
     let re = Regex::new(r"^COL\[(\S+?),(\S+?),(\S+?)\]$").unwrap();
-    let caps = re.captures(s).unwrap();
-    let r = caps[1].parse::<Flt>()?;
-    let g = caps[2].parse::<Flt>()?;
-    let b = caps[3].parse::<Flt>()?;
+    let caps = re.captures(s).ok_or(format!("invalid color: {}", s))?;
+    let r = parse_flt(&caps[1], s)?;
+    let g = parse_flt(&caps[2], s)?;
+    let b = parse_flt(&caps[3], s)?;
     Ok(Color::new(r, g, b))
   }
 }
@@ -179,15 +295,6 @@ pub fn relative_ior_color(ior1: &Color, ior2: &Color) -> Color {
   )
 }
 
-#[inline(always)]
-pub fn relative_ior_wavelength(ior1: &Color, ior2: &Color, wl: &Wavelength) -> Flt {
-  match wl {
-    Wavelength::Red   => relative_ior(&ior1.0, &ior2.0),
-    Wavelength::Green => relative_ior(&ior1.1, &ior2.1),
-    Wavelength::Blue  => relative_ior(&ior1.2, &ior2.2),
-  }
-}
-
 #[inline(always)]
 pub fn relative_ior_average(ior1: &Color, ior2: &Color) -> Flt {
   let aior1 = (ior1.0 + ior1.1 + ior1.2) / 3.0;
@@ -197,7 +304,7 @@ pub fn relative_ior_average(ior1: &Color, ior2: &Color) -> Flt {
 
 // eta = n2 / n1
 #[inline(always)]
-fn relative_ior(ior1: &Flt, ior2: &Flt) -> Flt {
+pub fn relative_ior(ior1: &Flt, ior2: &Flt) -> Flt {
   match ior1 {
     0.0 => 1.0,
     _   => ior2 / ior1,
@@ -238,13 +345,12 @@ pub fn reflection_glossy(nvec: &Direction3, rvec: &Direction3, pw: &Flt) -> Dire
     },
   };
   let vvec = uvec.cross(rvec);
-  let mut rng = rand::thread_rng();
   // 試行として入射角に応じて反射ベクトルの分散度を変化させる(cosを掛ける)
   let c0 = nvec.dot(&rvec);
   //if c0 < 0.0 { eprintln!("COS is Minus: {}", c0)}
-  let xi0 = rng.gen_range(0.0, 1.0) as Flt;
+  let xi0 = rng::gen_range(0.0, 1.0) as Flt;
   let xi1 = xi0.powf(pw * c0);
-  let xi2 = 2.0 * f64::consts::PI * rng.gen_range(0.0, 1.0);
+  let xi2 = 2.0 * f64::consts::PI * rng::gen_range(0.0, 1.0);
 
   let x = f64::cos(xi2) * f64::sqrt(1.0 - xi1 * xi1);
   let y = xi1;
@@ -321,8 +427,7 @@ pub fn schlick(f0: &Flt, cos: &Flt) -> Flt {
 // Russian Roulette
 
 pub fn russian_roulette(ps: &[Flt]) -> usize {
-  let mut rng = rand::thread_rng();
-  let p: Flt = rng.gen_range(0.0, 1.0);
+  let p: Flt = rng::gen_range(0.0, 1.0);
   check_under(ps, &p)
 }
 
@@ -342,17 +447,17 @@ mod tests {
 
   #[test]
   fn test_wl() {
-    let r = Wavelength::Red;
-    assert_eq!(r, Wavelength::Red);
-    assert_eq!(format!("{}", Wavelength::Red), "WL:Red");
-    assert_eq!(format!("{}", Wavelength::Green), "WL:Green");
-    assert_eq!(format!("{}", Wavelength::Blue), "WL:Blue");
+    let r = Wavelength::RED;
+    assert_eq!(r, Wavelength(620.0));
+    assert_eq!(format!("{}", Wavelength::RED), "WL:620");
+    assert_eq!(format!("{}", Wavelength(512.5)), "WL:512.5");
     let sr = "WL:Red";
-    assert_eq!(Wavelength::from_str(sr).unwrap(), Wavelength::Red);
+    assert_eq!(Wavelength::from_str(sr).unwrap(), Wavelength::RED);
     let sg = "WL:Green";
-    assert_eq!(Wavelength::from_str(sg).unwrap(), Wavelength::Green);
-    let sb = "WL:Blue";
-    assert_eq!(Wavelength::from_str(sb).unwrap(), Wavelength::Blue);
+    assert_eq!(Wavelength::from_str(sg).unwrap(), Wavelength::GREEN);
+    let sb = "WL:450";
+    assert_eq!(Wavelength::from_str(sb).unwrap(), Wavelength::BLUE);
+    assert!(Wavelength::from_str("WL:900").is_err());
     let sx = "WL:aaa";
     match Wavelength::from_str(sx) {
       Ok(_) => assert!(false, format!("Str '{}' is invalid, but test is through!", sx)),
@@ -372,12 +477,12 @@ mod tests {
     let c3 = c1.normalize();
     assert_eq!(c3.0 + c3.1 + c3.2, 1.0);
     assert_eq!(c3, Color::new(0.1834862385321101, 0.35779816513761464, 0.4587155963302752));
-    assert_eq!(c3.decide_wavelength(0.1), Wavelength::Red);
-    assert_eq!(c3.decide_wavelength(0.3), Wavelength::Green);
-    assert_eq!(c3.decide_wavelength(0.7), Wavelength::Blue);
-    assert_eq!(c2.select_wavelength(Wavelength::Red)  , 0.6);
-    assert_eq!(c2.select_wavelength(Wavelength::Green), 0.21999999999999997);
-    assert_eq!(c2.select_wavelength(Wavelength::Blue) , 0.0);
+    assert_eq!(c3.decide_channel(0.1), 0);
+    assert_eq!(c3.decide_channel(0.3), 1);
+    assert_eq!(c3.decide_channel(0.7), 2);
+    assert_eq!(c2.wavelength(&Wavelength(700.0)), 0.6);
+    assert_eq!(c2.wavelength(&Wavelength(540.0)), 0.21999999999999997);
+    assert_eq!(c2.wavelength(&Wavelength(400.0)), 0.0);
 
     let c4 = Color(0.8, 0.5, 0.3);
     let c5 = Color(0.2, 0.3, 0.8);
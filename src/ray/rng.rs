@@ -0,0 +1,52 @@
+// rng
+//   random number streams. each worker thread owns a PCG stream selected
+//   by set_stream(); a thread never given a stream draws from entropy.
+
+use std::cell::RefCell;
+
+use rand::{FromEntropy, Rng};
+use rand_pcg::Pcg32;
+
+use super::*;
+
+thread_local! {
+  static RNG: RefCell<Pcg32> = RefCell::new(Pcg32::from_entropy());
+}
+
+pub fn set_stream(seed: u64, stream: u64) {
+  RNG.with(|r| *r.borrow_mut() = Pcg32::new(seed, stream));
+}
+
+// the global worker threads (as many as the cores if nthread is 0), each with
+// its own stream of a random seed
+pub fn init_workers(nthread: usize) {
+  let seed: u64 = rand::random();
+  rayon::ThreadPoolBuilder::new()
+    .num_threads(nthread)
+    .start_handler(move |i| set_stream(seed, i as u64))
+    .build_global()
+    .expect("failed to start worker threads");
+}
+
+// uniform in [lo, hi)
+pub fn gen_range(lo: Flt, hi: Flt) -> Flt {
+  RNG.with(|r| r.borrow_mut().gen_range(lo, hi))
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_stream() {
+    set_stream(42, 0);
+    let a: Vec<Flt> = (0..8).map(|_| gen_range(0.0, 1.0)).collect();
+    set_stream(42, 1);
+    let b: Vec<Flt> = (0..8).map(|_| gen_range(0.0, 1.0)).collect();
+    set_stream(42, 0);
+    let c: Vec<Flt> = (0..8).map(|_| gen_range(0.0, 1.0)).collect();
+    assert_eq!(a, c);
+    assert_ne!(a, b);
+    assert!(a.iter().all(|x| 0.0 <= *x && *x < 1.0));
+  }
+}
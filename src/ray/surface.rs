@@ -3,10 +3,12 @@
 //   https://www.slideshare.net/teppeikurita/brdf-196782059
 //
 
-use rand::Rng;
 
 use super::*;
 use super::algebra::*;
+use super::conductor::*;
+use super::disney::*;
+use super::microfacet::*;
 use super::optics::*;
 use super::physics::*;
 
@@ -25,7 +27,8 @@ pub enum Surface {
     // calculate values
     density_pow:   Flt,
   },
-  // Torrance-Sparrow model
+  // Torrance-Sparrow model: GGX microfacets, sampled by the visible normals.
+  // the diffuse and transmitted light is what the Fresnel term leaves.
   TS {
     albedo_diff: Color,
     albedo_spec: Color,
@@ -33,11 +36,38 @@ pub enum Surface {
     metalness:   Flt,   // 0.0:dielectric, 1.0: metal
     roughness:   Flt,
     // calculate values
-    density_pow: Flt,
-    alpha:       Flt,
+    alpha:       Flt,   // of GGX
+  },
+  // Disney principled BRDF
+  DisneyBRDF(Disney),
+  // Ashikhmin-Shirley anisotropic Phong: glossy along a tangent and a bitangent
+  // (brushed metal, satin). the diffuse light is what the Fresnel term leaves.
+  Brady {
+    albedo_diff: Color,
+    albedo_spec: Color,
+    roughness_t: Flt,               // along the tangent
+    roughness_b: Flt,               // along the bitangent
+    tangent:     Option<Direction3>,  // fixed in the scene, or that of the shape
+    // calculate values
+    exp_t:       Flt,   // Phong exponents
+    exp_b:       Flt,
+  },
+  // metal: GGX microfacets with the exact Fresnel term of a complex ior.
+  // what is not reflected is absorbed.
+  Conductor {
+    nk:          Conductor,
+    roughness:   Flt,
+    // calculate values
+    alpha:       Flt,   // of GGX
+  },
+  // dielectric boundary (GGX) of a translucent object: the light entering it walks
+  // through the scattering and absorption of the material (marble, wax, milk).
+  // what is seen is the light that comes out of the surface again.
+  Subsurface {
+    roughness:   Flt,
+    // calculate values
+    alpha:       Flt,   // of GGX
   },
-  DisneyBRDF,
-  Brady,
 }
 
 impl Surface {
@@ -60,8 +90,36 @@ impl Surface {
       scatterness: *scat,
       metalness:   *meta,
       roughness:   *rough,
-      density_pow: 1.0 / (10.0_f64.powf(5.0 * (1.0 - f64::sqrt(*rough))) + 1.0),
-      alpha:       *rough * *rough * *rough * *rough,
+      alpha:       (*rough * *rough).max(MIN_ALPHA),
+    }
+  }
+
+  // exponents by alpha = roughness^2 as the Ns of MTL: Ns = 2 / alpha^2 - 2
+  pub fn new_brady(al_diff: &Color, al_spec: &Color, rough_t: &Flt, rough_b: &Flt, tangent: &Option<Direction3>) -> Surface {
+    let exponent = |r: &Flt| 2.0 / (r * r).max(MIN_ALPHA).powi(2) - 2.0;
+    Surface::Brady {
+      albedo_diff: *al_diff,
+      albedo_spec: *al_spec,
+      roughness_t: *rough_t,
+      roughness_b: *rough_b,
+      tangent:     *tangent,
+      exp_t:       exponent(rough_t),
+      exp_b:       exponent(rough_b),
+    }
+  }
+
+  pub fn new_conductor(nk: &Conductor, rough: &Flt) -> Surface {
+    Surface::Conductor {
+      nk:          *nk,
+      roughness:   *rough,
+      alpha:       (*rough * *rough).max(MIN_ALPHA),
+    }
+  }
+
+  pub fn new_subsurface(rough: &Flt) -> Surface {
+    Surface::Subsurface {
+      roughness:   *rough,
+      alpha:       (*rough * *rough).max(MIN_ALPHA),
     }
   }
 
@@ -86,7 +144,6 @@ impl Surface {
         scatterness,
         metalness,
         roughness,
-        density_pow,
         alpha,
       } => {
         match metalness {
@@ -95,7 +152,10 @@ impl Surface {
           _   => true,
         }
       },
-      _ => false,
+      Surface::DisneyBRDF(_) => true,
+      Surface::Brady {albedo_spec, ..} => *albedo_spec != Color::BLACK,
+      Surface::Conductor {..} => true,
+      Surface::Subsurface {..} => true,
     }
   }
 
@@ -118,7 +178,6 @@ impl Surface {
         scatterness,
         metalness,
         roughness,
-        density_pow,
         alpha,
       } => {
         if *metalness == 0.0 {
@@ -132,8 +191,8 @@ impl Surface {
     }
   }
 
-  pub fn bsdf(&self, nvec: &Direction3, edir: &Direction3, rdir: &Direction3, tdir: &Option<Direction3>,
-              cos0: &Flt, ior: &Flt, di: &Radiance, si: &Radiance, ti: &Radiance)
+  pub fn bsdf(&self, nvec: &Direction3, tvec: &Option<Direction3>, edir: &Direction3, rdir: &Direction3,
+              tdir: &Option<Direction3>, cos0: &Flt, ior: &Flt, di: &Radiance, si: &Radiance, ti: &Radiance)
              -> Radiance {
     //let mate = is.mate;
 
@@ -158,62 +217,84 @@ impl Surface {
         scatterness,
         metalness,
         roughness,
-        density_pow,
         alpha,
       } => {
         let lvec = *rdir;
         let vvec = -*edir;
-        let hvec = (lvec + vvec).normalize().unwrap();
-        let cos_h = hvec.dot(&vvec);
-        //let f = reflection_index(albedo_spec, &cos_h);
-        let f = reflection_index(albedo_spec, &cos0);
-        let f2 = -f;  // (1 - f)
-        let i_de = match metalness {
-          0.0 => f2 * *albedo_diff * (
-            *scatterness         * ONE_PI * *di
-            // 屈折率の異なる物質に入射した光の輝度は1/eta^2となるらしい
-            //+ (1.0 - *scatterness) * 1.0 / (ior * ior) * *ti
-            + (1.0 - *scatterness) * *ti
-          ),
-          _   => Radiance::RADIANCE0,
+        let hvec = match (lvec + vvec).normalize() {
+          Some(h) => h,
+          None    => *nvec,
         };
-        /*
-        let cos_v = nvec.dot(&vvec);   // (v.n)
-        //let cos_v = *cos0;             // (v.n)
-        let cos_l = nvec.dot(&lvec);   // (l.n)
-        let cos_n = nvec.dot(&hvec);   // (h.n)
-        //println!("a:{}/cos_n:{}/cos_v:{}/cos_l:{}", alpha, cos_n, cos_v, cos_l);
-        let cos_sq = cos_n * cos_n;
-        let d0 = cos_sq * (alpha - 1.0) + 1.0;
-        let d = (alpha / f64::consts::PI) / (d0 * d0);
-        let lam_v = -1.0 + f64::sqrt(1.0 + alpha * (1.0 / (cos_v * cos_v) - 1.0)) / 2.0;
-        let lam_l = -1.0 + f64::sqrt(1.0 + alpha * (1.0 / (cos_l * cos_l) - 1.0)) / 2.0;
-        let g = (1.0 / (1.0 + lam_l)) * (1.0 / (1.0 + lam_v));
-        //let i_mt = (d * g / (4.0 * cos_l * cos_v)) * f * *si;
-        // そもそもsiにTSモデルなどは適用しない？
-        */
-        let i_mt = f * *si;
-        //let i_mt = f * *si + (f2 * ior * ior) * *ti;
+        let f = reflection_index(albedo_spec, &fresnel_cos(&hvec, &vvec, tdir, scatterness));
+        let f2 = -f;  // (1 - f)
+        let i_de = (1.0 - *metalness) * f2 * *albedo_diff * (
+          *scatterness         * ONE_PI * *di
+          // 屈折率の異なる物質に入射した光の輝度は1/eta^2となるらしい
+          //+ (1.0 - *scatterness) * 1.0 / (ior * ior) * *ti
+          + (1.0 - *scatterness) * *ti
+        );
+        // D G F / (4 cos_l cos_v) * cos_l / pdf of the sampled lvec
+        let g = ggx_reflection_weight(&Frame::new(nvec), &lvec, &vvec, alpha, alpha);
+        let i_mt = (g * f) * *si;
         i_de + i_mt
       },
-      Surface::DisneyBRDF   => {
-        Radiance::RADIANCE0
+      Surface::DisneyBRDF(d) => {
+        let vvec = -*edir;
+        d.diffuse(nvec, &vvec) * *di + d.specular(&self.frame(nvec, tvec), &vvec, rdir) * *si
+      },
+      Surface::Brady {albedo_diff, albedo_spec, ..} => {
+        let lvec = *rdir;
+        let vvec = -*edir;
+        let hvec = match (lvec + vvec).normalize() {
+          Some(h) => h,
+          None    => *nvec,
+        };
+        let f = reflection_index(albedo_spec, &hvec.dot(&vvec).max(0.0));
+        // the light of di is taken as coming from the normal
+        let i_d = (-f * *albedo_diff) * (brady_diffuse(&nvec.dot(&vvec), &1.0) * ONE_PI) * *di;
+        let i_s = (as_reflection_weight(nvec, &lvec, &vvec) * f) * *si;
+        i_d + i_s
+      },
+      Surface::Conductor {nk, alpha, ..} => {
+        let lvec = *rdir;
+        let vvec = -*edir;
+        let hvec = match (lvec + vvec).normalize() {
+          Some(h) => h,
+          None    => *nvec,
+        };
+        let f = nk.fresnel_color(&hvec.dot(&vvec).max(0.0));
+        let g = ggx_reflection_weight(&Frame::new(nvec), &lvec, &vvec, alpha, alpha);
+        (g * f) * *si
       },
-      Surface::Brady        => {
-        Radiance::RADIANCE0
+      Surface::Subsurface {alpha, ..} => {
+        let lvec = *rdir;
+        let vvec = -*edir;
+        let hvec = match (lvec + vvec).normalize() {
+          Some(h) => h,
+          None    => *nvec,
+        };
+        let f = schlick(&dielectric_f0(ior), &hvec.dot(&vvec).max(0.0));
+        let g = ggx_reflection_weight(&Frame::new(nvec), &lvec, &vvec, alpha, alpha);
+        // di: the light come out of the surface (the photons stored there as they
+        // left it), taken as Lambertian, less the part the surface reflects specularly.
+        (1.0 - f) * ONE_PI * *di + (g * f) * *si
       },
     }
   }
 
+  // IN : tvec  tangent of the shape, if any
   // OUT: dir  next ray direction. if dir is None, the photon is absorbed.
   //      T/F  true=reflection, false=refraction
 
-  pub fn next_direction(&self, eta: &Flt, nvec: &Direction3, vvec: &Direction3, wl: &Wavelength) -> Option<(Direction3, bool)> {
-    let (rdir0, cos1) = specular_reflection(nvec, vvec);
-    let rdir = reflection_glossy(nvec, &rdir0, &self.power_glossy());
-    let hvec = (rdir - *vvec).normalize().unwrap();
-    let (tdir, cos2) = specular_refraction(&hvec, vvec, eta);
-    let cos = if cos1 < cos2 { cos1 } else { cos2 };
+  pub fn next_direction(&self, eta: &Flt, nvec: &Direction3, tvec: &Option<Direction3>, vvec: &Direction3, wl: &Wavelength) -> Option<(Direction3, bool)> {
+    // the microfacet reflection and refraction, sampled only by the surfaces using them
+    let sample_dirs = || {
+      let (rdir0, _cos1) = specular_reflection(nvec, vvec);
+      let rdir = self.reflection_dir(nvec, tvec, vvec, &rdir0);
+      let hvec = (rdir - *vvec).normalize().unwrap();
+      let (tdir, _cos2) = specular_refraction(&hvec, vvec, eta);
+      (rdir, hvec, tdir)
+    };
     match self {
       Surface::Simple {
         reflectance,
@@ -231,16 +312,20 @@ impl Surface {
         scatterness,
         metalness,
         roughness,
-        density_pow,
         alpha,
       } => {
-        let f = schlick(&albedo_spec.wavelength(&wl), &cos);
-        // 鏡面反射
+        let (rdir, hvec, tdir) = sample_dirs();
+        let f = schlick(&albedo_spec.wavelength(&wl), &fresnel_cos(&hvec, &-*vvec, &tdir, scatterness));
+        // 鏡面反射 (マイクロファセットの遮蔽で失われる分は吸収)
         if russian_roulette(&[f]) == 0 {
-          return Some((rdir, true))
+          let g = ggx_reflection_weight(&Frame::new(nvec), &rdir, &-*vvec, alpha, alpha);
+          return match russian_roulette(&[g]) {
+            0 => Some((rdir, true)),
+            _ => None,
+          }
         }
         // 吸収
-        if russian_roulette(&[albedo_diff.wavelength(&wl)]) == 1 {
+        if russian_roulette(&[(1.0 - *metalness) * albedo_diff.wavelength(&wl)]) == 1 {
           return None
         }
         // 拡散反射
@@ -253,10 +338,100 @@ impl Surface {
           _ => None,
         }
       },
+      Surface::DisneyBRDF(d) => d.scatter(&self.frame(nvec, tvec), vvec, wl).map(|dir| (dir, true)),
+      Surface::Brady {albedo_diff, albedo_spec, exp_t, exp_b, ..} => {
+        let fr = self.frame(nvec, tvec);
+        let v = fr.to_local(&-*vvec);
+        let h = sample_as(exp_t, exp_b);
+        // 鏡面反射 (表面の下へ向かう分は吸収)
+        if russian_roulette(&[schlick(&albedo_spec.wavelength(wl), &v.dot(&h).max(0.0))]) == 0 {
+          let l = fr.to_world(&reflect_local(&v, &h)).normalize()?;
+          return match russian_roulette(&[as_reflection_weight(nvec, &l, &-*vvec)]) {
+            0 => Some((l, true)),
+            _ => None,
+          }
+        }
+        // 拡散反射: 方向を cos * g(cos_l) に比例して選ぶと係数 28/23 はその正規化で
+        // 打ち消され、重みは Rd * g(cos_v) (< 1) になる
+        let l = loop {
+          let l = diffuse_reflection(nvec);
+          if russian_roulette(&[brady_g(&nvec.dot(&l))]) == 0 {
+            break l
+          }
+        };
+        let a = albedo_diff.wavelength(wl) * brady_g(&-nvec.dot(vvec));
+        match russian_roulette(&[a]) {
+          0 => Some((l, true)),
+          _ => None,
+        }
+      },
+      Surface::Conductor {nk, alpha, ..} => {
+        let (rdir, hvec, _) = sample_dirs();
+        // 鏡面反射 (それ以外は吸収)
+        if russian_roulette(&[nk.fresnel(&hvec.dot(&-*vvec).max(0.0), wl)]) == 0 {
+          let g = ggx_reflection_weight(&Frame::new(nvec), &rdir, &-*vvec, alpha, alpha);
+          if russian_roulette(&[g]) == 0 {
+            return Some((rdir, true))
+          }
+        }
+        None
+      },
+      Surface::Subsurface {alpha, ..} => {
+        let (rdir, hvec, tdir) = sample_dirs();
+        // 鏡面反射 (全反射を含む)、それ以外は屈折して入る(出る)
+        let f = match tdir {
+          Some(tdir) => schlick(&dielectric_f0(eta), &hvec.dot(&-*vvec).max(0.0).min(-tdir.dot(&hvec))),
+          None       => 1.0,
+        };
+        if russian_roulette(&[f]) == 0 {
+          let g = ggx_reflection_weight(&Frame::new(nvec), &rdir, &-*vvec, alpha, alpha);
+          return match russian_roulette(&[g]) {
+            0 => Some((rdir, true)),
+            _ => None,
+          }
+        }
+        tdir.map(|t| (t, false))
+      },
       _ => None,
     }
   }
 
+  // direction of the reflected ray of an eye ray
+  // IN : tvec  tangent of the shape, if any
+  //      edir  eye direction (to surface)
+  //      rdir  specular reflection of edir
+  pub fn reflection_dir(&self, nvec: &Direction3, tvec: &Option<Direction3>, edir: &Direction3, rdir: &Direction3) -> Direction3 {
+    match self {
+      Surface::TS {
+        albedo_diff,
+        albedo_spec,
+        scatterness,
+        metalness,
+        roughness,
+        alpha,
+      } => sample_ggx_reflection(&Frame::new(nvec), &-*edir, alpha, alpha).unwrap_or(*rdir),
+      Surface::DisneyBRDF(d) => d.sample_specular(&self.frame(nvec, tvec), &-*edir, rdir),
+      Surface::Brady {exp_t, exp_b, ..}
+        => sample_as_reflection(&self.frame(nvec, tvec), &-*edir, exp_t, exp_b).unwrap_or(*rdir),
+      Surface::Conductor {alpha, ..} | Surface::Subsurface {alpha, ..}
+        => sample_ggx_reflection(&Frame::new(nvec), &-*edir, alpha, alpha).unwrap_or(*rdir),
+      _ => reflection_glossy(nvec, rdir, &self.power_glossy()),
+    }
+  }
+
+  // local frame of an anisotropic surface: the tangent given in the scene, that
+  // of the shape, or any
+  pub fn frame(&self, nvec: &Direction3, tvec: &Option<Direction3>) -> Frame {
+    let t = match self {
+      Surface::Brady {tangent: Some(t), ..} => Some(*t),
+      _                                     => *tvec,
+    };
+    match t {
+      Some(t) => Frame::with_tangent(nvec, &t),
+      None    => Frame::new(nvec),
+    }
+  }
+
   pub fn select_diffuse(&self, cos: &Flt, wl: &Wavelength) -> bool {
     match self {
       Surface::Simple {
@@ -276,7 +451,6 @@ impl Surface {
         scatterness,
         metalness,
         roughness,
-        density_pow,
         alpha,
       } => {
         let f = schlick(&albedo_spec.wavelength(wl), &cos);
@@ -302,9 +476,12 @@ impl Surface {
         scatterness,
         metalness,
         roughness,
-        density_pow,
         alpha,
       } => *metalness != 1.0 && *scatterness != 0.0,
+      Surface::DisneyBRDF(d) => d.metallic < 1.0,
+      Surface::Brady {albedo_diff, ..} => *albedo_diff != Color::BLACK,
+      Surface::Conductor {..} => false,
+      Surface::Subsurface {..} => false,   // but where the photons come out
       _ => true,     
     }
   }
@@ -325,9 +502,10 @@ impl Surface {
         scatterness,
         metalness,
         roughness,
-        density_pow,
         alpha,
       } => albedo_diff.wavelength(wl),
+      Surface::DisneyBRDF(d) => d.albedo_diff().wavelength(wl),
+      Surface::Brady {albedo_diff, ..} => albedo_diff.wavelength(wl),
       _ => 0.0,
     }
   }
@@ -348,9 +526,11 @@ impl Surface {
         scatterness,
         metalness,
         roughness,
-        density_pow,
         alpha,
       } => albedo_spec.wavelength(wl),
+      Surface::DisneyBRDF(d) => d.albedo_spec().wavelength(wl),
+      Surface::Brady {albedo_spec, ..} => albedo_spec.wavelength(wl),
+      Surface::Conductor {nk, ..} => nk.fresnel(&1.0, wl),
       _ => 0.0,
     }
   }
@@ -371,9 +551,12 @@ impl Surface {
         scatterness,
         metalness,
         roughness,
-        density_pow,
         alpha,
       } => *roughness,
+      Surface::DisneyBRDF(d) => d.roughness,
+      Surface::Brady {roughness_t, roughness_b, ..} => 0.5 * (roughness_t + roughness_b),
+      Surface::Conductor {roughness, ..} => *roughness,
+      Surface::Subsurface {roughness, ..} => *roughness,
       _ => 0.0,
     }
   }
@@ -388,15 +571,6 @@ impl Surface {
         roughness,
         density_pow,
       } => *density_pow,
-      Surface::TS {
-        albedo_diff,
-        albedo_spec,
-        scatterness,
-        metalness,
-        roughness,
-        density_pow,
-        alpha,
-      } => *density_pow,
       _ => 0.0,
     }
   }
@@ -509,6 +683,33 @@ pub fn specular_refraction(ior: &Flt, c0: &Flt, ed: &Direction3, n: &Direction3,
 
 // private methods
 
+// cosine for the Fresnel term at a microfacet hvec: that of the refraction if
+// smaller (light from a denser medium), 0 on total internal reflection.
+// a surface without transmission has no refraction.
+fn fresnel_cos(hvec: &Direction3, vvec: &Direction3, tdir: &Option<Direction3>, scat: &Flt) -> Flt {
+  let cos1 = hvec.dot(vvec).max(0.0);
+  match tdir {
+    Some(t)            => cos1.min(-t.dot(hvec)),
+    None if *scat < 1.0 => 0.0,
+    None               => cos1,
+  }
+}
+
+// diffuse term of Ashikhmin-Shirley over Rd / pi, without (1 - Rs).
+// 28/23 normalizes g(cos_l) over the hemisphere weighted by the cosine
+fn brady_diffuse(cos_v: &Flt, cos_l: &Flt) -> Flt {
+  28.0 / 23.0 * brady_g(cos_v) * brady_g(cos_l)
+}
+
+fn brady_g(c: &Flt) -> Flt {
+  1.0 - (1.0 - 0.5 * c.max(0.0)).powi(5)
+}
+
+// reflectance at the normal incidence on a dielectric of the relative ior
+fn dielectric_f0(eta: &Flt) -> Flt {
+  ((eta - 1.0) / (eta + 1.0)).powi(2)
+}
+
 fn reflection_index(col: &Color, c: &Flt) -> Color {
   let c2 = (1.0 - *c).powf(5.0);
   Color(col.0 + (1.0 - col.0) * c2, col.1 + (1.0 - col.1) * c2, col.2 + (1.0 - col.2) * c2)
@@ -0,0 +1,113 @@
+// volume
+//   participating media: homogeneous scattering inside a material (fog, smoke),
+//   with the Henyey-Greenstein phase function. the absorption is that of the
+//   transmittance of the material (Beer-Lambert).
+//
+//   photons fly a free path sampled by the scattering coefficient at their
+//   wavelength and are stored in the volume photon map where they scatter.
+//   eye rays march through the medium, gathering the volume photons.
+
+use std::f64;
+
+use super::*;
+use super::algebra::*;
+use super::microfacet::*;
+use super::physics::*;
+use super::rng;
+
+const ONE_PI4: Flt = 1.0 / PI4;
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct Scattering {
+  pub sigma_s: Color,   // scattering coefficient, per unit distance
+  pub g:       Flt,     // asymmetry of the phase function, (-1, 1): forward if positive
+}
+
+impl Scattering {
+  // Henyey-Greenstein phase function of the angle between the directions
+  // of the light before and after the scattering
+  pub fn phase(&self, cos: &Flt) -> Flt {
+    let g2 = self.g * self.g;
+    ONE_PI4 * (1.0 - g2) / (1.0 + g2 - 2.0 * self.g * cos).powf(1.5)
+  }
+
+  // direction after a scattering of the light going along dir
+  pub fn sample(&self, dir: &Direction3) -> Direction3 {
+    let xi: Flt = rng::gen_range(0.0, 1.0);
+    let g = self.g;
+    let cos = if g.abs() < 1.0e-3 {
+      1.0 - 2.0 * xi
+    } else {
+      let s = (1.0 - g * g) / (1.0 - g + 2.0 * g * xi);
+      ((1.0 + g * g - s * s) / (2.0 * g)).clamp(-1.0, 1.0)
+    };
+    let sin = (1.0 - cos * cos).max(0.0).sqrt();
+    let phi: Flt = rng::gen_range(0.0, PI2);
+    let l = Vector3::new(sin * phi.cos(), cos, sin * phi.sin());
+    Frame::new(dir).to_world(&l).normalize().unwrap_or(*dir)
+  }
+
+  // distance a photon flies to the next scattering, infinite if it does not scatter
+  pub fn free_path(&self, wl: &Wavelength) -> Flt {
+    let s = self.sigma_s.wavelength(wl);
+    if s <= 0.0 {
+      return f64::INFINITY
+    }
+    let xi: Flt = rng::gen_range(0.0, 1.0);
+    -(1.0 - xi).ln() / s
+  }
+
+  // light left after travelling d without being scattered out
+  pub fn transmittance(&self, d: &Flt) -> Color {
+    let t = |s: Flt| (-s * d).exp();
+    Color(t(self.sigma_s.0), t(self.sigma_s.1), t(self.sigma_s.2))
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_phase() {
+    // normalized over the sphere
+    for g in [0.0, 0.5, -0.7].iter() {
+      let s = Scattering {sigma_s: Color::WHITE, g: *g};
+      let n = 2000;
+      let sum: Flt = (0..n).map(|i| {
+        let cos = -1.0 + 2.0 * (i as Flt + 0.5) / n as Flt;
+        s.phase(&cos) * PI2 * 2.0 / n as Flt
+      }).sum();
+      assert!((sum - 1.0).abs() < 1.0e-3, "g={}: {}", g, sum);
+    }
+    let iso = Scattering {sigma_s: Color::WHITE, g: 0.0};
+    assert!((iso.phase(&0.3) - ONE_PI4).abs() < 1.0e-12);
+  }
+
+  #[test]
+  fn test_sample() {
+    // the mean cosine of Henyey-Greenstein is g
+    let s = Scattering {sigma_s: Color::WHITE, g: 0.6};
+    let dir = Vector3::new(1.0, 2.0, -1.0).normalize().unwrap();
+    let n = 20000;
+    let mut sum = 0.0;
+    for _ in 0..n {
+      let d = s.sample(&dir);
+      assert!((d.norm() - 1.0).abs() < 1.0e-9);
+      sum += d.dot(&dir);
+    }
+    assert!((sum / n as Flt - 0.6).abs() < 0.02);
+  }
+
+  #[test]
+  fn test_free_path() {
+    let s = Scattering {sigma_s: Color(0.0, 0.0, 2.0), g: 0.0};
+    assert_eq!(s.free_path(&Wavelength::RED), f64::INFINITY);
+    let n = 20000;
+    let mean: Flt = (0..n).map(|_| s.free_path(&Wavelength(400.0))).sum::<Flt>() / n as Flt;
+    assert!((mean - 0.5).abs() < 0.02);
+    let t = s.transmittance(&0.5);
+    assert_eq!(t.0, 1.0);
+    assert!((t.2 - (-1.0 as Flt).exp()).abs() < 1.0e-12);
+  }
+}
@@ -1,6 +1,18 @@
 // scene
 
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+use std::sync::Arc;
+
+use yaml_rust::{Yaml, YamlLoader};
+
+use super::error::*;
+
+use super::ray::*;
 use super::ray::algebra::*;
+use super::ray::conductor::*;
+use super::ray::disney::*;
 use super::ray::geometry::*;
 use super::ray::light::*;
 use super::ray::material::*;
@@ -8,441 +20,1122 @@ use super::ray::object::*;
 use super::ray::optics::*;
 use super::ray::physics::*;
 use super::ray::surface::*;
+use super::ray::volume::*;
+use super::ply::*;
+use super::stl::*;
+use super::wavefront::*;
 
 // CONSTANTS
 pub const M_AIR: Material = Material {
   emittance:     Radiance::RADIANCE0,
-  transmittance: Color::WHITE,
+  transmittance: Color::BLACK,
   ior:           Color(1.0, 1.0, 1.0),
+  dispersion:    None,
+  scattering:    None,
   surface: Surface::Nothing,
+  priority: 0,
 };
 
-pub fn read_scene(_file: &str) -> (Vec<Light>, Vec<Object>) {
+// scene file format (YAML)
+//
+//   light:     list of lights (type: point | parallelogram | sun)
+//   material:  list of named materials (type: solid | ts | disney | brady | conductor |
+//              subsurface | volume),
+//              with an optional 'priority' for overlapping transparent objects and an
+//              optional dispersion (cauchy or sellmeier) in place of the RGB 'ior'
+//   vertex:    named positions referred from polygons and parallelograms
+//   object:    list of objects (type: plain | sphere | polygon | parallelogram | mesh |
+//              box | cylinder | cone | disk | torus | obj | ply | stl | instance | csg)
+//
+// transmittance: the colour absorbed over the unit distance inside a material, by which
+//   photons and eye rays are attenuated through it (Beer-Lambert); [ 0, 0, 0 ] is clear.
+//   'absorption' may be given in place of it, as the coefficient per unit distance.
+//
+// participating media: 'scattering' (the coefficient per unit distance, RGB) and an
+//   optional 'phase_g' in (-1, 1) of the Henyey-Greenstein phase function (0 by
+//   default: isotropic, forward if positive) make the inside of a material scatter the
+//   light. 'volume' materials have no surface (and need no 'ior'): objects of them
+//   only bound a medium, such as a box of haze filling a room. photons scattered in
+//   media are stored in the volume photon map, gathered along eye rays.
+//
+// subsurface materials (translucent: marble, wax, milk): ior, roughness of the surface
+//   and 'scattering' inside it, or 'mean_free_path' (RGB) in place of it, with the
+//   optional 'phase_g' and 'absorption' (or 'transmittance'). photons entering a
+//   closed object of them walk inside until they come out, where they are stored; eye
+//   rays see those photons and the glossy reflection only.
+//
+// disney materials: base_color and optional subsurface, metallic, specular,
+//   specular_tint, roughness, anisotropic, sheen, sheen_tint, clearcoat and
+//   clearcoat_gloss, all in [0, 1].
+//
+// brady materials (anisotropic): albedo_diff, albedo_spec, roughness_t and roughness_b
+//   along the tangent and the bitangent, and an optional tangent direction (in the
+//   world). without it, the tangent is around the axis of spheres (the y axis),
+//   cylinders, cones, disks and tori, along dir1 of polygons and parallelograms,
+//   and along u of meshes with uvs.
+//
+// conductor materials (metals): roughness and either 'metal' (Au, Ag, Cu, Al or Cr, or
+//   gold, silver, copper, aluminium and chromium) for the measured complex ior, or
+//   the RGB 'n' and 'k' of it.
+//
+// dispersion: 'cauchy: [A, B]' (n = A + B / l^2) or 'sellmeier: [B1, B2, B3, C1, C2, C3]'
+//   (n^2 = 1 + sum(Bi l^2 / (l^2 - Ci))), l in micrometers. photons refract by the
+//   ior at their wavelength; 'ior' may then be omitted. without them, the ior at a
+//   wavelength is interpolated from the RGB 'ior'.
+//
+// solids:
+//   box:      min, max (axis aligned), or position and edges dir1, dir2, dir3 (oriented)
+//   cylinder: position (center of the bottom), axis (to the center of the top), radius
+//   cone:     position (center of the base), axis (to the apex), radius
+//   disk:     center, normal, radius
+//   torus:    center, axis, major_radius (of the center line of the tube), minor_radius
+//
+// 'obj', 'ply' and 'stl' objects load a mesh file (relative to the scene file).
+// 'material' is required for PLY and STL; for OBJ it overrides the MTL materials.
+// a file used again is not loaded again but shared.
+//
+// an 'instance' places the object named by 'object' again, sharing its shape,
+// with an optional 'material' in place of the original one.
+//
+// a 'csg' object combines 'left' and 'right' by 'op' (union | intersection | difference).
+// an operand is a closed object written in place (without material, may be a csg),
+// or the name of an object defined before.
+//
+// every object can have a transform, applied in the object space of the shape:
+//   transform: { scale: s or [x, y, z], rotate: [x, y, z] in degrees, translate: [x, y, z] }
+// in this order (rotation around x first, then y, then z).
+//
+// other top level sections are ignored.
 
-  // light
-  let l1 = Light::ParallelogramLight {
-    color: Color(1.0, 1.0, 1.0).normalize(),
-    flux: 5.0,
-    pos: Vector3::new(-0.67, 3.99, 2.33),
-    nvec: -Vector3::EY,
-    dir1: Vector3::new(1.33, 0.0, 0.0),
-    dir2: Vector3::new(0.0, 0.0, 1.33),
-  };
+pub fn read_scene(file: &str) -> Result<(Vec<Light>, Vec<Object>), Error> {
+  let contents = fs::read_to_string(file).map_err(|e| Error::io(file, &e))?;
+  let dir = Path::new(file).parent().unwrap_or_else(|| Path::new(""));
+  parse_scene_at(&contents, dir).map_err(|e| e.in_file(file))
+}
 
-  // material
-  let mwall = Material {
-    emittance: Radiance(0.0, 0.0, 0.0),
-    transmittance: Color(0.0, 0.0, 0.0),
-    ior:           Color(0.0, 0.0, 0.0),
-    surface: Surface::new_simple(
-      &Color(0.5, 0.5, 0.5),
-      &Color(0.8, 0.8, 0.8),
-      &1.0,
-      &0.0,
-      &0.0,
-    ),
-  };
-  let mwallb = Material {
-    emittance: Radiance(0.0, 0.0, 0.0),
-    transmittance: Color(0.0, 0.0, 0.0),
-    ior:           Color(0.0, 0.0, 0.0),
-    surface: Surface::new_simple(
-      &Color(0.1, 0.1, 0.4),
-      &Color(0.8, 0.0, 0.8),
-      &1.0,
-      &0.0,
-      &0.0,
-    ),
+pub fn parse_scene(contents: &str) -> Result<(Vec<Light>, Vec<Object>), Error> {
+  parse_scene_at(contents, Path::new(""))
+}
+
+//--------------------
+// private
+
+// files referred from the scene are looked for in dir
+fn parse_scene_at(contents: &str, dir: &Path) -> Result<(Vec<Light>, Vec<Object>), Error> {
+  let docs = YamlLoader::load_from_str(contents).map_err(|e| {
+    let msg = e.to_string();
+    Error::new(msg.split(" at line ").next().unwrap_or(&msg)).at_line(Some(e.marker().line()))
+  })?;
+  if docs.is_empty() {
+    return Ok((vec![], vec![]))
+  }
+  let doc = &docs[0];
+
+  let lgts = match doc["light"].as_vec() {
+    Some(ls) => ls.iter().enumerate()
+      .map(|(i, l)| conv_light(l).map_err(|e| locate(contents, "light", Some(i), e)))
+      .collect::<Result<Vec<Light>, Error>>()?,
+    None     => vec![],
   };
-  let mwallr = Material {
-    emittance: Radiance(0.0, 0.0, 0.0),
-    transmittance: Color(0.0, 0.0, 0.0),
-    ior:           Color(0.0, 0.0, 0.0),
-    surface: Surface::new_simple(
-      &Color(0.4, 0.1, 0.1),
-      &Color(0.0, 0.0, 0.0),
-      &1.0,
-      &0.0,
-      &0.0,
-    ),
+  let mut mates: HashMap<String, Material> = HashMap::new();
+  if let Some(ms) = doc["material"].as_vec() {
+    for (i, m) in ms.iter().enumerate() {
+      let (name, mate) = conv_material(m).map_err(|e| locate(contents, "material", Some(i), e))?;
+      mates.insert(name, mate);
+    }
+  }
+  let verts = conv_vertices(&doc["vertex"]).map_err(|e| locate(contents, "vertex", None, e))?;
+  let mut objs: Vec<Object> = vec![];
+  let mut shared = Shared {dir, named: HashMap::new(), meshes: HashMap::new()};
+  if let Some(os) = doc["object"].as_vec() {
+    for (i, o) in os.iter().enumerate() {
+      objs.extend(conv_object(o, &mates, &verts, &mut shared).map_err(|e| locate(contents, "object", Some(i), e))?);
+    }
+  }
+  Ok((lgts, objs))
+}
+
+fn conv_light(l: &Yaml) -> Result<Light, Error> {
+  let color = get_color(l, "color")?.normalize();
+  let flux = if l["flux"].is_badvalue() {
+    get_float(l, "power")?
+  } else {
+    get_float(l, "flux")?
   };
-  // == ORIGINAL
-  /*
-  let glass = Material {
-    emittance: Radiance(0.0, 0.0, 0.0),
-    transmittance: Color(0.0, 0.0, 0.0),
-    ior:           Color(2.0, 2.0, 2.0),
-    surface: Surface::Simple {
-      reflectance: Color(0.0, 0.0, 0.0),
-      specular_refl: Color(0.08, 0.08, 0.08),
-      diffuseness:   0.0,
-      metalness:     0.0,
-      roughness:    0.0,
+  let pos = get_vector(l, "position")?;
+  match get_str(l, "type")? {
+    "point" => Ok(Light::PointLight {
+      color,
+      flux,
+      pos,
+    }),
+    "parallelogram" => {
+      let dir1 = get_vector(l, "dir1")?;
+      let dir2 = get_vector(l, "dir2")?;
+      Ok(Light::ParallelogramLight {
+        color,
+        flux,
+        pos,
+        nvec: normal_of(&dir1, &dir2, "dir2")?,
+        dir1,
+        dir2,
+      })
     },
-  };
-  */
-  /*
-  let glass = Material {
-    emittance: Radiance(0.0, 0.0, 0.0),
-    reflectance: Color(0.0, 0.0, 0.0),
-    transmittance: Color(0.0, 0.0, 0.0),
-    specular_refl: Color(0.08, 0.08, 0.08),
-    ior:           Color(2.0, 2.0, 2.0),
-    diffuseness:   0.0,
-    metalness:     0.0,
-    roughness:    0.0,
-  };
-  */
-  // == ORIGINAL
-  /*
-  let silver = Material {
-    emittance: Radiance(0.0, 0.0, 0.0),
-    transmittance: Color(0.0, 0.0, 0.0),
-    ior:           Color(0.0, 0.0, 0.0),
-    surface: Surface::Simple {
-      reflectance: Color(0.0, 0.0, 0.0),
-      specular_refl: Color(0.78, 0.78, 0.78),
-      diffuseness:   0.0,
-      metalness:     1.0,
-      roughness:    0.0,
+    "sun" => {
+      let dir1 = get_vector(l, "dir1")?;
+      let dir2 = get_vector(l, "dir2")?;
+      let ldir = get_vector(l, "ldir")?.normalize()
+        .ok_or_else(|| value_error(&l["ldir"], "ldir", "a non-zero vector"))?;
+      Ok(Light::SunLight {
+        color,
+        flux,
+        pos,
+        nvec: normal_of(&dir1, &dir2, "dir2")?,
+        dir1,
+        dir2,
+        dir: ldir,
+      })
     },
-  };
-  */
-  /*
-  let silver = Material {
-    emittance: Radiance(0.0, 0.0, 0.0),
-    reflectance: Color(0.5, 0.3, 0.1),
-    transmittance: Color(0.0, 0.0, 0.0),
-    specular_refl: Color(0.78, 0.78, 0.78),
-    ior:           Color(0.0, 0.0, 0.0),
-    diffuseness:   0.0,
-    metalness:     1.0,
-    roughness:    0.0,
-  };
-  */
-  /*
-  let ball10 = Material {
-    emittance: Radiance(0.0, 0.0, 0.0),
-    transmittance: Color(0.0, 0.0, 0.0),
-    ior:           Color(0.0, 0.0, 0.0),
-    //surface: Surface::new_simple(
-    surface: Surface::new_ts(
-      &Color(0.0, 0.0, 0.0),
-      &Color(0.78, 0.78, 0.78),
-      &0.0,
-      &1.0,
-      &1.0,
+    t => Err(Error::invalid("type", "point, parallelogram or sun", t)),
+  }
+}
+
+fn conv_material(m: &Yaml) -> Result<(String, Material), Error> {
+  let name = get_str(m, "name")?.to_string();
+  let typ = get_str(m, "type")?;
+  let surface = match typ {
+    "solid" => Surface::new_simple(
+      &get_color(m, "reflectance")?,
+      &get_color(m, "specularrefl")?,
+      &get_float(m, "diffuseness")?,
+      &get_float(m, "metalness")?,
+      &get_float(m, "smoothness")?,
     ),
-  };
-  */
-  let ball10 = Material {
-    emittance: Radiance(0.0, 0.0, 0.0),
-    transmittance: Color(0.0, 0.0, 0.0),
-    ior:           Color(1.5, 1.5, 1.5),
-    /*
-    surface: Surface::new_simple(
-      &Color(1.0, 1.0, 1.0),
-      &Color(0.05, 0.05, 0.05),
-      &0.0,
-      &0.0,
-      &0.0,
+    "ts" => Surface::new_ts(
+      &get_color(m, "albedo_diff")?,
+      &get_color(m, "albedo_spec")?,
+      &get_float(m, "scatterness")?,
+      &get_float(m, "metalness")?,
+      &get_float(m, "roughness")?,
     ),
-    */
-    surface: Surface::new_ts(
-      //&Color(0.6, 0.35, 0.1),
-      //&Color(0.05, 0.05, 0.05),
-      &Color(1.0, 1.0, 1.0),
-      &Color(0.05, 0.05, 0.05),
-      &0.0,
-      &0.0,
-      &0.8,
+    "disney" => Surface::DisneyBRDF(conv_disney(m)?),
+    "brady" => Surface::new_brady(
+      &get_color(m, "albedo_diff")?,
+      &get_color(m, "albedo_spec")?,
+      &get_float(m, "roughness_t")?,
+      &get_float(m, "roughness_b")?,
+      &get_tangent(m)?,
     ),
-    
-  };
-
-  let ball9 = Material {
-    emittance: Radiance(0.0, 0.0, 0.0),
-    transmittance: Color(0.0, 0.0, 0.0),
-    ior:           Color(0.0, 0.0, 0.0),
-    surface: Surface::new_simple(
-      &Color(0.0, 0.0, 0.0),
-      &Color(0.78, 0.78, 0.78),
-      &0.0,
-      &1.0,
-      &0.9,
+    "conductor" => Surface::new_conductor(
+      &get_conductor(m)?,
+      &get_float(m, "roughness")?,
     ),
+    "subsurface" => Surface::new_subsurface(&get_float(m, "roughness")?),
+    "volume" => Surface::Nothing,
+    t => return Err(Error::invalid("type", "solid, ts, disney, brady, conductor, subsurface or volume", t)),
   };
-  let ball8 = Material {
-    emittance: Radiance(0.0, 0.0, 0.0),
-    transmittance: Color(0.0, 0.0, 0.0),
-    ior:           Color(0.0, 0.0, 0.0),
-    surface: Surface::new_simple(
-      &Color(0.0, 0.0, 0.0),
-      &Color(0.78, 0.78, 0.78),
-      &0.0,
-      &1.0,
-      &0.8,
-    ),
+  let dispersion = get_dispersion(m)?;
+  let ior = match dispersion {
+    Some(d) if m["ior"].is_badvalue()                 => d.ior_color(),
+    None if m["ior"].is_badvalue() && typ == "volume" => Color(1.0, 1.0, 1.0),
+    _                                                 => get_color(m, "ior")?,
   };
-  let ball7 = Material {
-    emittance: Radiance(0.0, 0.0, 0.0),
-    transmittance: Color(0.0, 0.0, 0.0),
-    ior:           Color(0.0, 0.0, 0.0),
-    surface: Surface::new_simple(
-      &Color(0.0, 0.0, 0.0),
-      &Color(0.78, 0.78, 0.78),
-      &0.0,
-      &1.0,
-      &0.7,
-    ),
+  let scattering = get_scattering(m)?;
+  if typ == "subsurface" && scattering.is_none() {
+    return Err(Error::missing("scattering", "scattering or mean_free_path for subsurface"));
+  }
+  let mate = Material {
+    emittance:     get_radiance(m, "emittance")?,
+    transmittance: get_transmittance(m)?,
+    ior,
+    dispersion,
+    scattering,
+    surface,
+    priority:      get_priority(m)?,
   };
-  /*
-  let ball6 = Material {
-    emittance: Radiance(0.0, 0.0, 0.0),
-    transmittance: Color(0.0, 0.0, 0.0),
-    ior:           Color(0.0, 0.0, 0.0),
-    surface: Surface::new_simple(
-      &Color(0.0, 0.0, 0.0),
-      &Color(0.78, 0.78, 0.78),
-      &0.0,
-      &1.0,
-      &0.6,
-    ),
+  Ok((name, mate))
+}
+
+// base_color is required, the others default to those of Disney::new
+fn conv_disney(m: &Yaml) -> Result<Disney, Error> {
+  let mut d = Disney::new(&get_color(m, "base_color")?);
+  for (key, v) in [
+    ("subsurface", &mut d.subsurface),
+    ("metallic", &mut d.metallic),
+    ("specular", &mut d.specular),
+    ("specular_tint", &mut d.specular_tint),
+    ("roughness", &mut d.roughness),
+    ("anisotropic", &mut d.anisotropic),
+    ("sheen", &mut d.sheen),
+    ("sheen_tint", &mut d.sheen_tint),
+    ("clearcoat", &mut d.clearcoat),
+    ("clearcoat_gloss", &mut d.clearcoat_gloss),
+  ] {
+    if !m[key].is_badvalue() {
+      let x = get_float(m, key)?;
+      if !(0.0..=1.0).contains(&x) {
+        return Err(Error::invalid(key, "a number in [0, 1]", &x.to_string()));
+      }
+      *v = x;
+    }
+  }
+  Ok(d)
+}
+
+// optional, the shape gives one if not given
+fn get_tangent(m: &Yaml) -> Result<Option<Direction3>, Error> {
+  if m["tangent"].is_badvalue() {
+    return Ok(None)
+  }
+  let t = get_vector(m, "tangent")?.normalize()
+    .ok_or_else(|| value_error(&m["tangent"], "tangent", "a non-zero vector"))?;
+  Ok(Some(t))
+}
+
+// 'metal' of the table, or 'n' and 'k'
+fn get_conductor(m: &Yaml) -> Result<Conductor, Error> {
+  if m["metal"].is_badvalue() {
+    return Ok(Conductor::Rgb(get_color(m, "n")?, get_color(m, "k")?))
+  }
+  Metal::from_name(get_str(m, "metal")?).map(Conductor::Metal)
+    .ok_or_else(|| value_error(&m["metal"], "metal", "Au, Ag, Cu, Al or Cr"))
+}
+
+// 'transmittance', or the coefficient 'absorption' (per unit distance) in place of it
+fn get_transmittance(m: &Yaml) -> Result<Color, Error> {
+  match (m["transmittance"].is_badvalue(), m["absorption"].is_badvalue()) {
+    (_, true)      => get_color(m, "transmittance"),
+    (true, false)  => {
+      let a = get_color(m, "absorption")?;
+      let t = |s: Flt| 1.0 - (-s).exp();
+      Ok(Color(t(a.0), t(a.1), t(a.2)))
+    },
+    (false, false) => Err(Error::invalid("absorption", "either transmittance or absorption", "both")),
+  }
+}
+
+// optional 'scattering: [r, g, b]' (per unit distance), or 'mean_free_path' as the
+// inverse of it, with 'phase_g' in (-1, 1)
+fn get_scattering(m: &Yaml) -> Result<Option<Scattering>, Error> {
+  let sigma_s = match (m["scattering"].is_badvalue(), m["mean_free_path"].is_badvalue()) {
+    (true, true)   => return Ok(None),
+    (false, true)  => get_color(m, "scattering")?,
+    (true, false)  => {
+      let d = get_color(m, "mean_free_path")?;
+      if d.0 <= 0.0 || d.1 <= 0.0 || d.2 <= 0.0 {
+        return Err(value_error(&m["mean_free_path"], "mean_free_path", "positive numbers"));
+      }
+      Color(1.0 / d.0, 1.0 / d.1, 1.0 / d.2)
+    },
+    (false, false) => return Err(Error::invalid("mean_free_path", "either scattering or mean_free_path", "both")),
   };
-  */
-  let ball6 = Material {
-    emittance: Radiance(0.0, 0.0, 0.0),
-    transmittance: Color(0.0, 0.0, 0.0),
-    ior:           Color(0.0, 0.0, 0.0),
-    surface: Surface::new_ts(
-      &Color(0.0, 0.0, 0.0),
-      &Color(0.78, 0.78, 0.78),
-      &0.0,
-      &1.0,
-      &0.6,
-    ),
+  let g = if m["phase_g"].is_badvalue() { 0.0 } else { get_float(m, "phase_g")? };
+  if g <= -1.0 || g >= 1.0 {
+    return Err(Error::invalid("phase_g", "a number in (-1, 1)", &g.to_string()));
+  }
+  Ok(Some(Scattering {sigma_s, g}))
+}
+
+// optional 'cauchy: [A, B]' or 'sellmeier: [B1, B2, B3, C1, C2, C3]', for micrometers
+fn get_dispersion(m: &Yaml) -> Result<Option<Dispersion>, Error> {
+  match (m["cauchy"].is_badvalue(), m["sellmeier"].is_badvalue()) {
+    (true, true)   => Ok(None),
+    (false, true)  => {
+      let v = get_floats(m, "cauchy", 2)?;
+      Ok(Some(Dispersion::Cauchy(v[0], v[1])))
+    },
+    (true, false)  => {
+      let v = get_floats(m, "sellmeier", 6)?;
+      Ok(Some(Dispersion::Sellmeier([v[0], v[1], v[2]], [v[3], v[4], v[5]])))
+    },
+    (false, false) => Err(Error::invalid("sellmeier", "either cauchy or sellmeier", "both")),
+  }
+}
+
+// optional, 0 if not given. where transparent objects overlap (the wall of a
+// glass and the water in it), the one of the higher priority is in effect.
+fn get_priority(m: &Yaml) -> Result<i32, Error> {
+  match m["priority"] {
+    Yaml::BadValue   => Ok(0),
+    Yaml::Integer(i) => Ok(i as i32),
+    ref y            => Err(value_error(y, "priority", "an integer")),
+  }
+}
+
+// vertices are given either as a map or as a list of single entry maps.
+fn conv_vertices(vs: &Yaml) -> Result<HashMap<String, Position3>, Error> {
+  let mut verts: HashMap<String, Position3> = HashMap::new();
+  let mut add_vertices = |h: &Yaml| -> Result<(), Error> {
+    if let Some(h) = h.as_hash() {
+      for (k, v) in h {
+        if let Some(name) = k.as_str() {
+          verts.insert(name.to_string(), conv_vector(v, name)?);
+        }
+      }
+    }
+    Ok(())
   };
+  match vs {
+    Yaml::Array(vs) => vs.iter().try_for_each(&mut add_vertices)?,
+    Yaml::Hash(_)   => add_vertices(vs)?,
+    _               => (),
+  }
+  Ok(verts)
+}
 
-  let ball5 = Material {
-    emittance: Radiance(0.0, 0.0, 0.0),
-    transmittance: Color(0.0, 0.0, 0.0),
-    ior:           Color(0.0, 0.0, 0.0),
-    surface: Surface::new_ts(
-      &Color(0.0, 0.0, 0.0),
-      &Color(0.78, 0.78, 0.78),
-      &0.0,
-      &1.0,
-      &0.5,
-    ),
+// (name, mesh, material) of a mesh file
+type FileMesh = (String, Arc<Mesh>, Option<Material>);
+
+// what objects can share
+struct Shared<'a> {
+  dir: &'a Path,                              // of the scene file
+  named: HashMap<String, Vec<Object>>,        // objects by name
+  meshes: HashMap<String, Vec<FileMesh>>,     // mesh files loaded
+}
+
+// an object of a mesh file or an instance can make several objects
+fn conv_object(o: &Yaml, mates: &HashMap<String, Material>, verts: &HashMap<String, Position3>, shared: &mut Shared) -> Result<Vec<Object>, Error> {
+  let objs = match get_str(o, "type")? {
+    "obj" | "ply" | "stl" => conv_meshfile(o, mates, shared)?,
+    "instance" => {
+      let oname = get_str(o, "object")?;
+      let objs = shared.named.get(oname)
+        .ok_or_else(|| Error::invalid("object", "the name of an object defined before", oname))?;
+      let mate = get_material(o, mates)?;
+      objs.iter().map(|ob| Object::new(&ob.shape, &mate.unwrap_or(ob.material))).collect()
+    },
+    t => {
+      let mate = get_material(o, mates)?.ok_or_else(|| Error::missing("material", "a defined material"))?;
+      let shape = if t == "csg" { conv_csg(o, verts, shared)? } else { conv_shape(o, verts)? };
+      vec![Object::new(&shape, &mate)]
+    },
   };
-  /*
-  let ball5 = Material {
-    emittance: Radiance(0.0, 0.0, 0.0),
-    transmittance: Color(0.0, 0.0, 0.0),
-    ior:           Color(0.0, 0.0, 0.0),
-    //surface: Surface::new_simple(
-    surface: Surface::new_ts(
-      &Color(0.6, 0.35, 0.1),
-      &Color(0.05, 0.05, 0.05),
-      &1.0,
-      &0.0,
-      &0.9,
-    ),
+  let objs: Vec<Object> = match o["transform"] {
+    Yaml::BadValue => objs,
+    _ => {
+      let t = get_transform(&o["transform"])?;
+      objs.iter().map(|ob| Object::new(&Shape::new_instance(&ob.shape, &t), &ob.material)).collect()
+    },
   };
-  */
-  /*
-  let ball5 = Material {
-    emittance: Radiance(0.0, 0.0, 0.0),
-    transmittance: Color(0.0, 0.0, 0.0),
-    //ior:           Color(0.0, 0.0, 0.0),
-    ior:           Color(2.0, 2.0, 2.0),
-    /*
-    surface: Surface::new_simple(
-      &Color(0.6, 0.35, 0.1),
-      &Color(0.08, 0.08, 0.08),
-      &0.5,
-      &0.0,
-      &0.5,
-    ),
-    */
-    surface: Surface::new_ts(
-      //&Color(0.6, 0.35, 0.1),
-      //&Color(0.05, 0.05, 0.05),
-      &Color(1.0, 1.0, 1.0),
-      &Color(0.05, 0.05, 0.05),
-      &0.0,
-      &0.0,
-      &0.0,
-    ),
+  if let Some(name) = o["name"].as_str() {
+    shared.named.insert(name.to_string(), objs.clone());
+  }
+  Ok(objs)
+}
+
+fn conv_shape(o: &Yaml, verts: &HashMap<String, Position3>) -> Result<Shape, Error> {
+  match get_str(o, "type")? {
+    "plain" => {
+      let nvec = get_vector(o, "normal")?.normalize()
+        .ok_or_else(|| value_error(&o["normal"], "normal", "a non-zero vector"))?;
+      let pos = get_vector(o, "position")?;
+      Ok(Shape::Plain {nvec, dist: -nvec.dot(&pos)})
+    },
+    "sphere" => Ok(Shape::Sphere {
+      center: get_vector(o, "center")?,
+      radius: get_float(o, "radius")?,
+    }),
+    "polygon" => {
+      let (p1, p2, p3) = get_positions(o, verts)?;
+      Ok(Shape::new_polygon(&p1, &p2, &p3))
+    },
+    "parallelogram" => {
+      let (p1, p2, p3) = get_positions(o, verts)?;
+      Ok(Shape::new_parallelogram(&p1, &p2, &p3))
+    },
+    "mesh" => Ok(Shape::Mesh {mesh: Arc::new(get_mesh(o)?)}),
+    "box" => {
+      if o["min"].is_badvalue() {
+        let p0 = get_vector(o, "position")?;
+        let dir3 = get_vector(o, "dir3")?;
+        Shape::new_oriented_box(&p0, &get_vector(o, "dir1")?, &get_vector(o, "dir2")?, &dir3)
+          .ok_or_else(|| Error::invalid("dir3", "a direction not on the plane of the others", &format!("{}", dir3)))
+      } else {
+        let (min, max) = (get_vector(o, "min")?, get_vector(o, "max")?);
+        if (0..3).any(|i| min.v[i] > max.v[i]) {
+          return Err(Error::invalid("max", "a position not less than min", &format!("{}", max)));
+        }
+        Ok(Shape::Box {min, max})
+      }
+    },
+    "cylinder" => Shape::new_cylinder(&get_vector(o, "position")?, &get_vector(o, "axis")?, get_float(o, "radius")?)
+      .ok_or_else(|| value_error(&o["axis"], "axis", "a non-zero vector")),
+    "cone" => Shape::new_cone(&get_vector(o, "position")?, &get_vector(o, "axis")?, get_float(o, "radius")?)
+      .ok_or_else(|| value_error(&o["axis"], "axis", "a non-zero vector")),
+    "disk" => Ok(Shape::Disk {
+      center: get_vector(o, "center")?,
+      nvec: get_vector(o, "normal")?.normalize().ok_or_else(|| value_error(&o["normal"], "normal", "a non-zero vector"))?,
+      radius: get_float(o, "radius")?,
+    }),
+    "torus" => Ok(Shape::Torus {
+      center: get_vector(o, "center")?,
+      axis: get_vector(o, "axis")?.normalize().ok_or_else(|| value_error(&o["axis"], "axis", "a non-zero vector"))?,
+      major: get_float(o, "major_radius")?,
+      minor: get_float(o, "minor_radius")?,
+    }),
+    t => Err(Error::invalid("type", "plain, sphere, polygon, parallelogram, mesh, box, cylinder, cone, disk, torus, obj, ply, stl or instance", t)),
+  }
+}
+
+fn conv_csg(o: &Yaml, verts: &HashMap<String, Position3>, shared: &Shared) -> Result<Shape, Error> {
+  let op = match get_str(o, "op")? {
+    "union"        => CsgOp::Union,
+    "intersection" => CsgOp::Intersection,
+    "difference"   => CsgOp::Difference,
+    op             => return Err(Error::invalid("op", "union, intersection or difference", op)),
   };
-  */
-  let ball4 = Material {
-    emittance: Radiance(0.0, 0.0, 0.0),
-    transmittance: Color(0.0, 0.0, 0.0),
-    ior:           Color(0.0, 0.0, 0.0),
-    surface: Surface::new_simple(
-      &Color(0.0, 0.0, 0.0),
-      &Color(0.78, 0.78, 0.78),
-      &0.0,
-      &1.0,
-      &0.4,
-    ),
+  let left = conv_operand(&o["left"], "left", verts, shared)?;
+  let right = conv_operand(&o["right"], "right", verts, shared)?;
+  Shape::new_csg(op, &left, &right).ok_or_else(|| Error::invalid("op", "closed shapes", "an open shape"))
+}
+
+fn conv_operand(y: &Yaml, key: &str, verts: &HashMap<String, Position3>, shared: &Shared) -> Result<Shape, Error> {
+  let shape = match y {
+    Yaml::String(name) => match shared.named.get(name).map(|os| os.as_slice()) {
+      Some([ob]) => ob.shape.clone(),
+      _          => return Err(Error::invalid(key, "the name of a single object defined before", name)),
+    },
+    Yaml::Hash(_) => {
+      let s = match get_str(y, "type")? {
+        "csg" => conv_csg(y, verts, shared)?,
+        _     => conv_shape(y, verts)?,
+      };
+      match y["transform"] {
+        Yaml::BadValue => s,
+        _              => Shape::new_instance(&s, &get_transform(&y["transform"])?),
+      }
+    },
+    _ => return Err(value_error(y, key, "an object or the name of an object")),
   };
-  let ball3 = Material {
-    emittance: Radiance(0.0, 0.0, 0.0),
-    transmittance: Color(0.0, 0.0, 0.0),
-    ior:           Color(0.0, 0.0, 0.0),
-    surface: Surface::new_simple(
-      &Color(0.0, 0.0, 0.0),
-      &Color(0.78, 0.78, 0.78),
-      &0.0,
-      &1.0,
-      &0.3,
-    ),
+  if !shape.is_closed() {
+    return Err(Error::invalid(key, "a closed shape", &to_string(y)));
+  }
+  Ok(shape)
+}
+
+// None: not given
+fn get_material(o: &Yaml, mates: &HashMap<String, Material>) -> Result<Option<Material>, Error> {
+  match o["material"] {
+    Yaml::BadValue => Ok(None),
+    _ => {
+      let mname = get_str(o, "material")?;
+      let mate = mates.get(mname).ok_or_else(|| Error::invalid("material", "a defined material", mname))?;
+      Ok(Some(*mate))
+    },
+  }
+}
+
+// meshes from an OBJ, PLY or STL file. triangles with no area are removed.
+fn conv_meshfile(o: &Yaml, mates: &HashMap<String, Material>, shared: &mut Shared) -> Result<Vec<Object>, Error> {
+  let ftype = get_str(o, "type")?;
+  let file = shared.dir.join(get_str(o, "file")?).to_string_lossy().to_string();
+  if !shared.meshes.contains_key(&file) {
+    let groups: Vec<(String, Mesh, Option<Material>)> = match ftype {
+      "obj" => read_obj(&file)?.into_iter().map(|g| (g.name, g.mesh, g.material)).collect(),
+      "ply" => vec![(file.clone(), read_ply(&file)?, None)],
+      _     => vec![(file.clone(), read_stl(&file)?, None)],
+    };
+    let meshes = groups.into_iter()
+      .map(|(name, mut mesh, mate)| {
+        let n = mesh.remove_degenerate();
+        if n > 0 {
+          eprintln!("{}: removed {} degenerate triangles of '{}'", file, n, name);
+        }
+        (name, Arc::new(mesh), mate)
+      })
+      .collect();
+    shared.meshes.insert(file.clone(), meshes);
+  }
+  let mate = get_material(o, mates)?;
+  shared.meshes[&file].iter()
+    .map(|(name, mesh, mate0)| {
+      let m = mate.or(*mate0)
+        .ok_or_else(|| Error::missing("material", &format!("a material for '{}'", name)))?;
+      Ok(Object::new(&Shape::Mesh {mesh: mesh.clone()}, &m))
+    })
+    .collect()
+}
+
+// scale (a number or [x, y, z]), rotate [x, y, z] in degrees and translate [x, y, z], in this order
+fn get_transform(y: &Yaml) -> Result<Transform, Error> {
+  if y.as_hash().is_none() {
+    return Err(value_error(y, "transform", "a map of scale, rotate and translate"));
+  }
+  let mut t = Transform::IDENTITY;
+  match &y["scale"] {
+    Yaml::BadValue => (),
+    s => {
+      let sv = match conv_float(s) {
+        Some(f) => Vector3::new(f, f, f),
+        None    => conv_vector(s, "scale")?,
+      };
+      t = t.then(&Transform::scale(&sv).ok_or_else(|| value_error(s, "scale", "non-zero factors"))?);
+    },
+  }
+  if !y["rotate"].is_badvalue() {
+    let rv = get_vector(y, "rotate")?;
+    for axis in 0..3 {
+      t = t.then(&Transform::rotate(axis, rv.v[axis]));
+    }
+  }
+  if !y["translate"].is_badvalue() {
+    t = t.then(&Transform::translate(&get_vector(y, "translate")?));
+  }
+  Ok(t)
+}
+
+// positions and indices, with optional per vertex normals and uvs
+fn get_mesh(o: &Yaml) -> Result<Mesh, Error> {
+  let list = |key: &str| o[key].as_vec().ok_or_else(|| value_error(&o[key], key, "a list"));
+  let positions = list("positions")?.iter()
+    .map(|v| conv_vector(v, "positions"))
+    .collect::<Result<Vec<Position3>, Error>>()?;
+  let normals = match o["normals"] {
+    Yaml::BadValue => None,
+    _ => Some(list("normals")?.iter()
+      .map(|v| conv_vector(v, "normals")?.normalize().ok_or_else(|| value_error(v, "normals", "a non-zero vector")))
+      .collect::<Result<Vec<Direction3>, Error>>()?),
   };
-  let ball2 = Material {
-    emittance: Radiance(0.0, 0.0, 0.0),
-    transmittance: Color(0.0, 0.0, 0.0),
-    ior:           Color(0.0, 0.0, 0.0),
-    surface: Surface::new_simple(
-      &Color(0.0, 0.0, 0.0),
-      &Color(0.78, 0.78, 0.78),
-      &0.0,
-      &1.0,
-      &0.2,
-    ),
+  let uvs = match o["uvs"] {
+    Yaml::BadValue => None,
+    _ => Some(list("uvs")?.iter()
+      .map(|v| match v.as_vec().map(|es| es.iter().filter_map(conv_float).collect::<Vec<Flt>>()) {
+        Some(es) if es.len() == 2 => Ok((es[0], es[1])),
+        _                         => Err(value_error(v, "uvs", "a pair [u, v]")),
+      })
+      .collect::<Result<Vec<(Flt, Flt)>, Error>>()?),
   };
-  /*
-  let ball1 = Material {
-    emittance: Radiance(0.0, 0.0, 0.0),
-    transmittance: Color(0.0, 0.0, 0.0),
-    ior:           Color(0.0, 0.0, 0.0),
-    //surface: Surface::new_simple(
-    surface: Surface::new_ts(
-      &Color(0.0, 0.0, 0.0),
-      &Color(0.78, 0.78, 0.78),
-      &0.0,
-      &1.0,
-      &0.1,
-    ),
+  let indices = list("indices")?.iter()
+    .map(|v| match v.as_vec().map(|es| es.iter().filter_map(|e| e.as_i64()).collect::<Vec<i64>>()) {
+      Some(es) if es.len() == 3 && es.iter().all(|e| *e >= 0) => Ok([es[0] as usize, es[1] as usize, es[2] as usize]),
+      _ => Err(value_error(v, "indices", "three vertex indices [i, j, k]")),
+    })
+    .collect::<Result<Vec<[usize; 3]>, Error>>()?;
+  Mesh::new(positions, normals, uvs, indices).map_err(|e| Error::invalid("indices", "a valid mesh", &e))
+}
+
+// three positions must not be on a line
+fn get_positions(o: &Yaml, verts: &HashMap<String, Position3>) -> Result<(Position3, Position3, Position3), Error> {
+  let p1 = get_position(o, "pos1", verts)?;
+  let p2 = get_position(o, "pos2", verts)?;
+  let p3 = get_position(o, "pos3", verts)?;
+  normal_of(&(p2 - p1), &(p3 - p1), "pos3")?;
+  Ok((p1, p2, p3))
+}
+
+// a position is a vertex name or a vector
+fn get_position(o: &Yaml, key: &str, verts: &HashMap<String, Position3>) -> Result<Position3, Error> {
+  match &o[key] {
+    Yaml::String(name) => verts.get(name).copied()
+      .ok_or_else(|| Error::invalid(key, "a defined vertex", name)),
+    v => conv_vector(v, key),
+  }
+}
+
+fn normal_of(d1: &Direction3, d2: &Direction3, key: &str) -> Result<Direction3, Error> {
+  d1.cross(d2).normalize()
+    .ok_or_else(|| Error::invalid(key, "a direction not parallel to the others", &format!("{}", d2)))
+}
+
+fn get_str<'a>(y: &'a Yaml, key: &str) -> Result<&'a str, Error> {
+  y[key].as_str().ok_or_else(|| value_error(&y[key], key, "a string"))
+}
+
+fn get_float(y: &Yaml, key: &str) -> Result<Flt, Error> {
+  conv_float(&y[key]).ok_or_else(|| value_error(&y[key], key, "a number"))
+}
+
+fn get_vector(y: &Yaml, key: &str) -> Result<Vector3, Error> {
+  conv_vector(&y[key], key)
+}
+
+fn get_color(y: &Yaml, key: &str) -> Result<Color, Error> {
+  let v = conv_vector(&y[key], key)?;
+  Ok(Color(v.v[0], v.v[1], v.v[2]))
+}
+
+fn get_floats(y: &Yaml, key: &str, n: usize) -> Result<Vec<Flt>, Error> {
+  let es: Vec<Flt> = match y[key].as_vec() {
+    Some(es) => es.iter().filter_map(conv_float).collect(),
+    None     => vec![],
   };
-  */
-  let ball1 = Material {
-    emittance: Radiance(0.0, 0.0, 0.0),
-    transmittance: Color(0.0, 0.0, 0.0),
-    ior:           Color(1.5, 1.5, 1.5),
-    //surface: Surface::new_simple(
-    surface: Surface::new_ts(
-      &Color(0.6, 0.35, 0.1),
-      &Color(0.05, 0.05, 0.05),
-      &1.0,
-      &0.0,
-      &0.0,
-    ),
+  match y[key].as_vec() {
+    Some(ys) if ys.len() == n && es.len() == n => Ok(es),
+    _ => Err(value_error(&y[key], key, &format!("a list of {} numbers", n))),
+  }
+}
+
+fn get_radiance(y: &Yaml, key: &str) -> Result<Radiance, Error> {
+  let v = conv_vector(&y[key], key)?;
+  Ok(Radiance(v.v[0], v.v[1], v.v[2]))
+}
+
+fn conv_vector(y: &Yaml, key: &str) -> Result<Vector3, Error> {
+  let es: Vec<Flt> = match y.as_vec() {
+    Some(es) => es.iter().filter_map(conv_float).collect(),
+    None     => vec![],
   };
+  match y.as_vec() {
+    Some(ys) if ys.len() == 3 && es.len() == 3 => Ok(Vector3::new(es[0], es[1], es[2])),
+    _ => Err(value_error(y, key, "a vector [x, y, z]")),
+  }
+}
 
+fn conv_float(y: &Yaml) -> Option<Flt> {
+  match y {
+    Yaml::Real(_)    => y.as_f64(),
+    Yaml::Integer(i) => Some(*i as Flt),
+    _                => None,
+  }
+}
 
-  let mparal = Material {
-    //emittance: Radiance(0.7958, 0.7958, 0.7958),
-    emittance: Radiance(0.15, 0.15, 0.15),
-    transmittance: Color(0.0, 0.0, 0.0),
-    ior:           Color(0.0, 0.0, 0.0),
-    surface: Surface::new_simple(
-      &Color(0.0, 0.0, 0.0),
-      &Color(0.8, 0.8, 0.8),
-      &0.0,
-      &0.0,
-      &0.0,
-    ),
+fn value_error(y: &Yaml, key: &str, expected: &str) -> Error {
+  if y.is_badvalue() {
+    Error::missing(key, expected)
+  } else {
+    Error::invalid(key, expected, &to_string(y))
+  }
+}
+
+fn to_string(y: &Yaml) -> String {
+  match y {
+    Yaml::Real(s)    => s.to_string(),
+    Yaml::Integer(i) => i.to_string(),
+    Yaml::String(s)  => s.to_string(),
+    Yaml::Boolean(b) => b.to_string(),
+    Yaml::Array(ys)  => format!("[{}]", ys.iter().map(to_string).collect::<Vec<String>>().join(", ")),
+    Yaml::Hash(_)    => "a map".to_string(),
+    Yaml::Null       => "null".to_string(),
+    _                => "nothing".to_string(),
+  }
+}
+
+// find the line of an error in the source text: the line of 'key' in the
+// index-th item of 'section', or the first line of the item.
+fn locate(contents: &str, section: &str, index: Option<usize>, e: Error) -> Error {
+  if e.file.is_some() {
+    return e;   // an error in another file
+  }
+  let line = find_line(contents, section, index, e.key.as_deref());
+  e.at_line(line)
+}
+
+fn find_line(contents: &str, section: &str, index: Option<usize>, key: Option<&str>) -> Option<usize> {
+  let lines: Vec<(usize, &str)> = contents.lines().enumerate()
+    .map(|(i, l)| (i + 1, l.split('#').next().unwrap_or("")))
+    .filter(|(_, l)| !l.trim().is_empty())
+    .collect();
+  let is_top = |l: &str| !l.starts_with(' ') && !l.starts_with('\t');
+  let head = lines.iter().position(|(_, l)| is_top(l) && l.split(':').next().unwrap_or("").trim() == section)?;
+  let body: Vec<(usize, &str)> = lines[head + 1..].iter()
+    .take_while(|(_, l)| !is_top(l))
+    .cloned()
+    .collect();
+  let item: Vec<(usize, &str)> = match index {
+    Some(i) => {
+      let starts: Vec<usize> = body.iter().enumerate()
+        .filter(|(_, (_, l))| l.trim_start().starts_with('-'))
+        .map(|(j, _)| j)
+        .collect();
+      let s = *starts.get(i)?;
+      let e = *starts.get(i + 1).unwrap_or(&body.len());
+      body[s..e].to_vec()
+    },
+    None => body,
   };
+  let matches_key = |l: &str, k: &str| {
+    let l2 = l.trim_start().trim_start_matches('-').trim_start();
+    l2.starts_with(k) && l2[k.len()..].trim_start().starts_with(':')
+  };
+  match key {
+    Some(k) => item.iter().find(|(_, l)| matches_key(l, k)).or_else(|| item.first()),
+    None    => item.first(),
+  }.map(|(n, _)| *n)
+}
 
-  // objects
-  let flooring = Object {
-    shape: Shape::Plain {nvec: Vector3::EY, dist: 0.0},
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  const SCENE: &str = "
+light:
+  - type     : parallelogram
+    color    : [ 1.0, 1.0, 1.0 ]
+    flux     : 5.0
+    position : [ -0.67, 3.99, 2.33 ]
+    dir1     : [ 1.33, 0.0, 0.0 ]
+    dir2     : [ 0.0, 0.0, 1.33 ]
+material:
+  - type         : solid
+    name         : mwall
+    emittance    : [ 0.0, 0.0, 0.0 ]
+    reflectance  : [ 0.5, 0.5, 0.5 ]
+    transmittance: [ 0.0, 0.0, 0.0 ]
+    specularrefl : [ 0.8, 0.8, 0.8 ]
+    ior          : [ 0.0, 0.0, 0.0 ]
+    diffuseness  : 1
+    metalness    : 0.0
+    smoothness   : 0.0
+vertex:
+  - p1: [ 0.0, 0.0, 0.0 ]
+  - p2: [ 1.0, 0.0, 0.0 ]
+object:
+  - type    : plain
+    name    : ceiling
+    normal  : [ 0.0, -1.0, 0.0 ]
+    position: [ 0.0, 4.0, 0.0 ]
     material: mwall
-  };
-  let ceiling  = Object {
-    shape: Shape::Plain {nvec: -Vector3::EY, dist: 4.0},
+  - type    : polygon
+    name    : poly
+    pos1    : p1
+    pos2    : p2
+    pos3    : [ 0.0, 0.0, 1.0 ]
     material: mwall
-  };
-  let rsidewall = Object {
-    shape: Shape::Plain {nvec: -Vector3::EX, dist: 2.0},
-    material: mwallb
-  };
-  let lsidewall = Object {
-    shape: Shape::Plain {nvec: Vector3::EX, dist: 2.0},
-    material: mwallr
-  };
-  let backwall = Object {
-    shape: Shape::Plain {nvec: Vector3::EZ, dist: 6.0},
+";
+
+  // SCENE with the material added and the objects appended
+  fn scene_with(mate: &str, items: &str) -> String {
+    SCENE.replace("vertex:\n", &(mate.to_string() + "vertex:\n")) + items
+  }
+
+  // a ball of the material, to be appended to SCENE
+  fn ball(material: &str) -> String {
+    format!("  - type    : sphere
+    center  : [ 0.0, 1.0, 0.0 ]
+    radius  : 1.0
+    material: {}
+", material)
+  }
+
+  #[test]
+  fn test_parse_scene() {
+    let (lgts, objs) = parse_scene(SCENE).unwrap();
+    assert_eq!(lgts.len(), 1);
+    assert_eq!(lgts[0].flux(), 5.0);
+    match lgts[0] {
+      Light::ParallelogramLight {nvec, ..} => assert_eq!(nvec, -Vector3::EY),
+      _ => panic!("not a parallelogram light"),
+    }
+    assert_eq!(objs.len(), 2);
+    assert_eq!(objs[0].shape, Shape::Plain {nvec: -Vector3::EY, dist: 4.0});
+    assert_eq!(objs[1].shape, Shape::new_polygon(&Vector3::O, &Vector3::EX, &Vector3::EZ));
+    assert_eq!(objs[1].material.surface.albedo_diff(&Wavelength::RED), 0.5);
+  }
+
+  #[test]
+  fn test_parse_mesh() {
+    let mesh = "  - type     : mesh
+    positions: [ [ 0.0, 0.0, 0.0 ], [ 1.0, 0.0, 0.0 ], [ 0.0, 0.0, 1.0 ] ]
+    uvs      : [ [ 0.0, 0.0 ], [ 1.0, 0.0 ], [ 0.0, 1.0 ] ]
+    indices  : [ [ 0, 2, 1 ] ]
+    material : mwall
+";
+    let (_, objs) = parse_scene(&(SCENE.to_string() + mesh)).unwrap();
+    match &objs[2].shape {
+      Shape::Mesh {mesh} => {
+        assert_eq!(mesh.ntriangle(), 1);
+        assert_eq!(mesh.normals, None);
+        assert_eq!(mesh.normal(0, 0.2, 0.2), Some(Vector3::EY));
+      },
+      _ => panic!("not a mesh"),
+    }
+    let e = parse_scene(&(SCENE.to_string() + &mesh.replace("[ 0, 2, 1 ]", "[ 0, 2, 3 ]"))).unwrap_err();
+    assert_eq!(format!("{}", e), "38: 'indices': expected a valid mesh, found 'index 3 out of 3 positions'");
+  }
+
+  #[test]
+  fn test_parse_meshfile() {
+    let file = std::env::temp_dir().join("ppmpa-test-degenerate.ply");
+    fs::write(&file, "ply\nformat ascii 1.0\nelement vertex 3\nproperty float x\nproperty float y\nproperty float z\n\
+      element face 2\nproperty list uchar int vertex_indices\nend_header\n0 0 0\n1 0 0\n0 0 1\n3 0 2 1\n3 0 1 1\n").unwrap();
+    let item = format!("  - type    : ply\n    file    : {}\n    material: mwall\n", file.display());
+    let (_, objs) = parse_scene(&(SCENE.to_string() + &item)).unwrap();
+    match &objs[2].shape {
+      Shape::Mesh {mesh} => assert_eq!(mesh.indices, vec![[0, 2, 1]]),
+      _ => panic!("not a mesh"),
+    }
+    let e = parse_scene(&(SCENE.to_string() + &item.replace("    material: mwall\n", ""))).unwrap_err();
+    assert_eq!(e.key, Some("material".to_string()));
+    fs::remove_file(&file).unwrap();
+  }
+
+  #[test]
+  fn test_parse_instance() {
+    let items = "  - type     : sphere
+    name     : ball
+    center   : [ 0.0, 0.0, 0.0 ]
+    radius   : 0.5
+    material : mwall
+    transform: { scale: [ 1.0, 2.0, 1.0 ] }
+  - type     : instance
+    object   : ball
+    transform:
+      translate: [ 0.0, 1.0, 3.0 ]
+";
+    let (_, objs) = parse_scene(&(SCENE.to_string() + items)).unwrap();
+    assert_eq!(objs.len(), 4);
+    let r = Ray::new(&Vector3::new(0.0, 5.0, 3.0), &(-Vector3::EY));
+    assert_eq!(objs[3].shape.distance(&r), vec![3.0, 5.0]);
+    match (&objs[2].shape, &objs[3].shape) {
+      (Shape::Instance {shape: s2, ..}, Shape::Instance {shape: s3, ..}) => assert!(Arc::ptr_eq(s2, s3)),
+      _ => panic!("not instances"),
+    }
+    let e = parse_scene(&(SCENE.to_string() + &items.replace("object   : ball", "object   : box"))).unwrap_err();
+    assert_eq!(format!("{}", e), "42: 'object': expected the name of an object defined before, found 'box'");
+  }
+
+  #[test]
+  fn test_parse_solids() {
+    let items = "  - type    : box
+    position: [ 0.0, 0.0, 0.0 ]
+    dir1    : [ 1.0, 0.0, 0.0 ]
+    dir2    : [ 0.0, 1.0, 0.0 ]
+    dir3    : [ 0.0, 0.0, 1.0 ]
     material: mwall
-  };
-  let frontwall = Object {
-    shape: Shape::Plain {nvec: -Vector3::EZ, dist: 5.0},
+  - type    : cylinder
+    position: [ 0.0, 0.0, 0.0 ]
+    axis    : [ 0.0, 2.0, 0.0 ]
+    radius  : 0.5
     material: mwall
-  };
-  /*
-  let ball_glass = Object {
-    shape: Shape::Sphere {center: Position3::new_pos(1.0, 0.7, 2.6), radius: 0.7},
-    material: glass
-  };
-  let ball_mirror = Object {
-    shape: Shape::Sphere {center: Position3::new_pos(-0.9, 0.7, 3.8), radius: 0.7},
-    material: silver
-  };
-  */
-  let ball_1 = Object {
-    shape: Shape::Sphere {center: Position3::new_pos(-1.6, 1.5, 3.0), radius: 0.4},
-    material: ball1
-  };
-  let ball_2 = Object {
-    shape: Shape::Sphere {center: Position3::new_pos(-0.8, 1.5, 3.0), radius: 0.4},
-    material: ball2
-  };
-  let ball_3 = Object {
-    shape: Shape::Sphere {center: Position3::new_pos(0.0, 1.5, 3.0), radius: 0.4},
-    material: ball3
-  };
-  let ball_4 = Object {
-    shape: Shape::Sphere {center: Position3::new_pos(0.8, 1.5, 3.0), radius: 0.4},
-    material: ball4
-  };
-  let ball_5 = Object {
-    shape: Shape::Sphere {center: Position3::new_pos(1.6, 1.5, 3.0), radius: 0.4},
-    material: ball5
-  };
-  let ball_6 = Object {
-    shape: Shape::Sphere {center: Position3::new_pos(-1.6, 0.5, 2.5), radius: 0.4},
-    material: ball6
-  };
-  let ball_7 = Object {
-    shape: Shape::Sphere {center: Position3::new_pos(-0.8, 0.5, 2.5), radius: 0.4},
-    material: ball7
-  };
-  let ball_8 = Object {
-    shape: Shape::Sphere {center: Position3::new_pos(0.0, 0.5, 2.5), radius: 0.4},
-    material: ball8
-  };
-  let ball_9 = Object {
-    shape: Shape::Sphere {center: Position3::new_pos(0.8, 0.5, 2.5), radius: 0.4},
-    material: ball9
-  };
-  let ball_10 = Object {
-    shape: Shape::Sphere {center: Position3::new_pos(1.6, 0.5, 2.5), radius: 0.4},
-    material: ball10
-  };
-  let ceiling_light = Object {
-    shape: Shape::Parallelogram {position: Vector3::new(-0.67, 3.99, 2.33),
-                                 nvec: -Vector3::EY,
-                                 dir1: Vector3::new(0.67, 3.99, 2.33) - Vector3::new(-0.67, 3.99, 2.33),
-                                 dir2: Vector3::new(-0.67, 3.99, 3.67) - Vector3::new(-0.67, 3.99, 2.33),
-    },
-    material: mparal
-  };
-  
-  (vec![l1],
-   vec![flooring, ceiling, rsidewall, lsidewall, backwall, frontwall,
-         //ball_glass, ball_mirror,
-         ball_1, ball_2, ball_3, ball_4, ball_5, ball_6, ball_7, ball_8, ball_9, ball_10,
-         ceiling_light])
+  - type        : torus
+    center      : [ 0.0, 1.0, 0.0 ]
+    axis        : [ 0.0, 1.0, 0.0 ]
+    major_radius: 1.0
+    minor_radius: 0.2
+    material    : mwall
+";
+    let (_, objs) = parse_scene(&(SCENE.to_string() + items)).unwrap();
+    assert_eq!(objs.len(), 5);
+    assert_eq!(objs[3].shape, Shape::Cylinder {position: Vector3::O, axis: Vector3::EY, height: 2.0, radius: 0.5});
+    assert_eq!(objs[4].shape, Shape::Torus {center: Vector3::EY, axis: Vector3::EY, major: 1.0, minor: 0.2});
+    let e = parse_scene(&(SCENE.to_string() + &items.replace("[ 0.0, 0.0, 1.0 ]", "[ 1.0, 1.0, 0.0 ]"))).unwrap_err();
+    assert_eq!(e.key, Some("dir3".to_string()));
+    assert_eq!(e.line, Some(39));
+  }
+
+  #[test]
+  fn test_parse_disney() {
+    let mate = "  - type         : disney
+    name         : mgold
+    emittance    : [ 0.0, 0.0, 0.0 ]
+    transmittance: [ 0.0, 0.0, 0.0 ]
+    ior          : [ 0.0, 0.0, 0.0 ]
+    base_color   : [ 1.0, 0.8, 0.3 ]
+    metallic     : 1
+    roughness    : 0.3
+    clearcoat    : 0.5
+";
+    let scene = scene_with(mate, &ball("mgold"));
+    let (_, objs) = parse_scene(&scene).unwrap();
+    let mut d = Disney::new(&Color(1.0, 0.8, 0.3));
+    d.metallic = 1.0;
+    d.roughness = 0.3;
+    d.clearcoat = 0.5;
+    assert_eq!(objs[2].material.surface, Surface::DisneyBRDF(d));
+    let e = parse_scene(&scene.replace("roughness    : 0.3", "roughness    : 1.5")).unwrap_err();
+    assert_eq!(format!("{}", e), "27: 'roughness': expected a number in [0, 1], found '1.5'");
+  }
+
+  #[test]
+  fn test_parse_brady() {
+    let mate = "  - type         : brady
+    name         : brushed
+    emittance    : [ 0.0, 0.0, 0.0 ]
+    transmittance: [ 0.0, 0.0, 0.0 ]
+    ior          : [ 0.0, 0.0, 0.0 ]
+    albedo_diff  : [ 0.1, 0.1, 0.1 ]
+    albedo_spec  : [ 0.9, 0.9, 0.9 ]
+    roughness_t  : 0.2
+    roughness_b  : 0.6
+    tangent      : [ 2.0, 0.0, 0.0 ]
+";
+    let scene = scene_with(mate, &ball("brushed"));
+    let (_, objs) = parse_scene(&scene).unwrap();
+    let sf = Surface::new_brady(&Color(0.1, 0.1, 0.1), &Color(0.9, 0.9, 0.9), &0.2, &0.6, &Some(Vector3::EX));
+    assert_eq!(objs[2].material.surface, sf);
+    let (_, objs) = parse_scene(&scene.replace("    tangent      : [ 2.0, 0.0, 0.0 ]\n", "")).unwrap();
+    assert!(matches!(objs[2].material.surface, Surface::Brady {tangent: None, ..}));
+    let e = parse_scene(&scene.replace("[ 2.0, 0.0, 0.0 ]", "[ 0.0, 0.0, 0.0 ]")).unwrap_err();
+    assert_eq!(format!("{}", e), "29: 'tangent': expected a non-zero vector, found '[0.0, 0.0, 0.0]'");
+  }
+
+  #[test]
+  fn test_parse_conductor() {
+    let mate = "  - type         : conductor
+    name         : gold
+    emittance    : [ 0.0, 0.0, 0.0 ]
+    transmittance: [ 0.0, 0.0, 0.0 ]
+    ior          : [ 0.0, 0.0, 0.0 ]
+    metal        : gold
+    roughness    : 0.2
+";
+    let scene = scene_with(mate, &ball("gold"));
+    let (_, objs) = parse_scene(&scene).unwrap();
+    assert_eq!(objs[2].material.surface, Surface::new_conductor(&Conductor::Metal(Metal::Au), &0.2));
+    let nk = "    n            : [ 0.2, 0.4, 1.4 ]\n    k            : [ 3.4, 2.3, 1.8 ]\n";
+    let (_, objs) = parse_scene(&scene.replace("    metal        : gold\n", nk)).unwrap();
+    let c = Conductor::Rgb(Color(0.2, 0.4, 1.4), Color(3.4, 2.3, 1.8));
+    assert_eq!(objs[2].material.surface, Surface::new_conductor(&c, &0.2));
+    let e = parse_scene(&scene.replace("metal        : gold", "metal        : iron")).unwrap_err();
+    assert_eq!(format!("{}", e), "25: 'metal': expected Au, Ag, Cu, Al or Cr, found 'iron'");
+  }
+
+  #[test]
+  fn test_parse_volume() {
+    let mate = "  - type         : volume
+    name         : fog
+    emittance    : [ 0.0, 0.0, 0.0 ]
+    absorption   : [ 0.0, 0.0, 0.5 ]
+    scattering   : [ 0.2, 0.2, 0.2 ]
+    phase_g      : 0.4
+";
+    let items = "  - type    : box
+    min     : [ -1.0, 0.0, -1.0 ]
+    max     : [ 1.0, 2.0, 1.0 ]
+    material: fog
+";
+    let scene = scene_with(mate, items);
+    let (_, objs) = parse_scene(&scene).unwrap();
+    let m = objs[2].material;
+    assert_eq!(m.surface, Surface::Nothing);
+    assert_eq!(m.ior, Color(1.0, 1.0, 1.0));
+    assert_eq!(m.scattering, Some(Scattering {sigma_s: Color(0.2, 0.2, 0.2), g: 0.4}));
+    assert_eq!(m.transmittance.0, 0.0);
+    assert!((m.transmittance.2 - (1.0 - (-0.5 as Flt).exp())).abs() < 1.0e-12);
+    let (_, objs) = parse_scene(&scene.replace("    phase_g      : 0.4\n", "")).unwrap();
+    assert_eq!(objs[2].material.scattering.unwrap().g, 0.0);
+    let e = parse_scene(&scene.replace("phase_g      : 0.4", "phase_g      : 1.0")).unwrap_err();
+    assert_eq!(format!("{}", e), "25: 'phase_g': expected a number in (-1, 1), found '1'");
+    let e = parse_scene(&scene.replace("absorption   :", "transmittance: [ 0.0, 0.0, 0.0 ]\n    absorption   :")).unwrap_err();
+    assert_eq!(format!("{}", e), "24: 'absorption': expected either transmittance or absorption, found 'both'");
+  }
+
+  #[test]
+  fn test_parse_subsurface() {
+    let mate = "  - type         : subsurface
+    name         : marble
+    emittance    : [ 0.0, 0.0, 0.0 ]
+    mean_free_path: [ 0.02, 0.025, 0.04 ]
+    absorption   : [ 0.1, 0.2, 0.4 ]
+    ior          : [ 1.6, 1.6, 1.6 ]
+    roughness    : 0.3
+";
+    let scene = scene_with(mate, &ball("marble"));
+    let (_, objs) = parse_scene(&scene).unwrap();
+    let m = objs[2].material;
+    assert_eq!(m.surface, Surface::new_subsurface(&0.3));
+    assert_eq!(m.scattering, Some(Scattering {sigma_s: Color(50.0, 40.0, 25.0), g: 0.0}));
+    let (_, objs) = parse_scene(&scene.replace("mean_free_path: [ 0.02, 0.025, 0.04 ]", "scattering   : [ 30.0, 30.0, 30.0 ]")).unwrap();
+    assert_eq!(objs[2].material.scattering.unwrap().sigma_s, Color(30.0, 30.0, 30.0));
+    let e = parse_scene(&scene.replace("    mean_free_path: [ 0.02, 0.025, 0.04 ]\n", "")).unwrap_err();
+    assert_eq!(format!("{}", e), "20: 'scattering': missing, expected scattering or mean_free_path for subsurface");
+    let e = parse_scene(&scene.replace("0.025", "0.0")).unwrap_err();
+    assert_eq!(format!("{}", e), "23: 'mean_free_path': expected positive numbers, found '[0.02, 0.0, 0.04]'");
+  }
+
+  #[test]
+  fn test_parse_dispersion() {
+    let mate = "  - type         : solid
+    name         : bk7
+    emittance    : [ 0.0, 0.0, 0.0 ]
+    reflectance  : [ 0.0, 0.0, 0.0 ]
+    transmittance: [ 0.0, 0.0, 0.0 ]
+    specularrefl : [ 0.08, 0.08, 0.08 ]
+    sellmeier    : [ 1.03961212, 0.231792344, 1.01046945, 0.00600069867, 0.0200179144, 103.560653 ]
+    diffuseness  : 0.0
+    metalness    : 0.0
+    smoothness   : 0.0
+";
+    let scene = scene_with(mate, &ball("bk7"));
+    let (_, objs) = parse_scene(&scene).unwrap();
+    let m = objs[2].material;
+    assert!(matches!(m.dispersion, Some(Dispersion::Sellmeier(_, _))));
+    assert_eq!(m.ior.1, m.ior_wavelength(&Wavelength::GREEN));
+    assert!((m.ior_wavelength(&Wavelength(587.6)) - 1.5168).abs() < 0.0001);
+    let (_, objs) = parse_scene(&scene.replace("sellmeier    : [ 1.03961212, 0.231792344, 1.01046945, 0.00600069867, 0.0200179144, 103.560653 ]", "cauchy       : [ 1.5046, 0.0042 ]\n    ior          : [ 1.5, 1.5, 1.5 ]")).unwrap();
+    assert_eq!(objs[2].material.dispersion, Some(Dispersion::Cauchy(1.5046, 0.0042)));
+    assert_eq!(objs[2].material.ior, Color(1.5, 1.5, 1.5));
+    let e = parse_scene(&scene.replace("sellmeier    :", "cauchy       :")).unwrap_err();
+    assert_eq!(format!("{}", e), "26: 'cauchy': expected a list of 2 numbers, found '[1.03961212, 0.231792344, 1.01046945, 0.00600069867, 0.0200179144, 103.560653]'");
+  }
+
+  #[test]
+  fn test_parse_csg() {
+    let items = "  - type    : sphere
+    name    : ball
+    center  : [ 0.0, 0.0, 0.0 ]
+    radius  : 1.0
+    material: mwall
+  - type    : csg
+    op      : difference
+    left    : ball
+    right   :
+      type     : csg
+      op       : union
+      left     : { type: sphere, center: [ -1.5, 0.0, 0.0 ], radius: 1.0 }
+      right    : { type: box, min: [ 0.5, -2.0, -2.0 ], max: [ 2.0, 2.0, 2.0 ] }
+      transform: { translate: [ 0.0, 0.0, 0.0 ] }
+    material: mwall
+";
+    let (_, objs) = parse_scene(&(SCENE.to_string() + items)).unwrap();
+    let r = Ray::new(&Vector3::new(-5.0, 0.0, 0.0), &Vector3::EX);
+    assert_eq!(objs[3].shape.distance(&r), vec![4.5, 5.5]);
+    let e = parse_scene(&(SCENE.to_string() + &items.replace("{ type: sphere,", "{ type: disk, normal: [ 1.0, 0.0, 0.0 ],"))).unwrap_err();
+    assert_eq!(e.key, Some("left".to_string()));
+    let e = parse_scene(&(SCENE.to_string() + &items.replace("op      : difference", "op      : xor"))).unwrap_err();
+    assert_eq!(format!("{}", e), "41: 'op': expected union, intersection or difference, found 'xor'");
+  }
+
+  #[test]
+  fn test_example_scenes() {
+    let (lgts, objs) = read_scene(concat!(env!("CARGO_MANIFEST_DIR"), "/example/ex-11.7.scene")).unwrap();
+    assert!(!lgts.is_empty());
+    assert!(!objs.is_empty());
+    let (_, objs) = read_scene(concat!(env!("CARGO_MANIFEST_DIR"), "/example/ex-obj.scene")).unwrap();
+    assert_eq!(objs.len(), 8);
+    match &objs[6].shape {
+      Shape::Instance {shape, transform} => {
+        let mesh = match &**shape {
+          Shape::Mesh {mesh} => mesh,
+          _                  => panic!("not a mesh"),
+        };
+        assert_eq!(mesh.ntriangle(), 12);
+        assert!(transform.position(&mesh.positions[6]).near(&Vector3::new(0.3 + 0.3 * (3.0_f64.sqrt() + 1.0), 1.2, 3.0 + 0.3 * (3.0_f64.sqrt() - 1.0))));
+      },
+      _ => panic!("not an instance"),
+    }
+    assert_eq!(objs[6].material.surface.albedo_diff(&Wavelength::RED), 0.8);
+    let (_, objs) = read_scene(concat!(env!("CARGO_MANIFEST_DIR"), "/example/ex-solids.scene")).unwrap();
+    assert_eq!(objs.len(), 11);
+    let (_, objs) = read_scene(concat!(env!("CARGO_MANIFEST_DIR"), "/example/ex-csg.scene")).unwrap();
+    assert!(objs.iter().all(|o| o.shape.is_closed()));
+    let (_, objs) = read_scene(concat!(env!("CARGO_MANIFEST_DIR"), "/example/ex-water.scene")).unwrap();
+    assert_eq!(objs.iter().map(|o| o.material.priority).collect::<Vec<i32>>(), vec![0, 0, 0, 0, 0, 0, 1, 0]);
+    let (_, objs) = read_scene(concat!(env!("CARGO_MANIFEST_DIR"), "/example/ex-disney.scene")).unwrap();
+    assert_eq!(objs.iter().filter(|o| matches!(o.material.surface, Surface::DisneyBRDF(_))).count(), 4);
+    let (_, objs) = read_scene(concat!(env!("CARGO_MANIFEST_DIR"), "/example/ex-brady.scene")).unwrap();
+    assert_eq!(objs.iter().filter(|o| matches!(o.material.surface, Surface::Brady {..})).count(), 3);
+    let (_, objs) = read_scene(concat!(env!("CARGO_MANIFEST_DIR"), "/example/ex-metals.scene")).unwrap();
+    assert_eq!(objs.iter().filter(|o| matches!(o.material.surface, Surface::Conductor {nk: Conductor::Metal(_), ..})).count(), 5);
+    let (_, objs) = read_scene(concat!(env!("CARGO_MANIFEST_DIR"), "/example/ex-sunshaft.scene")).unwrap();
+    assert_eq!(objs.iter().filter(|o| o.material.scattering.is_some()).count(), 1);
+    let (_, objs) = read_scene(concat!(env!("CARGO_MANIFEST_DIR"), "/example/ex-prism.scene")).unwrap();
+    assert_eq!(objs.iter().filter(|o| matches!(o.material.dispersion, Some(Dispersion::Sellmeier(_, _)))).count(), 2);
+    for f in ["gray-marble-ball", "white-stone-ball"].iter() {
+      let (_, objs) = read_scene(&format!("{}/example/{}.scene", env!("CARGO_MANIFEST_DIR"), f)).unwrap();
+      assert_eq!(objs.iter().filter(|o| matches!(o.material.surface, Surface::Subsurface {..})).count(), 1);
+    }
+  }
+
+  #[test]
+  fn test_scene_error() {
+    let e1 = parse_scene(&SCENE.replace("flux     : 5.0", "flux     : five")).unwrap_err();
+    assert_eq!(e1.key, Some("flux".to_string()));
+    assert_eq!(e1.line, Some(5));
+    assert_eq!(format!("{}", e1), "5: 'flux': expected a number, found 'five'");
+    let e2 = parse_scene(&SCENE.replace("pos1    : p1", "pos1    : p9")).unwrap_err();
+    assert_eq!(format!("{}", e2), "31: 'pos1': expected a defined vertex, found 'p9'");
+    let e3 = read_scene("no-such-file.scene").unwrap_err();
+    assert_eq!(e3.file, Some("no-such-file.scene".to_string()));
+  }
 }
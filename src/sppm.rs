@@ -0,0 +1,96 @@
+// sppm
+//   stochastic progressive photon mapping (Hachisuka & Jensen, 2009).
+//   every pixel keeps its own radius, photon count and flux, updated after
+//   each photon pass from the hit points generated for that pass.
+
+use std::f64;
+
+use super::camera::*;
+use super::photonmap::*;
+use super::ray::*;
+use super::ray::optics::*;
+use super::tracer::*;
+
+const ONE_PI: Flt = 1.0 / f64::consts::PI;
+
+#[derive(Debug, Clone, Copy)]
+pub struct PixelStat {
+  pub radius: Flt,        // squared radius R^2
+  pub nphoton: Flt,       // accumulated photon count N
+  pub flux: Radiance,     // accumulated flux tau
+  pub direct: Radiance,   // sum of the radiance not from the photon map
+  pub npass: usize,
+}
+
+impl PixelStat {
+  pub fn new(radius: &Flt) -> PixelStat {
+    PixelStat {
+      radius: *radius,
+      nphoton: 0.0,
+      flux: Radiance::RADIANCE0,
+      direct: Radiance::RADIANCE0,
+      npass: 0,
+    }
+  }
+
+  // add a pass: M new photons shrink the radius so that N' = N + alpha * M
+  pub fn update(&mut self, alpha: &Flt, cam: &Camera, pmap: &PhotonMap, hps: &[HitPoint], direct: &Radiance) {
+    self.npass += 1;
+    self.direct = self.direct + *direct;
+    let mut m = 0;
+    let mut phi = Radiance::RADIANCE0;
+    for hp in hps {
+      let (m2, phi2) = gather_photons(cam, pmap, hp, &self.radius);
+      m += m2;
+      phi = phi + phi2;
+    }
+    if m == 0 {
+      return;
+    }
+    let n2 = self.nphoton + alpha * m as Flt;
+    let ratio = n2 / (self.nphoton + m as Flt);   // R'^2 / R^2
+    self.radius *= ratio;
+    self.flux = (self.flux + phi) * ratio;
+    self.nphoton = n2;
+  }
+
+  // pmap.power is the power of a photon in one pass, so the flux is averaged over the passes
+  pub fn radiance(&self) -> Radiance {
+    if self.npass == 0 {
+      return Radiance::RADIANCE0;
+    }
+    (self.direct + self.flux * (ONE_PI / self.radius)) * (1.0 / self.npass as Flt)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use super::super::ray::algebra::*;
+  use super::super::ray::geometry::*;
+  use super::super::ray::physics::*;
+
+  #[test]
+  fn test_update() {
+    let cam = parse_camera("").unwrap();
+    let mut pmap = build_photonmap(&0.5, &1.0, &vec![], &1).1;
+    let hp = HitPoint {
+      pos: Vector3::O,
+      nvec: Vector3::EY,
+      weight: Radiance::RADIANCE1,
+    };
+    let mut st = PixelStat::new(&1.0);
+    st.update(&0.5, &cam, &pmap, &[hp], &Radiance::RADIANCE1);
+    assert_eq!(st.radius, 1.0);
+    assert_eq!(st.radiance(), Radiance::RADIANCE1);
+
+    let ph = Photon::new(&Wavelength::RED, &Ray::new(&Vector3::O, &(-Vector3::EY)));
+    pmap = build_photonmap(&0.5, &1.0, &vec![ph, ph], &1).1;
+    st.update(&0.5, &cam, &pmap, &[hp], &Radiance::RADIANCE0);
+    // N = 0 + 0.5 * 2, R^2 = 1.0 * 1 / 2
+    assert_eq!(st.nphoton, 1.0);
+    assert_eq!(st.radius, 0.5);
+    assert!(st.flux.near(&(Wavelength::RED.to_rgb() * Radiance(0.5, 0.5, 0.5))));
+    assert_eq!(st.npass, 2);
+  }
+}
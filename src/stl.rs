@@ -0,0 +1,83 @@
+// stl
+//   binary STL import. the facet normals in the file are not used;
+//   triangles are shaded by their own normals.
+//
+//   80 bytes header, u32 number of triangles, then 50 bytes per triangle:
+//   normal and three vertices (f32 x 12) and u16 attribute, little-endian.
+
+use std::collections::HashMap;
+use std::fs;
+
+use super::error::*;
+use super::ray::*;
+use super::ray::algebra::*;
+use super::ray::geometry::*;
+
+const HEADER_SIZE: usize = 84;
+const TRIANGLE_SIZE: usize = 50;
+
+pub fn read_stl(file: &str) -> Result<Mesh, Error> {
+  let bytes = fs::read(file).map_err(|e| Error::io(file, &e))?;
+  parse_stl(&bytes).map_err(|e| e.in_file(file))
+}
+
+pub fn parse_stl(bytes: &[u8]) -> Result<Mesh, Error> {
+  let ntri = bytes.get(80..HEADER_SIZE).map(|b| u32::from_le_bytes([b[0], b[1], b[2], b[3]]) as usize);
+  let ntri = match ntri {
+    Some(n) if bytes.len() >= HEADER_SIZE + n * TRIANGLE_SIZE => n,
+    _ if bytes.starts_with(b"solid") => return Err(Error::new("ASCII STL is not supported, expected binary STL")),
+    Some(n) => return Err(Error::new(&format!("{} triangles declared, found {} bytes", n, bytes.len()))),
+    None    => return Err(Error::new("too short for a binary STL file")),
+  };
+
+  // vertices at the same position are shared
+  let mut ids: HashMap<[u32; 3], usize> = HashMap::new();
+  let mut positions: Vec<Position3> = vec![];
+  let mut indices: Vec<[usize; 3]> = Vec::with_capacity(ntri);
+  for t in bytes[HEADER_SIZE..HEADER_SIZE + ntri * TRIANGLE_SIZE].chunks(TRIANGLE_SIZE) {
+    let mut idx = [0; 3];
+    for (k, v) in t[12..48].chunks(12).enumerate() {
+      let f = |i: usize| f32::from_le_bytes([v[i], v[i + 1], v[i + 2], v[i + 3]]);
+      let (x, y, z) = (f(0), f(4), f(8));
+      idx[k] = *ids.entry([x.to_bits(), y.to_bits(), z.to_bits()]).or_insert_with(|| {
+        positions.push(Vector3::new(x as Flt, y as Flt, z as Flt));
+        positions.len() - 1
+      });
+    }
+    indices.push(idx);
+  }
+  Mesh::new(positions, None, None, indices).map_err(|e| Error::new(&e))
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn stl(tris: &[[f32; 9]]) -> Vec<u8> {
+    let mut bs = vec![0u8; 80];
+    bs.extend(&(tris.len() as u32).to_le_bytes());
+    for t in tris {
+      bs.extend(&[0u8; 12]);
+      for f in t {
+        bs.extend(&f.to_le_bytes());
+      }
+      bs.extend(&[0u8; 2]);
+    }
+    bs
+  }
+
+  #[test]
+  fn test_parse_stl() {
+    let bs = stl(&[
+      [0.0, 0.0, 0.0, 0.0, 0.0, 1.0, 1.0, 0.0, 0.0],
+      [1.0, 0.0, 0.0, 0.0, 0.0, 1.0, 1.0, 0.0, 1.0],
+    ]);
+    let m = parse_stl(&bs).unwrap();
+    assert_eq!(m.positions.len(), 4);
+    assert_eq!(m.indices, vec![[0, 1, 2], [2, 1, 3]]);
+    assert_eq!(m.normal(1, 0.3, 0.3), Some(Vector3::EY));
+    assert!(parse_stl(&bs[..bs.len() - 1]).is_err());
+    assert_eq!(format!("{}", parse_stl(b"solid cube\nfacet normal 0 0 1\n").unwrap_err()),
+      "ASCII STL is not supported, expected binary STL");
+  }
+}
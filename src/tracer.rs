@@ -3,41 +3,66 @@
 use std::f64;
 use std::iter::*;
 use std::vec::*;
-use std::cmp::*;
 
 use kdtree::distance::squared_euclidean;
 
 use super::ray::*;
 use super::ray::algebra::*;
+use super::ray::bvh::*;
 use super::ray::geometry::*;
-use super::ray::object::*;
 use super::ray::optics::*;
 use super::ray::light::*;
 use super::ray::material::*;
+use super::ray::medium::*;
 use super::ray::physics::*;
 use super::ray::surface::*;
+use super::ray::rng;
 
 use super::camera::*;
 use super::photonmap::*;
-use super::scene::*;
 
 const ONE_PI: Flt  = 1.0 / f64::consts::PI;
 const SR_HALF: Flt = 1.0 / (2.0 * f64::consts::PI);
 
 const MAX_TRACE: i32 = 10;
+const MAX_SCATTER: i32 = 1000;  // scatterings of a photon in a participating medium between surfaces
+const MAX_WALK: i32 = 10000;    // scatterings of a photon inside a subsurface object
 
 // Photon tracing
+//   OUT: photons stored on the surfaces. those scattered in participating media
+//        are added to vps (the volume photon map).
 
-pub fn trace_photon(uc: &bool, m0: &Material, objs: &Vec<Object>, l: i32, ph: &Photon) -> Vec<Photon> {
+pub fn trace_photon(uc: &bool, media: &Media, objs: &Bvh, l: i32, ph: &Photon, vps: &mut Vec<Photon>) -> Vec<Photon> {
   if l >= MAX_TRACE {
     return vec![]
   }
   
   let is = calc_intersection(&ph.ray, objs);
-  if is == None {
+  if let Some(s) = media.current().scattering {
+    let t = s.free_path(&ph.wl);
+    if is.as_ref().map_or(true, |is1| t < is1.dist) {
+      return scatter_photon(uc, media, objs, l, ph, &t, vps)
+    }
+  }
+  match is {
+    Some(is1) => hit_photon(uc, media, objs, l, ph, is1, vps),
+    None      => vec![],
+  }
+}
+
+// a photon reaching the surface at is1
+fn hit_photon(uc: &bool, media: &Media, objs: &Bvh, l: i32, ph: &Photon, is1: Intersection, vps: &mut Vec<Photon>) -> Vec<Photon> {
+  if l >= MAX_TRACE {
     return vec![]
   }
-  let is1 = is.unwrap();
+  if absorbed(media.current(), &is1.dist, &ph.wl) {
+    return vec![]
+  }
+  let entering = is1.io == InOut::In;
+  if passes(media, &is1, &entering) {
+    let ph2 = Photon::new(&ph.wl, &Ray::new(&is1.pos, &ph.ray.dir));
+    return trace_photon(uc, &media.cross(&is1.obj, &is1.mate, &entering), objs, l+1, &ph2, vps)
+  }
   let sf = is1.mate.surface;
   let mut pcs = match sf {
     Surface::Simple {
@@ -49,8 +74,8 @@ pub fn trace_photon(uc: &bool, m0: &Material, objs: &Vec<Object>, l: i32, ph: &P
       density_pow: _,
     } => {
       match russian_roulette(&[sf.roughness()]) {
-        0 => reflect_diff(uc, m0, objs, l, ph, &is1),
-        _ => reflect_spec(uc, m0, objs, l, ph, &is1),
+        0 => reflect_diff(uc, media, objs, l, ph, &is1, vps),
+        _ => reflect_spec(uc, media, objs, l, ph, &is1, vps),
       }
     },
     Surface::TS {
@@ -59,19 +84,28 @@ pub fn trace_photon(uc: &bool, m0: &Material, objs: &Vec<Object>, l: i32, ph: &P
       scatterness: _,
       metalness: _,
       roughness: _,
-      density_pow: _,
       alpha: _,
-    } => {
-      let eta = relative_ior_wavelength(&m0.ior, &is1.mate.ior, &ph.wl);
-      match sf.next_direction(&eta, &is1.nvec, &ph.ray.dir, &ph.wl) {
+    } | Surface::DisneyBRDF(_) | Surface::Brady {..} | Surface::Conductor {..} => {
+      let media2 = media.cross(&is1.obj, &is1.mate, &entering);
+      let eta = relative_ior(&media.current().ior_wavelength(&ph.wl), &media2.current().ior_wavelength(&ph.wl));
+      match sf.next_direction(&eta, &is1.nvec, &is1.tvec, &ph.ray.dir, &ph.wl) {
         Some((dir, m)) => {
-          let mate = if m == true { m0 } else { &is1.mate };
-          trace_photon(uc, mate, objs, l+1, &Photon::new(&ph.wl, &Ray::new(&is1.pos, &dir)))
+          let media3 = if m == true { media } else { &media2 };
+          trace_photon(uc, media3, objs, l+1, &Photon::new(&ph.wl, &Ray::new(&is1.pos, &dir)), vps)
         },
         None      => vec![],
       }
       
     },
+    Surface::Subsurface {..} => {
+      let media2 = media.cross(&is1.obj, &is1.mate, &entering);
+      let eta = relative_ior(&media.current().ior_wavelength(&ph.wl), &media2.current().ior_wavelength(&ph.wl));
+      match sf.next_direction(&eta, &is1.nvec, &is1.tvec, &ph.ray.dir, &ph.wl) {
+        Some((dir, true))  => trace_photon(uc, media, objs, l+1, &Photon::new(&ph.wl, &Ray::new(&is1.pos, &dir)), vps),
+        Some((dir, false)) => walk_photon(uc, media, &media2, objs, l, &Photon::new(&ph.wl, &Ray::new(&is1.pos, &dir)), vps),
+        None               => vec![],
+      }
+    },
     _ => vec![],
   };
   if (*uc == false || l > 0) && sf.store_photon() == true {
@@ -80,63 +114,135 @@ pub fn trace_photon(uc: &bool, m0: &Material, objs: &Vec<Object>, l: i32, ph: &P
   pcs
 }
 
-fn reflect_diff(uc: &bool, m0: &Material, objs: &Vec<Object>, l: i32, ph: &Photon, is: &Intersection) -> Vec<Photon> {
+fn reflect_diff(uc: &bool, media: &Media, objs: &Bvh, l: i32, ph: &Photon, is: &Intersection, vps: &mut Vec<Photon>) -> Vec<Photon> {
   let i = russian_roulette(&[is.mate.surface.albedo_diff(&ph.wl)]);
   match i {
     0 => {
       let dr = diffuse_reflection(&is.nvec);
-      trace_photon(uc, m0, objs, l+1, &Photon::new(&ph.wl, &Ray::new(&is.pos, &dr)))
+      trace_photon(uc, media, objs, l+1, &Photon::new(&ph.wl, &Ray::new(&is.pos, &dr)), vps)
     },
     _ => vec![],
   }
 }
 
-fn reflect_spec(uc: &bool, m0: &Material, objs: &Vec<Object>, l: i32, ph: &Photon, is: &Intersection) -> Vec<Photon> {
+fn reflect_spec(uc: &bool, media: &Media, objs: &Bvh, l: i32, ph: &Photon, is: &Intersection, vps: &mut Vec<Photon>) -> Vec<Photon> {
   let (rdir, cos1) = specular_reflection(&is.nvec, &ph.ray.dir);
 
   let f = schlick(&is.mate.surface.albedo_spec(&ph.wl), &cos1);
   let j = russian_roulette(&[f]);
   match j {
-    0 => trace_photon(uc, m0, objs, l+1, &Photon::new(&ph.wl, &Ray::new(&is.pos, &rdir))),
+    0 => trace_photon(uc, media, objs, l+1, &Photon::new(&ph.wl, &Ray::new(&is.pos, &rdir)), vps),
     _ => {
-      if is.mate.ior.wavelength(&ph.wl) == 0.0 {
+      if is.mate.ior_wavelength(&ph.wl) == 0.0 {
         vec![]
       } else {
-        reflect_trans(uc, m0, objs, l, ph, is, &cos1)
+        reflect_trans(uc, media, objs, l, ph, is, vps)
       }
     },
   }
 }
 
-fn reflect_trans(uc: &bool, m0: &Material, objs: &Vec<Object>, l: i32, ph: &Photon, is: &Intersection, _c0: &Flt) -> Vec<Photon> {
-  let eta = relative_ior_wavelength(&m0.ior, &is.mate.ior, &ph.wl);
+fn reflect_trans(uc: &bool, media: &Media, objs: &Bvh, l: i32, ph: &Photon, is: &Intersection, vps: &mut Vec<Photon>) -> Vec<Photon> {
+  let media2 = media.cross(&is.obj, &is.mate, &(is.io == InOut::In));
+  let eta = relative_ior(&media.current().ior_wavelength(&ph.wl), &media2.current().ior_wavelength(&ph.wl));
   let (tdir, _cos2) = specular_refraction(&is.nvec, &ph.ray.dir, &eta);
   match tdir {
     Some(tdir) => {
-      let m02 = if tdir.dot(&is.nvec) < 0.0 {
-        is.mate
-      } else {
-        M_AIR
-      };
-      trace_photon(uc, &m02, objs, l+1, &Photon::new(&ph.wl, &Ray::new(&is.pos, &tdir)))
+      trace_photon(uc, &media2, objs, l+1, &Photon::new(&ph.wl, &Ray::new(&is.pos, &tdir)), vps)
     },
     None => vec![]
   }
 }
 
+// a photon scattered in the current medium at t: stored there (every time, as the light
+// from the lights is not traced to the media for eye rays), then goes on in a
+// direction by the phase function, scattered again until it reaches a surface.
+// the scatterings between two surfaces count as one in the depth (then the photon is
+// not direct, stored even by uc) and have their own limit; a photon over it is lost
+// (a little darker media of a very high albedo).
+fn scatter_photon(uc: &bool, media: &Media, objs: &Bvh, l: i32, ph: &Photon, t: &Flt, vps: &mut Vec<Photon>) -> Vec<Photon> {
+  let m = media.current();
+  let s = match m.scattering {
+    Some(s) => s,
+    None    => return vec![],
+  };
+  let (mut ray, mut t) = (ph.ray, *t);
+  for _ in 0..MAX_SCATTER {
+    if absorbed(m, &t, &ph.wl) {
+      return vec![]
+    }
+    let pos = ray.target(t);
+    vps.push(Photon::new(&ph.wl, &Ray::new(&pos, &ray.dir)));
+    ray = Ray::new(&pos, &s.sample(&ray.dir));
+    let is = calc_intersection(&ray, objs);
+    t = s.free_path(&ph.wl);
+    match is {
+      Some(is1) if t >= is1.dist => return hit_photon(uc, media, objs, l+1, &Photon::new(&ph.wl, &ray), is1, vps),
+      _                          => (),
+    }
+  }
+  vec![]
+}
+
+// random walk of a photon entered a subsurface object (the current medium of inner):
+// it is scattered and absorbed inside until it comes out of the surface, where it is
+// stored as the light leaving there, and goes on outside (in media). a photon reaching
+// another object inside is lost, and so is one still inside after MAX_WALK scatterings,
+// which darkens thick objects of little absorption a little.
+fn walk_photon(uc: &bool, media: &Media, inner: &Media, objs: &Bvh, l: i32, ph: &Photon, vps: &mut Vec<Photon>) -> Vec<Photon> {
+  let m = inner.current();
+  let mut ray = ph.ray;
+  for _ in 0..MAX_WALK {
+    let is = match calc_intersection(&ray, objs) {
+      Some(is) if is.io == InOut::Out && is.mate == *m => is,
+      _                                                => return vec![],
+    };
+    let t = m.scattering.map_or(f64::INFINITY, |s| s.free_path(&ph.wl));
+    if absorbed(m, &t.min(is.dist), &ph.wl) {
+      return vec![]
+    }
+    if let Some(s) = m.scattering.filter(|_| t < is.dist) {
+      let pos = ray.target(t);
+      ray = Ray::new(&pos, &s.sample(&ray.dir));
+      continue;
+    }
+    let eta = relative_ior(&m.ior_wavelength(&ph.wl), &media.current().ior_wavelength(&ph.wl));
+    match m.surface.next_direction(&eta, &is.nvec, &is.tvec, &ray.dir, &ph.wl) {
+      Some((dir, true))  => ray = Ray::new(&is.pos, &dir),
+      Some((dir, false)) => {
+        let mut pcs = trace_photon(uc, media, objs, l+1, &Photon::new(&ph.wl, &Ray::new(&is.pos, &dir)), vps);
+        // stored reversed, as if it came in along the way it went out
+        pcs.push(Photon::new(&ph.wl, &Ray::new(&is.pos, &-dir)));
+        return pcs
+      },
+      None               => return vec![],
+    }
+  }
+  vec![]
+}
+
 // Photon mapping method
 
-pub fn trace_ray(cam: &Camera, m0: &Material, l: i32, objs: &Vec<Object>, lgts: &Vec<Light>, r: &Ray, pmap: &PhotonMap, radius: &Flt, uc: &bool) -> Radiance {
+// wl: wavelength of an eye ray split into the channels by a dispersive surface (None: not split)
+// vmap: volume photon map, gathered along the paths in participating media
+pub fn trace_ray(cam: &Camera, media: &Media, l: i32, objs: &Bvh, lgts: &Vec<Light>, r: &Ray, wl: &Option<Wavelength>, pmap: &PhotonMap, vmap: &PhotonMap, radius: &Flt, uc: &bool) -> Radiance {
   if l >= MAX_TRACE { return Radiance::RADIANCE0 }
   let is = calc_intersection(r, objs);
   if is == None { return Radiance::RADIANCE0 }
   let is1 = is.unwrap();
+  let att = media.current().attenuation(&is1.dist);
+  let vi = estimate_volume(vmap, media.current(), r, &is1.dist, radius);
+  let entering = is1.io == InOut::In;
+  let media2 = media.cross(&is1.obj, &is1.mate, &entering);
+  if passes(media, &is1, &entering) {
+    return vi + att * trace_ray(cam, &media2, l+1, objs, lgts, &Ray::new(&is1.pos, &r.dir), wl, pmap, vmap, radius, uc)
+  }
   
   // L_diffuse
-  let mut di = if *uc {
+  let mut di = if *uc && !lit_inside(&is1.mate.surface) {
     let mut rad = Radiance::RADIANCE0;
     for lt in lgts {
-      rad = rad + get_radiance_from_light(objs, &is1.pos, &is1.nvec, lt);
+      rad = rad + get_radiance_from_light(objs, media, &is1.pos, &is1.nvec, lt);
     }
     rad
   } else {
@@ -148,52 +254,102 @@ pub fn trace_ray(cam: &Camera, m0: &Material, l: i32, objs: &Vec<Object>, lgts:
 
   // L_spec
   let (rdir0, cos1) = specular_reflection(&is1.nvec, &r.dir);
-  let rdir = reflection_glossy(&is1.nvec, &rdir0, &mate.surface.power_glossy());
+  let rdir = mate.surface.reflection_dir(&is1.nvec, &is1.tvec, &r.dir, &rdir0);
   let si = if mate.surface.reflect(&cos1) == true {
-    trace_ray(cam, m0, l+1, objs, lgts, &Ray::new(&is1.pos, &rdir), pmap, radius, uc)
+    trace_ray(cam, media, l+1, objs, lgts, &Ray::new(&is1.pos, &rdir), wl, pmap, vmap, radius, uc)
   } else {
     Radiance::RADIANCE0
   };
   
-  // L_trans
-  let eta = relative_ior_average(&m0.ior, &mate.ior);
+  // L_trans, by the channels at a dispersive surface
   let hvec = (rdir - r.dir).normalize().unwrap();
-  let (tdir, cos2) = specular_refraction(&hvec, &r.dir, &eta);
-  let ti = match tdir {
-    Some(tdir) if mate.surface.refract(&cos1) == true => {
-      let m02 = match is1.io {
-        InOut::In  => mate,
-        InOut::Out => M_AIR,
-      };
-      trace_ray(cam, &m02, l+1, objs, lgts, &Ray::new(&is1.pos, &tdir), pmap, radius, uc)
-    },
-    _ => Radiance::RADIANCE0,
-  };
+  let mut rad = Radiance::RADIANCE0;
+  for (wl1, mask, eta) in eye_refractions(wl, media.current(), media2.current()) {
+    let (tdir, cos2) = specular_refraction(&hvec, &r.dir, &eta);
+    let ti = match tdir {
+      Some(tdir) if mate.surface.refract(&cos1) == true => {
+        trace_ray(cam, &media2, l+1, objs, lgts, &Ray::new(&is1.pos, &tdir), &wl1, pmap, vmap, radius, uc)
+      },
+      _ => Radiance::RADIANCE0,
+    };
+
+    let cos = if cos1 < cos2 { cos1 } else { cos2 };
+    rad = rad + mask * mate.surface.bsdf(&is1.nvec, &is1.tvec, &r.dir, &rdir, &tdir, &cos, &eta, &di, &si, &ti);
+  }
 
-  let cos = if cos1 < cos2 { cos1 } else { cos2 };
+  vi + att * (mate.emittance * SR_HALF + rad)
+}
 
-  mate.emittance * SR_HALF +
-  mate.surface.bsdf(&is1.nvec, &r.dir, &rdir, &tdir, &cos, &eta, &di, &si, &ti)
+// radiance scattered toward the eye along r up to d in the medium m (Jensen & Christensen,
+// 1998): the volume photons within the radius are gathered at points a diameter apart
+// from a random start, each standing for the segment up to the next one.
+fn estimate_volume(vmap: &PhotonMap, m: &Material, r: &Ray, d: &Flt, radius: &Flt) -> Radiance {
+  let s = match m.scattering {
+    Some(s) if vmap.kdtree.size() > 0 && *radius > 0.0 => s,
+    _                                                   => return Radiance::RADIANCE0,
+  };
+  let step = 2.0 * radius.sqrt();
+  let mag = step / (4.0 / 3.0 * f64::consts::PI * radius * radius.sqrt());
+  let mut rad = Radiance::RADIANCE0;
+  let mut t = step * rng::gen_range(0.0, 1.0);
+  while t < *d {
+    let ps = vmap.kdtree.within(&r.target(t).v, *radius, &squared_euclidean).unwrap();
+    let mut li = Radiance::RADIANCE0;
+    for (_, p) in ps {
+      li = li + photon_power(&(vmap.power * s.phase(&-p.ray.dir.dot(&r.dir))), p);
+    }
+    rad = rad + m.attenuation(&t) * (li * mag);
+    t += step;
+  }
+  rad
 }
 
 fn estimate_radiance(radius: &Flt, cam: &Camera, pmap: &PhotonMap, is: &Intersection) -> Radiance {
-  let ps: Vec<(Flt, &Photon)> = pmap.kdtree.within(&is.pos.v, pmap.radius, &squared_euclidean).unwrap();
-  if ps.len() == 0 {
+  let (ps, radius) = match cam.pestimate {
+    PhotonEstimate::Radius => {
+      (pmap.kdtree.within(&is.pos.v, pmap.radius, &squared_euclidean).unwrap(), *radius)
+    },
+    PhotonEstimate::Nearest       => nearest_photons(pmap, &is.pos, None),
+    PhotonEstimate::NearestCapped => nearest_photons(pmap, &is.pos, Some(pmap.radius)),
+  };
+  if ps.len() == 0 || radius <= 0.0 {
     Radiance::RADIANCE0
   } else {
     let mut rad = Radiance::RADIANCE0;
     for (d, p2) in ps {
-      let wt = match cam.pfilter {
-        PhotonFilter::None  => 1.0,
-        PhotonFilter::Cone  => filter_cone(&d, &radius),
-        PhotonFilter::Gauss => filter_gauss(&d, &radius),
-      };
+      let wt = filter_weight(cam, &d, &radius);
       rad = rad + photon_to_radiance(&is.nvec, &(wt * pmap.power), p2);
     }
     rad * (ONE_PI / radius)
   }
 }
 
+// k (= nsample) nearest photons and the squared distance to the k-th one.
+// with a cap, photons beyond it are dropped and the radius is at most the cap.
+fn nearest_photons<'a>(pmap: &'a PhotonMap, p: &Position3, cap: Option<Flt>) -> (Vec<(Flt, &'a Photon)>, Flt) {
+  let k = if pmap.nsample > 0 { pmap.nsample as usize } else { 1 };
+  let mut ps = pmap.kdtree.nearest(&p.v, k, &squared_euclidean).unwrap();
+  let dmax = match ps.last() {
+    Some((d, _)) => *d,
+    None         => 0.0,
+  };
+  match cap {
+    Some(c) if dmax > c => {
+      ps.retain(|(d, _)| *d <= c);
+      (ps, c)
+    },
+    _ => (ps, dmax),
+  }
+}
+
+fn filter_weight(cam: &Camera, d: &Flt, radius: &Flt) -> Flt {
+  match cam.pfilter {
+    PhotonFilter::None  => 1.0,
+    PhotonFilter::Cone  => filter_cone(d, radius),
+    PhotonFilter::Gauss => filter_gauss(d, radius),
+  }
+}
+
 // Cone filter
 const K_CONE: Flt = 1.1;
 const FAC_K: Flt  = 1.0 - 2.0 / (3.0 * K_CONE);
@@ -215,10 +371,112 @@ fn filter_gauss(d: &Flt, rmax: &Flt) -> Flt {
   if e_r > E_BETA { 0.0 } else { ALPHA * (1.0 - e_r / E_BETA) + CORR }
 }
 
+//
+// Stochastic progressive photon mapping
+//
+
+// a point where the photon map is looked up for a pixel
+#[derive(Debug, Clone, Copy)]
+pub struct HitPoint {
+  pub pos:    Position3,
+  pub nvec:   Direction3,
+  pub weight: Radiance,   // contribution to the pixel of the radiance estimated here
+}
+
+// wavelength, mask of the channels, eta, refraction direction and cos of an eye ray
+type EyeChannel = (Option<Wavelength>, Color, Flt, Option<Direction3>, Flt);
+
+// same path as trace_ray, but the photon map estimates are left to the hit points.
+// wt is the contribution of the radiance along r to the pixel.
+// OUT: radiance along r without the photon map estimates
+// the participating media are estimated along the path from the volume photon map
+// of the pass (vmap), by the fixed radius. wl is that of trace_ray.
+pub fn trace_hitpoints(media: &Media, l: i32, objs: &Bvh, lgts: &Vec<Light>, r: &Ray, wl: &Option<Wavelength>, wt: &Radiance, uc: &bool, vmap: &PhotonMap, radius: &Flt, hps: &mut Vec<HitPoint>) -> Radiance {
+  if l >= MAX_TRACE { return Radiance::RADIANCE0 }
+  let is = calc_intersection(r, objs);
+  if is.is_none() { return Radiance::RADIANCE0 }
+  let is1 = is.unwrap();
+  // the weights and the radiance of this point are those through the medium
+  let att = media.current().attenuation(&is1.dist);
+  let vi = estimate_volume(vmap, media.current(), r, &is1.dist, radius);
+  let wt = &(att * *wt);
+  let entering = is1.io == InOut::In;
+  let media2 = media.cross(&is1.obj, &is1.mate, &entering);
+  if passes(media, &is1, &entering) {
+    return vi + att * trace_hitpoints(&media2, l+1, objs, lgts, &Ray::new(&is1.pos, &r.dir), wl, wt, uc, vmap, radius, hps)
+  }
+
+  let mut di = Radiance::RADIANCE0;
+  if *uc && !lit_inside(&is1.mate.surface) {
+    for lt in lgts {
+      di = di + get_radiance_from_light(objs, media, &is1.pos, &is1.nvec, lt);
+    }
+  }
+
+  let mate = is1.mate;
+  let r0 = Radiance::RADIANCE0;
+
+  // bsdf is linear in each incoming radiance, so feeding wt gives the weights of the next paths
+  let (rdir0, cos1) = specular_reflection(&is1.nvec, &r.dir);
+  let rdir = mate.surface.reflection_dir(&is1.nvec, &is1.tvec, &r.dir, &rdir0);
+  let hvec = (rdir - r.dir).normalize().unwrap();
+  // the refractions by the channels at a dispersive surface
+  let chs: Vec<EyeChannel> =
+    eye_refractions(wl, media.current(), media2.current()).into_iter().map(|(wl1, mask, eta)| {
+      let (tdir, cos2) = specular_refraction(&hvec, &r.dir, &eta);
+      (wl1, mask, eta, tdir, if cos1 < cos2 { cos1 } else { cos2 })
+    }).collect();
+  let bsdf = |ch: &EyeChannel, di: &Radiance, si: &Radiance, ti: &Radiance| {
+    let (_, mask, eta, tdir, cos) = ch;
+    *mask * mate.surface.bsdf(&is1.nvec, &is1.tvec, &r.dir, &rdir, tdir, cos, eta, di, si, ti)
+  };
+  let bsdf_all = |di: &Radiance, si: &Radiance| {
+    chs.iter().fold(Radiance::RADIANCE0, |rad, ch| rad + bsdf(ch, di, si, &r0))
+  };
+
+  let wd = bsdf_all(wt, &r0);
+  if wd.norm() > 0.0 {
+    hps.push(HitPoint {pos: is1.pos, nvec: is1.nvec, weight: wd});
+  }
+
+  // L_spec
+  let si = if mate.surface.reflect(&cos1) {
+    trace_hitpoints(media, l+1, objs, lgts, &Ray::new(&is1.pos, &rdir), wl, &bsdf_all(&r0, wt), uc, vmap, radius, hps)
+  } else {
+    Radiance::RADIANCE0
+  };
+
+  // L_trans
+  let mut rad = bsdf_all(&di, &si);
+  for ch in &chs {
+    match ch.3 {
+      Some(tdir) if mate.surface.refract(&cos1) => {
+        let ti = trace_hitpoints(&media2, l+1, objs, lgts, &Ray::new(&is1.pos, &tdir), &ch.0, &bsdf(ch, &r0, &r0, wt), uc, vmap, radius, hps);
+        rad = rad + bsdf(ch, &r0, &r0, &ti);
+      },
+      _ => (),
+    }
+  }
+
+  vi + att * (mate.emittance * SR_HALF + rad)
+}
+
+// photons within radius (squared) of a hit point
+// OUT: (number of photons, their flux weighted for the pixel)
+pub fn gather_photons(cam: &Camera, pmap: &PhotonMap, hp: &HitPoint, radius: &Flt) -> (usize, Radiance) {
+  let ps: Vec<(Flt, &Photon)> = pmap.kdtree.within(&hp.pos.v, *radius, &squared_euclidean).unwrap();
+  let mut flux = Radiance::RADIANCE0;
+  for (d, p) in &ps {
+    let wt = filter_weight(cam, d, radius);
+    flux = flux + hp.weight.filter(&photon_to_radiance(&hp.nvec, &(wt * pmap.power), p));
+  }
+  (ps.len(), flux)
+}
+
 //
 // CLASSIC Ray tracer
 //
-pub fn trace_ray_classic(cam: &Camera, m0: &Material, l: i32, objs: &Vec<Object>, lgts: &Vec<Light>, r: &Ray) -> Radiance {
+pub fn trace_ray_classic(cam: &Camera, media: &Media, l: i32, objs: &Bvh, lgts: &Vec<Light>, r: &Ray, wl: &Option<Wavelength>) -> Radiance {
   if l >= 10 {
     return Radiance::RADIANCE0
   }
@@ -228,49 +486,97 @@ pub fn trace_ray_classic(cam: &Camera, m0: &Material, l: i32, objs: &Vec<Object>
   }
 
   let is1 = is.unwrap();
+  let att = media.current().attenuation(&is1.dist);
+  let entering = is1.io == InOut::In;
+  let media2 = media.cross(&is1.obj, &is1.mate, &entering);
+  if passes(media, &is1, &entering) {
+    return att * trace_ray_classic(cam, &media2, l+1, objs, lgts, &Ray::new(&is1.pos, &r.dir), wl)
+  }
   let mate = is1.mate;
   let (rdir, cos1) = specular_reflection(&is1.nvec, &r.dir);
 
   let mut di = Radiance::RADIANCE0;
   for lt in lgts {
-    di = di + get_radiance_from_light(objs, &is1.pos, &is1.nvec, lt);
+    di = di + get_radiance_from_light(objs, media, &is1.pos, &is1.nvec, lt);
   }
   di = di + cam.ambient; 
 
   let si = if mate.surface.reflect(&cos1) == true {
-    trace_ray_classic(cam, m0, l+1, objs, lgts, &Ray::new(&is1.pos, &rdir))
+    trace_ray_classic(cam, media, l+1, objs, lgts, &Ray::new(&is1.pos, &rdir), wl)
   } else {
     Radiance::RADIANCE0
   };
 
-  // L_trans
-  let eta = relative_ior_average(&m0.ior, &mate.ior);
-  let (tdir, _cos2) = specular_refraction(&is1.nvec, &r.dir, &eta);
-  let ti = match tdir {
-    Some(tdir) if mate.surface.refract(&cos1) == true => {
-      let m02 = if tdir.dot(&is1.nvec) < 0.0 { mate } else { M_AIR };
-      trace_ray_classic(cam, &m02, l+1, objs, lgts, &Ray::new(&is1.pos, &tdir))
-    },
-    _ => Radiance::RADIANCE0,
-  };
-  
-  mate.emittance * SR_HALF +
-  mate.surface.bsdf(&is1.nvec, &r.dir, &rdir, &tdir, &cos1, &eta, &di, &si, &ti)
+  // L_trans, by the channels at a dispersive surface
+  let mut rad = Radiance::RADIANCE0;
+  for (wl1, mask, eta) in eye_refractions(wl, media.current(), media2.current()) {
+    let (tdir, _cos2) = specular_refraction(&is1.nvec, &r.dir, &eta);
+    let ti = match tdir {
+      Some(tdir) if mate.surface.refract(&cos1) == true => {
+        trace_ray_classic(cam, &media2, l+1, objs, lgts, &Ray::new(&is1.pos, &tdir), &wl1)
+      },
+      _ => Radiance::RADIANCE0,
+    };
+    rad = rad + mask * mate.surface.bsdf(&is1.nvec, &is1.tvec, &r.dir, &rdir, &tdir, &cos1, &eta, &di, &si, &ti);
+  }
+
+  att * (mate.emittance * SR_HALF + rad)
 }
 
 // private
 
-fn get_radiance_from_light(objs: &Vec<Object>, p: &Position3, n: &Direction3, l: &Light) -> Radiance {
-  let (dists, coss): (Vec<Flt>, Vec<Flt>) = illuminated(objs, p, n, &l.get_direction(p)).iter().cloned().unzip();
+// relative iors of an eye ray, by the average of the RGB ior. at a dispersive surface, it
+// is split into the channels refracted at their representatives, each keeping the wavelength.
+// OUT: (wavelength of the ray after, channels of it, eta) of each
+fn eye_refractions(wl: &Option<Wavelength>, m1: &Material, m2: &Material) -> Vec<(Option<Wavelength>, Color, Flt)> {
+  let chs = if wl.is_none() && (m1.dispersion.is_some() || m2.dispersion.is_some()) {
+    vec![
+      (Some(Wavelength::RED),   Color(1.0, 0.0, 0.0)),
+      (Some(Wavelength::GREEN), Color(0.0, 1.0, 0.0)),
+      (Some(Wavelength::BLUE),  Color(0.0, 0.0, 1.0)),
+    ]
+  } else {
+    vec![(*wl, Color::WHITE)]
+  };
+  chs.into_iter().map(|(wl1, mask)| {
+    let eta = match wl1 {
+      Some(w) => relative_ior(&m1.ior_wavelength(&w), &m2.ior_wavelength(&w)),
+      None    => relative_ior_average(&m1.ior, &m2.ior),
+    };
+    (wl1, mask, eta)
+  }).collect()
+}
+
+// Beer-Lambert absorption of a photon on the way through the medium m
+fn absorbed(m: &Material, d: &Flt, wl: &Wavelength) -> bool {
+  m.transmittance != Color::BLACK && russian_roulette(&[m.absorption_wavelength(d, wl)]) > 0
+}
+
+// the light of a subsurface object comes out of the inside: all of it is in the photons
+// stored where it left, including the direct light
+fn lit_inside(sf: &Surface) -> bool {
+  matches!(sf, Surface::Subsurface {..})
+}
+
+// the surface is not seen: inside a medium of higher priority, or the boundary of a volume
+fn passes(media: &Media, is: &Intersection, entering: &bool) -> bool {
+  is.mate.surface == Surface::Nothing || media.hidden(&is.obj, &is.mate, entering)
+}
+
+// the light is attenuated through the media on the way from the light to p, which is in media
+fn get_radiance_from_light(objs: &Bvh, media: &Media, p: &Position3, n: &Direction3, l: &Light) -> Radiance {
+  let (dists, cas): (Vec<Flt>, Vec<(Flt, Color)>) = illuminated(objs, media, p, n, &l.get_direction(p)).iter()
+    .map(|(d, c, a)| (*d, (*c, *a))).unzip();
   let mut rad = Radiance::RADIANCE0;
-  for r in l.get_radiance(&dists).iter().zip(coss).map(|(a,b)| *a * b) {
-    rad = rad + r;
+  for (r, (c, a)) in l.get_radiance(&dists).iter().zip(cas) {
+    rad = rad + a * (*r * c);
   }
   rad
 }
 
-fn illuminated(os: &Vec<Object>, p: &Position3, n: &Direction3, lds: &Vec<Direction3>) -> Vec<(Flt, Flt)> {
-  let mut ret: Vec<(Flt, Flt)> = vec![];
+// OUT: (square of the distance, square of the cos, attenuation) of the lit directions
+fn illuminated(os: &Bvh, media: &Media, p: &Position3, n: &Direction3, lds: &Vec<Direction3>) -> Vec<(Flt, Flt, Color)> {
+  let mut ret: Vec<(Flt, Flt, Color)> = vec![];
   for ld in lds {
     let ld2 = ld.normalize();
     if ld2 == None { continue; }
@@ -278,13 +584,12 @@ fn illuminated(os: &Vec<Object>, p: &Position3, n: &Direction3, lds: &Vec<Direct
     let cos0 = n.dot(&ld3);
     if cos0 < 0.0 { continue; }
     let lray = Ray::new(&p, &ld3);
-    let is = calc_intersection(&lray, os);
-    if is == None { continue; }
-    let p2 = is.unwrap().pos;
+    let vis = visible_through(&lray, media, os);
+    if vis == None { continue; }
+    let (odist, att) = vis.unwrap();
     let sq_ldist = ld.square();
-    let sq_odist = (p2 - *p).square();
-    if sq_ldist - sq_odist > 0.002 { continue; }
-    ret.push((sq_ldist, cos0 * cos0));
+    if sq_ldist - odist * odist > 0.002 { continue; }
+    ret.push((sq_ldist, cos0 * cos0, att));
   }
   ret
 }
@@ -298,70 +603,60 @@ enum InOut {
 #[derive(PartialEq)]
 struct Intersection {
   pub pos:  Position3,
+  pub dist: Flt,          // from the origin of the ray
   pub nvec: Direction3,
+  pub tvec: Option<Direction3>,   // tangent of the shape, for anisotropic surfaces only
+  pub obj:  usize,        // id of the object: index in the scene
   pub mate: Material,
   pub io:   InOut,
 }
 
-fn calc_intersection(r: &Ray, os: &Vec<Object>) -> Option<Intersection> {
-  fn sorting(is1: &(Flt, Object), is2: &(Flt, Object)) -> Ordering {
-    if is1.0 < is2.0 {
-      Ordering::Less
-    } else if is1.0 > is2.0 {
-        Ordering::Greater
-    } else {
-        Ordering::Equal
-    }
-  }
-  /*
-  let iss0: Vec<Vec<(Flt, Object)>> = os.iter().map(|o| calc_distance(r, o)).collect();
-  let iss1 = iss0.concat();
-  */
-  let mut iss1: Vec<(Flt, Object)> = vec![];
-  for o in os {
-    let is = calc_distance(r, o);
-    for i in &is {
-      if i.0 < NEARLY0 { continue; }
-      iss1.push(*i);
-    }
-    //if is.0 > &NEARLY0 { continue; }
-    //iss1.append(&mut is);
-  }
-  
-  if iss1.len() == 0 {
-    None
-  } else {
-//    let mut iss: Vec<(Flt, Object)> = iss1.iter().filter(|i| i.0 > NEARLY0).cloned().collect();
-    iss1.sort_by(|a, b| sorting(a, b));
-    let (t, obj) = iss1.first().unwrap();
-    let p = r.target(*t);
-    let nvec = obj.shape.get_normal(&p);
-    if let Some(mut n) = nvec {
-      if n.dot(&r.dir) > 0.0 {
-        n = -n;
-        Some(Intersection {pos: p, nvec: n, mate: obj.material, io: InOut::Out})
-      } else {
-        Some(Intersection {pos: p, nvec: n, mate: obj.material, io: InOut::In})
-      }
-    } else {
-      None
+// the distance to the visible surface along r, through the boundaries of volumes and the
+// hidden surfaces, and the attenuation by each medium on the way
+fn visible_through(r: &Ray, media: &Media, os: &Bvh) -> Option<(Flt, Color)> {
+  let mut media1 = media.clone();
+  let mut r1 = *r;
+  let (mut dist, mut att) = (0.0, Color::WHITE);
+  loop {
+    let is = calc_intersection(&r1, os)?;
+    dist += is.dist;
+    att = att * media1.current().attenuation(&is.dist);
+    let entering = is.io == InOut::In;
+    if !passes(&media1, &is, &entering) {
+      return Some((dist, att))
     }
+    media1 = media1.cross(&is.obj, &is.mate, &entering);
+    r1 = Ray::new(&is.pos, &r.dir);
   }
 }
 
-fn calc_distance(r: &Ray, o: &Object) -> Vec<(Flt, Object)> {
-  let ts = o.shape.distance(r);
-  let mut iss: Vec<(Flt, Object)> = vec![];
-  for t in &ts {
-    iss.push((*t, *o))
+fn calc_intersection(r: &Ray, os: &Bvh) -> Option<Intersection> {
+  let (i, h) = os.nearest(r)?;
+  let obj = &os.objs[i];
+  let p = r.target(h.dist);
+  let nvec = obj.shape.normal_at(&p, &h);
+  let tvec = match obj.material.surface {
+    Surface::Brady {..} | Surface::DisneyBRDF(_) => obj.shape.tangent_at(&p, &h),
+    _                                            => None,
+  };
+  if let Some(mut n) = nvec {
+    if n.dot(&r.dir) > 0.0 {
+      n = -n;
+      Some(Intersection {pos: p, dist: h.dist, nvec: n, tvec, obj: i, mate: obj.material, io: InOut::Out})
+    } else {
+      Some(Intersection {pos: p, dist: h.dist, nvec: n, tvec, obj: i, mate: obj.material, io: InOut::In})
+    }
+  } else {
+    None
   }
-  iss
 }
 
 
 #[cfg(test)]
 mod tests {
   use super::*;
+  use super::super::ray::object::*;
+  use super::super::ray::volume::*;
 
   #[test]
   fn test_filter() {
@@ -375,6 +670,54 @@ mod tests {
     let wt4 = filter_gauss(&r, &r);
     assert_eq!(wt4, 0.6061526928041553);
   }
+
+  #[test]
+  fn test_nearest_photons() {
+    let phs: Vec<Photon> = (1..=5)
+      .map(|i| Photon::new(&Wavelength::RED, &Ray::new(&Vector3::new(i as Flt * 0.1, 0.0, 0.0), &Vector3::EY)))
+      .collect();
+    let (_, pmap) = build_photonmap(&1.0, &(0.25 * 0.25), &phs, &3);
+    let (ps, r) = nearest_photons(&pmap, &Vector3::O, None);
+    assert_eq!(ps.len(), 3);
+    assert!((r - 0.3 * 0.3).abs() < 1.0e-10);
+    let (ps2, r2) = nearest_photons(&pmap, &Vector3::O, Some(pmap.radius));
+    assert_eq!(ps2.len(), 2);
+    assert_eq!(r2, 0.25 * 0.25);
+  }
+
+  #[test]
+  fn test_walk_photon() {
+    let air = Material {
+      emittance:     Radiance::RADIANCE0,
+      transmittance: Color::BLACK,
+      ior:           Color(1.0, 1.0, 1.0),
+      dispersion:    None,
+      scattering:    None,
+      surface:       Surface::Nothing,
+      priority:      0,
+    };
+    let stone = Material {
+      ior:           Color(1.5, 1.5, 1.5),
+      scattering:    Some(Scattering {sigma_s: Color(10.0, 10.0, 10.0), g: 0.0}),
+      surface:       Surface::new_subsurface(&0.0),
+      ..air
+    };
+    let objs = Bvh::new(&vec![Object::new(&Shape::Sphere {center: Vector3::O, radius: 1.0}, &stone)]);
+    let n = 1000;
+    let mut phs = vec![];
+    let mut vps = vec![];
+    for _ in 0..n {
+      let ph = Photon::new(&Wavelength::GREEN, &Ray::new(&Vector3::new(0.0, 0.0, -3.0), &Vector3::EZ));
+      phs.extend(trace_photon(&false, &Media::new(&air), &objs, 0, &ph, &mut vps));
+    }
+    assert!(vps.is_empty());
+    // without absorption, what is not reflected at the entry comes out again
+    assert!(phs.len() > n * 9 / 10, "{}", phs.len());
+    for p in &phs {
+      assert!((p.ray.pos.norm() - 1.0).abs() < 1.0e-6);
+      assert!(p.ray.dir.dot(&p.ray.pos) < 0.0);
+    }
+  }
 }
 
 
@@ -0,0 +1,358 @@
+// wavefront
+//   Wavefront OBJ/MTL import.
+//   each group ('g' or 'o') and material ('usemtl') of an OBJ file becomes a
+//   triangle mesh. polygons are split into triangles as a fan.
+//
+//   MTL parameters are mapped onto Surface::TS:
+//     Kd -> albedo_diff, Ks -> albedo_spec, Ns -> roughness (or Pr), Pm -> metalness,
+//     d (or 1 - Tr) -> scatterness, 1 - Tf -> transmittance (absorption), Ni -> ior,
+//     Ke -> emittance
+
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+
+use super::error::*;
+use super::ray::*;
+use super::ray::algebra::*;
+use super::ray::geometry::*;
+use super::ray::material::*;
+use super::ray::optics::*;
+use super::ray::physics::*;
+use super::ray::surface::*;
+
+#[derive(Debug)]
+pub struct ObjGroup {
+  pub name: String,
+  pub mesh: Mesh,
+  pub material: Option<Material>,   // None: no 'usemtl' or not found in the MTL files
+}
+
+pub fn read_obj(file: &str) -> Result<Vec<ObjGroup>, Error> {
+  let contents = fs::read_to_string(file).map_err(|e| Error::io(file, &e))?;
+  let dir = Path::new(file).parent().unwrap_or_else(|| Path::new(""));
+  parse_obj(&contents, dir).map_err(|e| e.in_file(file))
+}
+
+// 'mtllib' files are looked for in dir
+pub fn parse_obj(contents: &str, dir: &Path) -> Result<Vec<ObjGroup>, Error> {
+  let mut positions: Vec<Position3> = vec![];
+  let mut normals: Vec<Direction3> = vec![];
+  let mut uvs: Vec<(Flt, Flt)> = vec![];
+  let mut mates: HashMap<String, Material> = HashMap::new();
+  let mut builders: Vec<MeshBuilder> = vec![];
+  let mut group = "default".to_string();
+  let mut usemtl: Option<String> = None;
+
+  for (i, line) in contents.lines().enumerate() {
+    let lno = Some(i + 1);
+    let body = line.split('#').next().unwrap_or("").trim();
+    let mut elems = body.split_whitespace();
+    let key = match elems.next() {
+      Some(k) => k,
+      None    => continue,
+    };
+    let args: Vec<&str> = elems.collect();
+    match key {
+      "v"  => positions.push(obj_vector(key, &args).map_err(|e| e.at_line(lno))?),
+      "vn" => normals.push(obj_vector(key, &args).map_err(|e| e.at_line(lno))?.normalize()
+        .ok_or_else(|| Error::invalid(key, "a non-zero vector", body).at_line(lno))?),
+      "vt" => {
+        let fs = obj_floats(key, &args, 1).map_err(|e| e.at_line(lno))?;
+        uvs.push((fs[0], if fs.len() > 1 { fs[1] } else { 0.0 }));
+      },
+      "f"  => {
+        if args.len() < 3 {
+          return Err(Error::invalid(key, "three or more vertices", body).at_line(lno));
+        }
+        let corners = args.iter()
+          .map(|a| obj_corner(a, positions.len(), uvs.len(), normals.len()))
+          .collect::<Option<Vec<Corner>>>()
+          .ok_or_else(|| Error::invalid(key, "vertices 'v', 'v/vt', 'v//vn' or 'v/vt/vn' of defined indices", body).at_line(lno))?;
+        let b = match builders.iter().position(|b| b.group == group && b.material == usemtl) {
+          Some(j) => j,
+          None    => {
+            builders.push(MeshBuilder::new(&group, &usemtl));
+            builders.len() - 1
+          },
+        };
+        for k in 1..corners.len() - 1 {
+          builders[b].add_triangle(&[corners[0], corners[k], corners[k + 1]]);
+        }
+      },
+      "g" | "o" => group = if args.is_empty() { "default".to_string() } else { args.join(" ") },
+      "usemtl"  => usemtl = args.first().map(|s| s.to_string()),
+      "mtllib"  => {
+        for f in &args {
+          let path = dir.join(f);
+          let file = path.to_string_lossy();
+          let contents = fs::read_to_string(&path).map_err(|e| Error::io(&file, &e).at_line(lno))?;
+          mates.extend(parse_mtl(&contents).map_err(|e| e.in_file(&file))?);
+        }
+      },
+      _ => (),    // s, l, p and others are not used
+    }
+  }
+
+  builders.iter()
+    .filter(|b| !b.triangles.is_empty())
+    .map(|b| {
+      let mesh = b.build(&positions, &uvs, &normals).map_err(|e| Error::new(&format!("group {}: {}", b.group, e)))?;
+      let material = b.material.as_ref().and_then(|m| mates.get(m)).copied();
+      Ok(ObjGroup {name: b.group.clone(), mesh, material})
+    })
+    .collect()
+}
+
+pub fn parse_mtl(contents: &str) -> Result<HashMap<String, Material>, Error> {
+  let mut mates: HashMap<String, Material> = HashMap::new();
+  let mut current: Option<(String, MtlParams)> = None;
+  for (i, line) in contents.lines().enumerate() {
+    let lno = Some(i + 1);
+    let body = line.split('#').next().unwrap_or("").trim();
+    let mut elems = body.split_whitespace();
+    let key = match elems.next() {
+      Some(k) => k,
+      None    => continue,
+    };
+    let args: Vec<&str> = elems.collect();
+    if key == "newmtl" {
+      if let Some((name, p)) = current.take() {
+        mates.insert(name, p.to_material());
+      }
+      current = Some((args.join(" "), MtlParams::default()));
+      continue;
+    }
+    let p = match current.as_mut() {
+      Some((_, p)) => p,
+      None         => continue,
+    };
+    let color = || -> Result<Color, Error> {
+      let fs = obj_floats(key, &args, 1)?;
+      Ok(if fs.len() >= 3 { Color(fs[0], fs[1], fs[2]) } else { Color(fs[0], fs[0], fs[0]) })
+    };
+    let float = || -> Result<Flt, Error> { Ok(obj_floats(key, &args, 1)?[0]) };
+    match key {
+      "Kd" => p.kd = color().map_err(|e| e.at_line(lno))?,
+      "Ks" => p.ks = color().map_err(|e| e.at_line(lno))?,
+      "Ke" => p.ke = color().map_err(|e| e.at_line(lno))?,
+      "Tf" => p.tf = Some(color().map_err(|e| e.at_line(lno))?),
+      "Ns" => p.ns = Some(float().map_err(|e| e.at_line(lno))?),
+      "Pr" => p.pr = Some(float().map_err(|e| e.at_line(lno))?),
+      "Pm" => p.pm = float().map_err(|e| e.at_line(lno))?,
+      "Ni" => p.ni = float().map_err(|e| e.at_line(lno))?,
+      "d"  => p.d = float().map_err(|e| e.at_line(lno))?,
+      "Tr" => p.d = 1.0 - float().map_err(|e| e.at_line(lno))?,
+      _    => (),   // texture maps and illumination models are not used
+    }
+  }
+  if let Some((name, p)) = current {
+    mates.insert(name, p.to_material());
+  }
+  Ok(mates)
+}
+
+//--------------------
+// private
+
+struct MtlParams {
+  kd: Color,
+  ks: Color,
+  ke: Color,
+  tf: Option<Color>,
+  ns: Option<Flt>,
+  pr: Option<Flt>,
+  pm: Flt,
+  ni: Flt,
+  d: Flt,
+}
+
+impl Default for MtlParams {
+  fn default() -> MtlParams {
+    MtlParams {
+      kd: Color(0.8, 0.8, 0.8),
+      ks: Color::BLACK,
+      ke: Color::BLACK,
+      tf: None,
+      ns: None,
+      pr: None,
+      pm: 0.0,
+      ni: 1.0,
+      d: 1.0,
+    }
+  }
+}
+
+impl MtlParams {
+  fn to_material(&self) -> Material {
+    // Phong exponent to roughness: Ns = 2 / alpha^2 - 2, alpha = r^2
+    let rough = match (self.pr, self.ns) {
+      (Some(r), _)    => r,
+      (None, Some(n)) => (2.0 / (n.max(0.0) + 2.0)).powf(0.25),
+      (None, None)    => 1.0,
+    };
+    let clamp = |x: Flt| x.clamp(0.0, 1.0);
+    Material {
+      emittance: Radiance(self.ke.0, self.ke.1, self.ke.2),
+      transmittance: self.tf.map(|tf| -tf).unwrap_or(Color::BLACK),
+      ior: Color(self.ni, self.ni, self.ni),
+      dispersion: None,
+      scattering: None,
+      surface: Surface::new_ts(&self.kd, &self.ks, &clamp(self.d), &clamp(self.pm), &clamp(rough)),
+      priority: 0,
+    }
+  }
+}
+
+// indices of position, uv and normal of a face corner
+type Corner = (usize, Option<usize>, Option<usize>);
+
+struct MeshBuilder {
+  group: String,
+  material: Option<String>,
+  triangles: Vec<[Corner; 3]>,
+}
+
+impl MeshBuilder {
+  fn new(group: &str, material: &Option<String>) -> MeshBuilder {
+    MeshBuilder {group: group.to_string(), material: material.clone(), triangles: vec![]}
+  }
+
+  fn add_triangle(&mut self, cs: &[Corner; 3]) {
+    self.triangles.push(*cs);
+  }
+
+  // OBJ indexes positions, uvs and normals separately; a mesh has one index per vertex.
+  // uvs and normals are kept only if every corner has them.
+  fn build(&self, positions: &[Position3], uvs: &[(Flt, Flt)], normals: &[Direction3]) -> Result<Mesh, String> {
+    let all_uv = self.triangles.iter().flatten().all(|c| c.1.is_some());
+    let all_nv = self.triangles.iter().flatten().all(|c| c.2.is_some());
+    let mut ids: HashMap<Corner, usize> = HashMap::new();
+    let mut ps: Vec<Position3> = vec![];
+    let mut ts: Vec<(Flt, Flt)> = vec![];
+    let mut ns: Vec<Direction3> = vec![];
+    let mut indices: Vec<[usize; 3]> = vec![];
+    for tri in &self.triangles {
+      let mut idx = [0; 3];
+      for (k, c) in tri.iter().enumerate() {
+        let key = (c.0, if all_uv { c.1 } else { None }, if all_nv { c.2 } else { None });
+        idx[k] = *ids.entry(key).or_insert_with(|| {
+          ps.push(positions[c.0]);
+          if let Some(t) = key.1 { ts.push(uvs[t]); }
+          if let Some(n) = key.2 { ns.push(normals[n]); }
+          ps.len() - 1
+        });
+      }
+      indices.push(idx);
+    }
+    Mesh::new(ps, if all_nv { Some(ns) } else { None }, if all_uv { Some(ts) } else { None }, indices)
+  }
+}
+
+fn obj_floats(key: &str, args: &[&str], n: usize) -> Result<Vec<Flt>, Error> {
+  let fs: Vec<Flt> = args.iter().filter_map(|a| a.parse::<Flt>().ok()).collect();
+  if fs.len() < n || fs.len() != args.len() {
+    return Err(Error::invalid(key, &format!("{} or more numbers", n), &args.join(" ")));
+  }
+  Ok(fs)
+}
+
+fn obj_vector(key: &str, args: &[&str]) -> Result<Vector3, Error> {
+  let fs = obj_floats(key, args, 3)?;
+  Ok(Vector3::new(fs[0], fs[1], fs[2]))
+}
+
+// 'v', 'v/vt', 'v//vn' or 'v/vt/vn', 1-origin. negative indices count from the last.
+fn obj_corner(s: &str, np: usize, nt: usize, nn: usize) -> Option<Corner> {
+  let idx = |e: &str, n: usize| -> Option<usize> {
+    let i = e.parse::<i64>().ok()?;
+    let j = if i < 0 { n as i64 + i } else { i - 1 };
+    if 0 <= j && j < n as i64 { Some(j as usize) } else { None }
+  };
+  let es: Vec<&str> = s.split('/').collect();
+  let v = idx(es[0], np)?;
+  let t = match es.get(1) {
+    Some(e) if !e.is_empty() => Some(idx(e, nt)?),
+    _                        => None,
+  };
+  let n = match es.get(2) {
+    Some(e) if !e.is_empty() => Some(idx(e, nn)?),
+    _                        => None,
+  };
+  if es.len() > 3 { None } else { Some((v, t, n)) }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  const OBJ: &str = "
+# a quad and a triangle
+v 0 0 0
+v 1 0 0
+v 1 0 1
+v 0 0 1
+v 0 1 0
+vn 0 1 0
+vt 0 0
+vt 1 0
+vt 1 1
+vt 0 1
+g floor
+usemtl white
+f 1/1/1 4/4/1 3/3/1 2/2/1
+g wall
+usemtl light
+f -5 -1 -4
+";
+
+  const MTL: &str = "
+newmtl white
+Kd 0.5 0.6 0.7
+Ns 18.0
+newmtl light
+Kd 0.0 0.0 0.0
+Ke 10 10 10
+d 0.5
+";
+
+  #[test]
+  fn test_parse_obj() {
+    let gs = parse_obj(OBJ, Path::new(".")).unwrap();
+    assert_eq!(gs.len(), 2);
+    assert_eq!(gs[0].name, "floor");
+    assert_eq!(gs[0].mesh.ntriangle(), 2);
+    assert_eq!(gs[0].mesh.positions.len(), 4);
+    assert_eq!(gs[0].mesh.normals, Some(vec![Vector3::EY; 4]));
+    assert_eq!(gs[0].mesh.uv(0, 0.0, 1.0), Some((1.0, 1.0)));
+    assert_eq!(gs[1].mesh.indices, vec![[0, 1, 2]]);
+    assert_eq!(gs[1].mesh.normals, None);
+    assert_eq!(gs[1].mesh.normal(0, 0.1, 0.1), Some(-Vector3::EZ));
+    assert!(gs[0].material.is_none());
+
+    let e = parse_obj("v 0 0 0\nv 1 0 0\nf 1 2 3\n", Path::new(".")).unwrap_err();
+    assert_eq!(e.line, Some(3));
+    assert_eq!(e.key, Some("f".to_string()));
+    let e = parse_obj("v 0 0 0\nvn 0 a 0\n", Path::new(".")).unwrap_err();
+    assert_eq!(e.line, Some(2));
+    assert_eq!(e.key, Some("vn".to_string()));
+  }
+
+  #[test]
+  fn test_parse_mtl() {
+    let ms = parse_mtl(MTL).unwrap();
+    let w = ms["white"];
+    assert_eq!(w.surface.albedo_diff(&Wavelength::GREEN), 0.6);
+    assert_eq!(w.surface.roughness(), (2.0 / 20.0 as Flt).powf(0.25));
+    let l = ms["light"];
+    assert_eq!(l.emittance, Radiance(10.0, 10.0, 10.0));
+    assert_eq!(l.ior, Color(1.0, 1.0, 1.0));
+    match l.surface {
+      Surface::TS {scatterness, ..} => assert_eq!(scatterness, 0.5),
+      _ => panic!("not a TS surface"),
+    }
+    let t = parse_mtl("newmtl x\nTf 1.0 0.75 0.5\n").unwrap();
+    assert_eq!(t["x"].transmittance, Color(0.0, 0.25, 0.5));
+    assert!(parse_mtl("newmtl x\nKd a b c\n").is_err());
+  }
+}